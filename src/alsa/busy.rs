@@ -0,0 +1,113 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// A process that has an ALSA device open
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Holder {
+    pub pid: u32,
+    pub name: String,
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (pid {})", self.name, self.pid)
+    }
+}
+
+/// Card index for a card given by number or by id ("2", "DAC")
+pub fn card_index(card: &str) -> Option<u32> {
+    if let Ok(index) = card.parse() {
+        return Some(index);
+    }
+    // /proc/asound/<id> is a symlink to card<N>
+    let target = fs::read_link(Path::new("/proc/asound").join(card)).ok()?;
+    target.to_str()?.strip_prefix("card")?.parse().ok()
+}
+
+/// Who has `hw:<card>,<device>` open for playback: the owner ALSA records
+/// for each substream, plus anything with the device node open
+pub fn holders(card: u32, device: u32) -> Vec<Holder> {
+    let mut pids = owner_pids(card, device);
+    for pid in fd_holders(Path::new(&format!("/dev/snd/pcmC{}D{}p", card, device))) {
+        if !pids.contains(&pid) {
+            pids.push(pid);
+        }
+    }
+    pids.into_iter().map(|pid| Holder { pid, name: process_name(pid) }).collect()
+}
+
+/// Whether anything, this player included, has a playback substream of
+/// `hw:<card>,<device>` open. Cheap: only ALSA's own status files are read.
+pub fn is_open(card: u32, device: u32) -> bool {
+    !owner_pids(card, device).is_empty()
+}
+
+fn owner_pids(card: u32, device: u32) -> Vec<u32> {
+    let Ok(subs) = fs::read_dir(format!("/proc/asound/card{}/pcm{}p", card, device)) else {
+        return Vec::new();
+    };
+    subs.filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with("sub"))
+        .filter_map(|e| fs::read_to_string(e.path().join("status")).ok())
+        .filter_map(|status| parse_owner_pid(&status))
+        .collect()
+}
+
+fn parse_owner_pid(status: &str) -> Option<u32> {
+    status.lines().find_map(|line| {
+        line.trim().strip_prefix("owner_pid")?.trim_start().strip_prefix(':')?.trim().parse().ok()
+    })
+}
+
+/// Processes with an open descriptor on `node`. Other users' processes are
+/// only visible to root.
+fn fd_holders(node: &Path) -> Vec<u32> {
+    let Ok(procs) = fs::read_dir("/proc") else { return Vec::new() };
+    procs.filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.parse::<u32>().ok())
+        .filter(|pid| {
+            fs::read_dir(format!("/proc/{}/fd", pid))
+                .map(|mut fds| fds.any(|fd| fd.is_ok_and(|fd| fs::read_link(fd.path()).is_ok_and(|t| t == node))))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Running processes whose name is one of `names`
+pub fn running(names: &[&str]) -> Vec<Holder> {
+    let Ok(procs) = fs::read_dir("/proc") else { return Vec::new() };
+    procs.filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.parse::<u32>().ok())
+        .map(|pid| Holder { pid, name: process_name(pid) })
+        .filter(|p| names.contains(&p.name.as_str()))
+        .collect()
+}
+
+fn process_name(pid: u32) -> String {
+    fs::read_to_string(format!("/proc/{}/comm", pid))
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "unknown".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::pcm::tests::TempPath;
+
+    #[test]
+    fn test_parse_owner_pid() {
+        let status = "state: RUNNING\nowner_pid   : 1234\ntrigger_time: 1.0\n";
+        assert_eq!(parse_owner_pid(status), Some(1234));
+        assert_eq!(parse_owner_pid("closed\n"), None);
+        assert_eq!(card_index("3"), Some(3));
+    }
+
+    #[test]
+    fn test_finds_own_open_descriptor() {
+        let path = TempPath::new("busy");
+        let _file = fs::File::create(&path).unwrap();
+        assert!(fd_holders(&path).contains(&std::process::id()));
+        assert!(holders(99, 0).is_empty());
+    }
+}
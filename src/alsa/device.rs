@@ -1,16 +1,50 @@
+use std::fmt;
+
 use alsa::{Direction, ValueOr, pcm::{PCM, HwParams, Format, Access}};
 
+use crate::player::pcm::{choose_encoding, OutputEncoding, SourceFormat};
+
+/// Rates worth knowing about for bit-perfect playback
+const STANDARD_RATES: [u32; 10] = [44100, 48000, 88200, 96000, 176400, 192000, 352800, 384000, 705600, 768000];
+
+/// Requested buffer and period lengths; `None` leaves them to the driver
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferTimes {
+    pub buffer_ms: Option<u32>,
+    pub period_ms: Option<u32>,
+}
+
+/// What a playback PCM accepts without conversion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub encodings: Vec<OutputEncoding>,
+    pub rates: Vec<u32>,
+    pub max_channels: u32,
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rates: Vec<String> = self.rates.iter().map(|r| (*r as f64 / 1000.0).to_string()).collect();
+        let encodings: Vec<&str> = self.encodings.iter().map(|e| e.name()).collect();
+        write!(f, "{} kHz, {}, {}ch", rates.join("/"), encodings.join(" "), self.max_channels)
+    }
+}
+
 #[allow(dead_code)]
 pub struct BitPerfectDevice {
     pub pcm: PCM,
     pub current_format: Format,
+    /// Whether snd_pcm_pause works on this hardware
+    pub can_pause: bool,
+    pub frame_bytes: usize,
+    pub buffer_frames: usize,
 }
 
 #[allow(dead_code)]
 impl BitPerfectDevice {
     pub fn open_raw(name: &str) -> Result<Self, alsa::Error> {
         let pcm = PCM::new(name, Direction::Playback, false)?;
-        Ok(Self { pcm, current_format: Format::Unknown })
+        Ok(Self { pcm, current_format: Format::Unknown, can_pause: false, frame_bytes: 0, buffer_frames: 0 })
     }
 
     pub fn open(card: &str, device: u32) -> Result<Self, alsa::Error> {
@@ -18,13 +52,33 @@ impl BitPerfectDevice {
         Self::open_raw(&name)
     }
 
+    /// Ask `hw:<card>,<device>` what it can do. Fails with EBUSY while
+    /// anything (including us) has it open.
+    pub fn probe(card: &str, device: u32) -> Result<Capabilities, alsa::Error> {
+        let pcm = PCM::new(&format!("hw:{},{}", card, device), Direction::Playback, true)?;
+        let hwp = HwParams::any(&pcm)?;
+        let encodings = [
+            OutputEncoding::U8, OutputEncoding::S16LE, OutputEncoding::S24LE,
+            OutputEncoding::S24_3LE, OutputEncoding::S32LE, OutputEncoding::FloatLE,
+        ];
+        Ok(Capabilities {
+            encodings: encodings.into_iter().filter(|e| hwp.test_format(e.alsa_format()).is_ok()).collect(),
+            rates: STANDARD_RATES.into_iter().filter(|r| hwp.test_rate(*r).is_ok()).collect(),
+            max_channels: hwp.get_channels_max()?,
+        })
+    }
+
     /// Configure for exact file format - NO CONVERSION
+    ///
+    /// Returns the sample encoding the hardware was set to; narrower sources
+    /// may be zero padded into a wider word, which is lossless.
     pub fn configure_exact(
         &mut self,
         sample_rate: u32,
-        bit_depth: u16,
+        source: SourceFormat,
         channels: u8,
-    ) -> Result<(), alsa::Error> {
+        times: BufferTimes,
+    ) -> Result<OutputEncoding, alsa::Error> {
         // If the device is already running or in a weird state, drop it to reset
         let _ = self.pcm.drop();
 
@@ -34,36 +88,41 @@ impl BitPerfectDevice {
         // Use RWInterleaved for the write_raw/io_bytes path
         hwp.set_access(Access::RWInterleaved)?;
 
-        // Try to set the best possible format for the given bit depth
-        let format = match bit_depth {
-            16 => Format::S16LE,
-            24 => {
-                if hwp.test_format(Format::S32LE).is_ok() {
-                    Format::S32LE
-                } else {
-                    Format::S243LE
-                }
-            },
-            32 => Format::S32LE,
-            _ => return Err(alsa::Error::new("Unsupported bit depth", -22)),
-        };
+        // Try to set the best possible format for the given source
+        let encoding = choose_encoding(source, |e| hwp.test_format(e.alsa_format()).is_ok())
+            .ok_or_else(|| alsa::Error::new("Unsupported sample format", -22))?;
+        let format = encoding.alsa_format();
         
         hwp.set_format(format)?;
         hwp.set_channels(channels as u32)?;
         
-        let actual_rate = hwp.set_rate_near(sample_rate, ValueOr::Nearest)?;
+        let _actual_rate = hwp.set_rate_near(sample_rate, ValueOr::Nearest)?;
+        if let Some(ms) = times.buffer_ms {
+            hwp.set_buffer_time_near(ms * 1000, ValueOr::Nearest)?;
+        }
+        if let Some(ms) = times.period_ms {
+            hwp.set_period_time_near(ms * 1000, ValueOr::Nearest)?;
+        }
         
         // Apply ALL parameters to hardware at once
         self.pcm.hw_params(&hwp)?;
         self.current_format = format;
+        self.can_pause = hwp.can_pause();
+        self.frame_bytes = channels as usize * encoding.width();
+        self.buffer_frames = hwp.get_buffer_size()? as usize;
         
-        Ok(())
+        Ok(encoding)
     }
     pub fn write_raw(&self, data: &[u8]) -> Result<usize, alsa::Error> {
         let io = self.pcm.io_bytes();
         io.writei(data)
     }
 
+    /// ALSA card index of the open PCM, used to find its mixer
+    pub fn card(&self) -> Option<i32> {
+        self.pcm.info().ok().map(|i| i.get_card()).filter(|c| *c >= 0)
+    }
+
     pub fn drain(&self) -> Result<(), alsa::Error> {
         self.pcm.drain()
     }
@@ -72,3 +131,18 @@ impl BitPerfectDevice {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_summary() {
+        let caps = Capabilities {
+            encodings: vec![OutputEncoding::S16LE, OutputEncoding::S32LE],
+            rates: vec![44100, 48000, 176400],
+            max_channels: 2,
+        };
+        assert_eq!(caps.to_string(), "44.1/48/176.4 kHz, S16_LE S32_LE, 2ch");
+    }
+}
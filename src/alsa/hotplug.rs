@@ -0,0 +1,214 @@
+use std::ffi::CStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::fd::FromRawFd;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::alsa::busy::{card_index, is_open};
+use crate::alsa::device::{BitPerfectDevice, Capabilities};
+use crate::gui::PlayerState;
+
+const DEV_SND: &CStr = c"/dev/snd";
+
+/// A playback PCM the kernel knows about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcmDevice {
+    pub card: u32,
+    pub device: u32,
+    /// Card id, e.g. "DAC" or "PCH"
+    pub card_id: String,
+    pub name: String,
+    /// Unknown until probed, or while the device is busy
+    pub caps: Option<Capabilities>,
+}
+
+impl PcmDevice {
+    /// Whether `target` names this device, by card number or id
+    pub fn is(&self, target: &Target) -> bool {
+        (target.card == self.card_id || target.card == self.card.to_string()) && target.device == self.device
+    }
+}
+
+impl fmt::Display for PcmDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} (hw:{},{})", self.card_id, self.name, self.card, self.device)
+    }
+}
+
+/// Every playback PCM listed in /proc/asound/pcm
+pub fn playback_devices() -> Vec<PcmDevice> {
+    let Ok(list) = fs::read_to_string("/proc/asound/pcm") else { return Vec::new() };
+    parse_pcm_list(&list)
+        .into_iter()
+        .map(|(card, device, name)| PcmDevice {
+            card,
+            device,
+            card_id: fs::read_to_string(format!("/proc/asound/card{}/id", card))
+                .map(|id| id.trim().to_string())
+                .unwrap_or_else(|_| card.to_string()),
+            name,
+            caps: None,
+        })
+        .collect()
+}
+
+/// `00-00: USB Audio : USB Audio : playback 1 : capture 1`, playback lines only
+fn parse_pcm_list(list: &str) -> Vec<(u32, u32, String)> {
+    list.lines()
+        .filter_map(|line| {
+            let (index, rest) = line.split_once(": ")?;
+            let (card, device) = index.split_once('-')?;
+            let mut fields = rest.split(" : ");
+            let _id = fields.next()?;
+            let name = fields.next()?.trim().to_string();
+            fields.any(|f| f.trim_start().starts_with("playback"))
+                .then_some((card.parse().ok()?, device.parse().ok()?, name))
+        })
+        .collect()
+}
+
+/// The output picked with `--card`/`--device` or the GUI. "default" takes
+/// whatever is there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub card: String,
+    pub device: u32,
+}
+
+impl Target {
+    pub fn auto() -> Self {
+        Self { card: "default".into(), device: 0 }
+    }
+
+    pub fn is_auto(&self) -> bool {
+        self.card == "default"
+    }
+
+    fn present(&self, devices: &[PcmDevice]) -> bool {
+        if self.is_auto() {
+            return !devices.is_empty();
+        }
+        card_index(&self.card).is_some_and(|card| {
+            devices.iter().any(|d| d.card == card && d.device == self.device)
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_auto() {
+            write!(f, "Auto")
+        } else {
+            write!(f, "hw:{},{}", self.card, self.device)
+        }
+    }
+}
+
+/// Keep `state.devices` in step with the sound cards plugged in, and pick up
+/// playback where it was cut off once `state.output` comes back (if `auto_resume`)
+pub fn spawn_monitor(state: Arc<Mutex<PlayerState>>, auto_resume: bool) {
+    thread::spawn(move || {
+        let mut watch = None;
+        loop {
+            refresh(&state, auto_resume);
+            if watch.is_none() {
+                watch = Watch::new(DEV_SND);
+            }
+            match &watch {
+                Some(w) if w.wait() => {
+                    // udev creates the nodes first and fixes up permissions after
+                    thread::sleep(Duration::from_millis(250));
+                    // The directory goes away with the last card; watch it again when it is back
+                    if fs::metadata("/dev/snd").is_err() {
+                        watch = None;
+                    }
+                }
+                _ => {
+                    watch = None;
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    });
+}
+
+fn refresh(state: &Mutex<PlayerState>, auto_resume: bool) {
+    let known = state.lock().unwrap().devices.clone();
+    let mut devices = playback_devices();
+    // Probing opens the device, so only do it for ones not seen before, and
+    // not while someone is playing through it: it is not ours to take, and a
+    // probe in the way of the next open makes that fail with EBUSY. Those are
+    // probed on a later refresh, once closed.
+    for d in &mut devices {
+        d.caps = known.iter()
+            .find(|k| k.card == d.card && k.device == d.device && k.card_id == d.card_id)
+            .and_then(|k| k.caps.clone())
+            .or_else(|| {
+                if is_open(d.card, d.device) {
+                    return None;
+                }
+                BitPerfectDevice::probe(&d.card.to_string(), d.device).ok()
+            });
+    }
+
+    let mut s = state.lock().unwrap();
+    let present = s.output.present(&devices);
+    if s.devices != devices {
+        s.devices = devices;
+    }
+    if s.device_lost && present {
+        s.device_lost = false;
+        if auto_resume {
+            s.is_playing = true;
+            s.error_message = None;
+        } else {
+            s.error_message = Some("Output device is back. Press Play to resume".into());
+        }
+    }
+}
+
+/// inotify on a directory; any entry created, removed or re-permissioned wakes `wait`
+struct Watch(File);
+
+impl Watch {
+    fn new(dir: &CStr) -> Option<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return None;
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mask = libc::IN_CREATE | libc::IN_DELETE | libc::IN_ATTRIB | libc::IN_DELETE_SELF;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+            return None;
+        }
+        Some(Watch(file))
+    }
+
+    /// Block until something changes
+    fn wait(&self) -> bool {
+        let mut events = [0u8; 4096];
+        (&self.0).read(&mut events).is_ok_and(|n| n > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pcm_list_keeps_playback_devices() {
+        let list = "00-00: ALC892 Analog : ALC892 Analog : playback 1 : capture 1\n\
+                    00-02: ALC892 Alt Analog : ALC892 Alt Analog : capture 1\n\
+                    01-03: HDMI 0 : HDMI 0 : playback 1\n\
+                    02-00: USB Audio : USB Audio : playback 1 : capture 1\n";
+        assert_eq!(parse_pcm_list(list), [
+            (0, 0, "ALC892 Analog".to_string()),
+            (1, 3, "HDMI 0".to_string()),
+            (2, 0, "USB Audio".to_string()),
+        ]);
+        assert!(parse_pcm_list("").is_empty());
+    }
+}
@@ -0,0 +1,61 @@
+use alsa::mixer::{Mixer, MilliBel, Selem, SelemId};
+use alsa::Round;
+
+/// Mixer controls that usually sit in the DAC's analog or digital stage
+const PREFERRED_CONTROLS: [&str; 5] = ["PCM", "Master", "Digital", "Speaker", "Headphone"];
+
+/// A hardware volume control on the same card as the output PCM.
+///
+/// Gain is applied relative to the level the user had when we opened it, and
+/// that level is restored on drop so we never leave the card louder than we found it.
+pub struct HardwareVolume {
+    mixer: Mixer,
+    id: SelemId,
+    base: MilliBel,
+    range: (MilliBel, MilliBel),
+}
+
+impl HardwareVolume {
+    /// Find a playback volume control with a dB scale on `hw:<card>`
+    pub fn open(card: i32) -> Option<Self> {
+        let mixer = Mixer::new(&format!("hw:{}", card), false).ok()?;
+        let (id, base, range) = {
+            let mut candidates: Vec<Selem> = mixer
+                .iter()
+                .filter_map(Selem::new)
+                .filter(|s| s.has_playback_volume())
+                .filter(|s| {
+                    let (min, max) = s.get_playback_db_range();
+                    max > min
+                })
+                .collect();
+            candidates.sort_by_key(|s| {
+                let id = s.get_id();
+                let name = id.get_name().unwrap_or_default().to_string();
+                PREFERRED_CONTROLS.iter().position(|p| *p == name).unwrap_or(PREFERRED_CONTROLS.len())
+            });
+            let selem = candidates.into_iter().next()?;
+            let base = selem.get_playback_vol_db(alsa::mixer::SelemChannelId::FrontLeft).ok()?;
+            (selem.get_id(), base, selem.get_playback_db_range())
+        };
+        Some(Self { mixer, id, base, range })
+    }
+
+    /// Set the control to `db` relative to the starting level.
+    /// Returns the gain actually applied after clamping to the control's range.
+    pub fn set_gain_db(&self, db: f64) -> Result<f64, alsa::Error> {
+        let selem = self.mixer.find_selem(&self.id)
+            .ok_or_else(|| alsa::Error::new("Mixer control disappeared", -19))?;
+        let target = MilliBel(self.base.0.saturating_add((db * 100.0) as i64).clamp(self.range.0.0, self.range.1.0));
+        selem.set_playback_db_all(target, Round::Floor)?;
+        // The control moves in steps; report the one it landed on
+        let set = selem.get_playback_vol_db(alsa::mixer::SelemChannelId::FrontLeft).unwrap_or(target);
+        Ok((set.0 - self.base.0) as f64 / 100.0)
+    }
+}
+
+impl Drop for HardwareVolume {
+    fn drop(&mut self) {
+        let _ = self.set_gain_db(0.0);
+    }
+}
@@ -1,4 +1,9 @@
+pub mod busy;
 pub mod device;
+pub mod hotplug;
+pub mod mixer;
+pub mod reserve;
+pub mod sink;
 pub mod sw_params;
 
 
@@ -0,0 +1,92 @@
+//! org.freedesktop.ReserveDevice1, the D-Bus protocol PulseAudio, PipeWire and
+//! JACK use to hand a sound card from one application to another.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ReserveError {
+    #[cfg(feature = "reserve")]
+    #[error("D-Bus error: {0}")]
+    Dbus(#[from] zbus::Error),
+    #[cfg(feature = "reserve")]
+    #[error("{0} refused to release the card")]
+    Refused(String),
+    #[cfg(not(feature = "reserve"))]
+    #[error("elitebox was built without the `reserve` feature")]
+    Unavailable,
+}
+
+/// Ownership of the card's reservation name, with the object that answers
+/// for it; dropping it lets the previous owner take the card back
+pub struct Reservation {
+    #[cfg(feature = "reserve")]
+    _connection: zbus::blocking::Connection,
+}
+
+/// Higher than PulseAudio (0) and JACK (10), so they are asked to give way
+#[cfg(feature = "reserve")]
+const PRIORITY: i32 = 20;
+
+/// The object every owner of a reservation name serves, so that the next
+/// application can negotiate instead of getting a D-Bus error
+#[cfg(feature = "reserve")]
+struct Owner {
+    card: u32,
+}
+
+#[cfg(feature = "reserve")]
+#[zbus::interface(name = "org.freedesktop.ReserveDevice1")]
+impl Owner {
+    /// The card is held for bit-perfect playback and is not handed over on
+    /// request, whatever the priority; stopping playback or `--release-after`
+    /// gives it back
+    fn request_release(&self, _priority: i32) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn priority(&self) -> i32 {
+        PRIORITY
+    }
+
+    #[zbus(property)]
+    fn application_name(&self) -> String {
+        "Elitebox".into()
+    }
+
+    #[zbus(property)]
+    fn application_device_name(&self) -> String {
+        format!("hw:{}", self.card)
+    }
+}
+
+/// Take over `Audio<card>`, asking the current owner to close it first
+#[cfg(feature = "reserve")]
+pub fn reserve(card: u32) -> Result<Reservation, ReserveError> {
+    use zbus::fdo::{RequestNameFlags, RequestNameReply};
+
+    let connection = zbus::blocking::Connection::session()?;
+    let path = format!("/org/freedesktop/ReserveDevice1/Audio{}", card);
+    // Served before the name is ours, so nobody finds the name without it
+    connection.object_server().at(path.as_str(), Owner { card })?;
+    let name = format!("org.freedesktop.ReserveDevice1.Audio{}", card);
+    let reply = connection.request_name_with_flags(name.as_str(), RequestNameFlags::DoNotQueue.into())?;
+    if reply != RequestNameReply::Exists {
+        return Ok(Reservation { _connection: connection });
+    }
+
+    // The owner closes the device before it answers
+    let owner = zbus::blocking::Proxy::new(&connection, name.as_str(), path.as_str(), "org.freedesktop.ReserveDevice1")?;
+    let released: bool = owner.call("RequestRelease", &(PRIORITY,))?;
+    if !released {
+        let who: String = owner.get_property("ApplicationName").unwrap_or_else(|_| "The current owner".into());
+        return Err(ReserveError::Refused(who));
+    }
+    connection.request_name_with_flags(name.as_str(), RequestNameFlags::DoNotQueue | RequestNameFlags::ReplaceExisting)?;
+    Ok(Reservation { _connection: connection })
+}
+
+#[cfg(not(feature = "reserve"))]
+pub fn reserve(_card: u32) -> Result<Reservation, ReserveError> {
+    Err(ReserveError::Unavailable)
+}
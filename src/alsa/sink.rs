@@ -0,0 +1,263 @@
+use std::collections::VecDeque;
+
+use alsa::pcm::State;
+
+use crate::alsa::busy::{card_index, holders};
+use crate::alsa::device::{BitPerfectDevice, BufferTimes};
+use crate::alsa::hotplug::Target;
+use crate::alsa::reserve::{reserve, Reservation};
+use crate::output::{AudioSink, SinkError};
+use crate::player::pcm::{OutputEncoding, SourceFormat};
+
+/// The DAC. The PCM is opened on the first `configure` and closed again after
+/// an error, so the next attempt starts from a fresh handle.
+pub struct AlsaSink {
+    card: String,
+    device_index: u32,
+    device: Option<BitPerfectDevice>,
+    paused: Option<Paused>,
+    /// The last buffer's worth of output, kept on hardware that cannot pause
+    /// so that resuming can replay what the drop threw away
+    retained: VecDeque<u8>,
+    /// Ask a sound server holding the card to let go of it
+    reserve: bool,
+    reservation: Option<Reservation>,
+    /// Buffer and period lengths asked of the hardware
+    times: BufferTimes,
+}
+
+enum Paused {
+    /// snd_pcm_pause; the buffer is frozen in place
+    Hardware,
+    /// Nothing had started playing yet
+    Idle,
+    /// Dropped, with the frames that had not been heard
+    Dropped(Vec<u8>),
+}
+
+impl AlsaSink {
+    pub fn new(card: String, device_index: u32) -> Self {
+        Self {
+            card,
+            device_index,
+            device: None,
+            paused: None,
+            retained: VecDeque::new(),
+            reserve: false,
+            reservation: None,
+            times: BufferTimes::default(),
+        }
+    }
+
+    pub fn with_reserve(mut self, reserve: bool) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    pub fn with_buffer(mut self, times: BufferTimes) -> Self {
+        self.times = times;
+        self
+    }
+
+    fn open(&mut self) -> Result<BitPerfectDevice, SinkError> {
+        let candidates = if self.card == "default" {
+            vec![("2".to_string(), 0), ("1".to_string(), 0), ("0".to_string(), 0)]
+        } else {
+            vec![(self.card.clone(), self.device_index)]
+        };
+
+        let mut last_error = None;
+        for (card, device) in candidates {
+            match self.open_hw(&card, device) {
+                Ok(d) => return Ok(d),
+                // The first card that is there is the one wanted. Moving on to
+                // the onboard card, or to a sound server behind "default",
+                // would play, but not bit-perfect.
+                Err(e) if e.errno() == libc::EBUSY => {
+                    return Err(SinkError::Busy {
+                        device: format!("hw:{},{}", card, device),
+                        holders: card_index(&card).map(|c| holders(c, device)).unwrap_or_default(),
+                    });
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        // Last resort for auto-detection, with no hw card at all: whatever "default" routes to
+        if self.card == "default"
+            && let Ok(d) = BitPerfectDevice::open_raw("default")
+        {
+            return Ok(d);
+        }
+
+        match last_error {
+            Some(e) => Err(e.into()),
+            None => Err(alsa::Error::new("snd_pcm_open", libc::ENODEV).into()),
+        }
+    }
+
+    /// Open one hw PCM, asking its current owner to release it if allowed to
+    fn open_hw(&mut self, card: &str, device: u32) -> Result<BitPerfectDevice, alsa::Error> {
+        match BitPerfectDevice::open(card, device) {
+            Err(e) if e.errno() == libc::EBUSY && self.reserve => {
+                let Some(index) = card_index(card) else { return Err(e) };
+                match reserve(index) {
+                    Ok(reservation) => {
+                        self.reservation = Some(reservation);
+                        BitPerfectDevice::open(card, device)
+                    }
+                    Err(err) => {
+                        eprintln!("Could not reserve card {}: {}", index, err);
+                        Err(e)
+                    }
+                }
+            }
+            other => other,
+        }
+    }
+
+    /// Run `f` on the open device, closing it if ALSA reports an error
+    fn with_device<T>(&mut self, f: impl FnOnce(&mut BitPerfectDevice) -> Result<T, alsa::Error>) -> Result<T, SinkError> {
+        let device = self.device.as_mut().ok_or(SinkError::NotConfigured)?;
+        f(device).inspect_err(|_| self.device = None).map_err(SinkError::from)
+    }
+}
+
+impl AudioSink for AlsaSink {
+    fn configure(&mut self, sample_rate: u32, source: SourceFormat, channels: u8) -> Result<OutputEncoding, SinkError> {
+        if self.device.is_none() {
+            self.device = Some(self.open()?);
+        }
+        self.paused = None;
+        self.retained.clear();
+        let times = self.times;
+        let encoding = self.with_device(|d| d.configure_exact(sample_rate, source, channels, times))?;
+        if let Some(d) = &self.device
+            && !d.can_pause
+        {
+            // A buffer's worth plus the packet being written, so that `write`
+            // never has to grow it
+            self.retained.reserve(2 * d.buffer_frames * d.frame_bytes);
+        }
+        Ok(encoding)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), SinkError> {
+        self.with_device(|d| d.write_raw(data).map(|_| ()))?;
+        if let Some(d) = &self.device
+            && !d.can_pause
+        {
+            self.retained.extend(data);
+            let excess = self.retained.len().saturating_sub(d.buffer_frames * d.frame_bytes);
+            self.retained.drain(..excess);
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<(), SinkError> {
+        self.with_device(|d| d.drain())
+    }
+
+    fn drop_queued(&mut self) -> Result<(), SinkError> {
+        self.paused = None;
+        self.retained.clear();
+        self.with_device(|d| d.pcm.drop())
+    }
+
+    fn pause(&mut self, pause: bool) -> Result<(), SinkError> {
+        if pause == self.paused.is_some() {
+            return Ok(());
+        }
+        if pause {
+            let retained = std::mem::take(&mut self.retained);
+            let paused = self.with_device(|d| {
+                if d.pcm.state() != State::Running {
+                    Ok(Paused::Idle)
+                } else if d.can_pause {
+                    d.pcm.pause(true).map(|_| Paused::Hardware)
+                } else {
+                    let unheard = unheard_tail(&retained, d.pcm.delay()?, d.frame_bytes);
+                    d.pcm.drop()?;
+                    Ok(Paused::Dropped(unheard))
+                }
+            });
+            self.retained = retained;
+            self.paused = Some(paused?);
+        } else {
+            match self.paused.take() {
+                Some(Paused::Hardware) => self.with_device(|d| d.pcm.pause(false))?,
+                Some(Paused::Dropped(unheard)) => {
+                    self.with_device(|d| {
+                        d.pcm.prepare()?;
+                        d.write_raw(&unheard).map(|_| ())
+                    })?;
+                    self.retained = unheard.into();
+                }
+                Some(Paused::Idle) | None => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        // Dropping the PCM closes it; drop first so nothing queued plays out
+        if let Some(device) = self.device.take() {
+            let _ = device.pcm.drop();
+        }
+        // Lets PipeWire/PulseAudio take the card back
+        self.reservation = None;
+        self.paused = None;
+        self.retained.clear();
+    }
+
+    fn select(&mut self, target: &Target) -> Result<(), SinkError> {
+        let card = std::mem::replace(&mut self.card, target.card.clone());
+        let device_index = std::mem::replace(&mut self.device_index, target.device);
+        self.release();
+        match self.open() {
+            Ok(device) => {
+                self.device = Some(device);
+                Ok(())
+            }
+            Err(e) => {
+                // The old device is closed, but the next track opens it again
+                self.card = card;
+                self.device_index = device_index;
+                Err(e)
+            }
+        }
+    }
+
+    fn delay(&self) -> Result<i64, SinkError> {
+        let device = self.device.as_ref().ok_or(SinkError::NotConfigured)?;
+        match &self.paused {
+            Some(Paused::Dropped(unheard)) => Ok((unheard.len() / device.frame_bytes) as i64),
+            _ => Ok(device.pcm.delay()?),
+        }
+    }
+
+    fn card(&self) -> Option<i32> {
+        self.device.as_ref().and_then(|d| d.card())
+    }
+}
+
+/// What a drop would throw away unheard: the last `delay` frames written,
+/// as far as `retained` reaches back. ALSA reports a negative delay after an underrun.
+fn unheard_tail(retained: &VecDeque<u8>, delay: i64, frame_bytes: usize) -> Vec<u8> {
+    let queued = (delay.max(0) as usize * frame_bytes).min(retained.len());
+    retained.range(retained.len() - queued..).copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unheard_tail() {
+        // Six 2-byte frames written, the last two still queued
+        let retained: VecDeque<u8> = (0..12).collect();
+        assert_eq!(unheard_tail(&retained, 2, 2), [8, 9, 10, 11]);
+        assert!(unheard_tail(&retained, -5, 2).is_empty());
+        // More queued than was kept: everything kept is replayed
+        assert_eq!(unheard_tail(&retained, 100, 2).len(), 12);
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::rt::SchedPolicy;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Cannot read {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("{0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("Unknown key {key:?} for {action} (use egui key names like \"Space\", \"J\", \"F5\")")]
+    UnknownKey { action: &'static str, key: String },
+}
+
+/// Everything `config.toml` can set. Missing sections and keys keep their
+/// defaults; the command line overrides whatever is here.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub output: OutputConfig,
+    pub realtime: RealtimeConfig,
+    pub library: LibraryConfig,
+    pub volume: VolumeConfig,
+    pub theme: Theme,
+    pub keys: KeyBindings,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub card: String,
+    pub device: u32,
+    /// ALSA buffer length; unset leaves it to the driver
+    pub buffer_ms: Option<u32>,
+    pub period_ms: Option<u32>,
+    pub release_after: Option<u64>,
+    pub reserve: bool,
+    pub resume_on_reconnect: bool,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            card: "default".into(),
+            device: 0,
+            buffer_ms: None,
+            period_ms: None,
+            release_after: None,
+            reserve: false,
+            resume_on_reconnect: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RealtimeConfig {
+    pub policy: SchedPolicy,
+    pub priority: i32,
+    /// Core to pin the audio thread to. Off by default: core 0 usually
+    /// services most interrupts.
+    pub cpu: Option<usize>,
+    pub lock_memory: bool,
+    /// Ask RTKit when the scheduler refuses
+    pub rtkit: bool,
+}
+
+impl Default for RealtimeConfig {
+    fn default() -> Self {
+        Self { policy: SchedPolicy::Fifo, priority: 95, cpu: None, lock_memory: true, rtkit: true }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LibraryConfig {
+    /// The browser starts in the first one; all of them get a shortcut
+    pub roots: Vec<PathBuf>,
+    /// Play tracks from RAM, for spinning disks and network mounts
+    pub read_ahead: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VolumeConfig {
+    /// Software volume at startup
+    pub initial_db: f64,
+    /// Ceiling for the volume slider
+    pub max_db: f64,
+}
+
+impl Default for VolumeConfig {
+    fn default() -> Self {
+        Self { initial_db: -10.0, max_db: 0.0 }
+    }
+}
+
+/// `#rrggbb`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl TryFrom<String> for Rgb {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let hex = s.strip_prefix('#').filter(|h| h.len() == 6 && h.is_ascii());
+        let channel = |i: usize| hex.and_then(|h| u8::from_str_radix(&h[i..i + 2], 16).ok());
+        match (channel(0), channel(2), channel(4)) {
+            (Some(r), Some(g), Some(b)) => Ok(Rgb(r, g, b)),
+            _ => Err(format!("expected a colour like \"#1d2021\", got {:?}", s)),
+        }
+    }
+}
+
+impl From<Rgb> for String {
+    fn from(c: Rgb) -> Self {
+        c.to_string()
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+}
+
+/// Gruvbox dark unless told otherwise
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    pub text: Rgb,
+    pub background: Rgb,
+    pub widget: Rgb,
+    pub active: Rgb,
+    /// The track now playing
+    pub accent: Rgb,
+    pub bit_perfect: Rgb,
+    pub warning: Rgb,
+    pub error: Rgb,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            text: Rgb(0xeb, 0xdb, 0xb2),
+            background: Rgb(0x1d, 0x20, 0x21),
+            widget: Rgb(0x28, 0x28, 0x28),
+            active: Rgb(0x45, 0x85, 0x88),
+            accent: Rgb(0xba, 0xbd, 0x2f),
+            bit_perfect: Rgb(0xb8, 0xbb, 0x26),
+            warning: Rgb(0xfe, 0x80, 0x19),
+            error: Rgb(0xfb, 0x49, 0x34),
+        }
+    }
+}
+
+/// egui key names. The arrow keys, Enter and Backspace always work as well.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    pub toggle: String,
+    pub down: String,
+    pub up: String,
+    pub enter: String,
+    pub back: String,
+    pub next: String,
+    pub prev: String,
+    pub stop: String,
+    pub repeat: String,
+    pub shuffle: String,
+    pub replaygain: String,
+    pub quit: String,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            toggle: "Space".into(),
+            down: "J".into(),
+            up: "K".into(),
+            enter: "L".into(),
+            back: "H".into(),
+            next: "N".into(),
+            prev: "P".into(),
+            stop: "S".into(),
+            repeat: "R".into(),
+            shuffle: "Z".into(),
+            replaygain: "G".into(),
+            quit: "Q".into(),
+        }
+    }
+}
+
+impl KeyBindings {
+    /// Action name and key name for each binding
+    pub fn actions(&self) -> [(&'static str, &str); 12] {
+        [
+            ("toggle", &self.toggle), ("down", &self.down), ("up", &self.up),
+            ("enter", &self.enter), ("back", &self.back), ("next", &self.next),
+            ("prev", &self.prev), ("stop", &self.stop), ("repeat", &self.repeat),
+            ("shuffle", &self.shuffle), ("replaygain", &self.replaygain), ("quit", &self.quit),
+        ]
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/elitebox/config.toml`, falling back to `~/.config`
+    pub fn default_path() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(base.join("elitebox").join("config.toml"))
+    }
+
+    /// Read `path`. A missing file is not an error when `required` is false:
+    /// everything keeps its default.
+    pub fn load(path: &Path, required: bool) -> Result<Config, ConfigError> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => return Ok(Config::default()),
+            Err(e) => return Err(ConfigError::Io(path.to_path_buf(), e)),
+        };
+        let mut config = Config::parse(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        config.library.roots = config.library.roots.iter().map(|root| expand_home(root)).collect();
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for (action, key) in self.keys.actions() {
+            if eframe::egui::Key::from_name(key).is_none() {
+                return Err(ConfigError::UnknownKey { action, key: key.to_string() });
+            }
+        }
+        Ok(())
+    }
+
+    /// The effective settings as TOML, for `--print-config`
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("config is always representable as TOML")
+    }
+}
+
+/// `~/Music` means the same here as in a shell
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_config_keeps_defaults() {
+        let config = Config::parse(r##"
+            [output]
+            card = "DAC"
+            buffer_ms = 200
+
+            [theme]
+            accent = "#ff0000"
+        "##).unwrap();
+        assert_eq!(config.output.card, "DAC");
+        assert_eq!(config.output.buffer_ms, Some(200));
+        assert_eq!(config.output.device, 0);
+        assert_eq!(config.theme.accent, Rgb(0xff, 0, 0));
+        assert_eq!(config.theme.background, Theme::default().background);
+        assert_eq!(config.volume, VolumeConfig::default());
+    }
+
+    #[test]
+    fn test_rejects_typos_and_bad_values() {
+        assert!(Config::parse("[output]\ncrad = \"DAC\"").is_err());
+        assert!(Config::parse("[theme]\naccent = \"red\"").is_err());
+        let config = Config::parse("[keys]\nstop = \"Stopp\"").unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::UnknownKey { action: "stop", .. })));
+    }
+
+    #[test]
+    fn test_printed_config_reads_back() {
+        let mut config = Config::default();
+        config.output.release_after = Some(30);
+        config.library.roots = vec![PathBuf::from("/srv/music")];
+        assert_eq!(Config::parse(&config.to_toml()).unwrap(), config);
+    }
+}
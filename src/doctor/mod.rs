@@ -0,0 +1,315 @@
+//! `elitebox doctor`: everything on the machine that stands between the
+//! files and a bit-perfect, glitch-free DAC.
+
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+
+use alsa::pcm::PCM;
+use alsa::{Direction, Output};
+
+use crate::alsa::busy::{holders, running};
+use crate::alsa::device::BitPerfectDevice;
+use crate::alsa::hotplug::playback_devices;
+use crate::config::Config;
+use crate::rt::SchedPolicy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// One finding, with what to do about it when it is not OK
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub status: Status,
+    pub summary: String,
+    pub advice: Option<String>,
+}
+
+impl Check {
+    fn ok(summary: impl Into<String>) -> Self {
+        Self { status: Status::Ok, summary: summary.into(), advice: None }
+    }
+
+    fn warn(summary: impl Into<String>, advice: impl Into<String>) -> Self {
+        Self { status: Status::Warn, summary: summary.into(), advice: Some(advice.into()) }
+    }
+
+    fn fail(summary: impl Into<String>, advice: impl Into<String>) -> Self {
+        Self { status: Status::Fail, summary: summary.into(), advice: Some(advice.into()) }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tag = match self.status {
+            Status::Ok => "[ OK ]",
+            Status::Warn => "[WARN]",
+            Status::Fail => "[FAIL]",
+        };
+        write!(f, "{} {}", tag, self.summary)?;
+        if let Some(advice) = &self.advice {
+            write!(f, "\n       {}", advice)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run every check and print the results. False if anything failed outright.
+pub fn run(config: &Config) -> bool {
+    let checks: Vec<Check> = [
+        limits(config),
+        access(),
+        sound_servers(),
+        vec![default_route()],
+        vec![governor_check(&governors())],
+        devices(),
+    ].concat();
+
+    for check in &checks {
+        println!("{}", check);
+    }
+    let count = |status| checks.iter().filter(|c| c.status == status).count();
+    println!("\n{} warning(s), {} failure(s)", count(Status::Warn), count(Status::Fail));
+    count(Status::Fail) == 0
+}
+
+fn limits(config: &Config) -> Vec<Check> {
+    let root = unsafe { libc::geteuid() } == 0;
+    let mut checks = Vec::new();
+    if config.realtime.policy == SchedPolicy::None {
+        checks.push(Check::ok("Real-time scheduling is disabled in the config"));
+    } else {
+        checks.push(rtprio_check(hard_limit(libc::RLIMIT_RTPRIO), root, config.realtime.priority));
+    }
+    if config.realtime.lock_memory {
+        checks.push(memlock_check(hard_limit(libc::RLIMIT_MEMLOCK), root));
+    }
+    checks
+}
+
+/// What the player gets: it raises the rtprio and memlock soft limits to the
+/// hard ones itself (`rt::apply`)
+fn hard_limit(resource: libc::__rlimit_resource_t) -> libc::rlim_t {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+        return 0;
+    }
+    limit.rlim_max
+}
+
+fn rtprio_check(limit: libc::rlim_t, root: bool, priority: i32) -> Check {
+    if root {
+        Check::ok("Running as root: any real-time priority is allowed")
+    } else if limit >= priority as libc::rlim_t {
+        Check::ok(format!("rtprio limit {} allows priority {}", limit, priority))
+    } else {
+        Check::warn(
+            format!("rtprio limit is {}, below the configured priority {}", limit, priority),
+            format!("Add \"@audio - rtprio {}\" to /etc/security/limits.conf and log in again, or use RTKit (--features rtkit)", priority),
+        )
+    }
+}
+
+fn memlock_check(limit: libc::rlim_t, root: bool) -> Check {
+    if root || limit == libc::RLIM_INFINITY {
+        Check::ok("memlock is unlimited")
+    } else {
+        Check::warn(
+            format!("memlock limit is {} MiB; locking the player in memory may fail", limit / (1024 * 1024)),
+            "Add \"@audio - memlock unlimited\" to /etc/security/limits.conf and log in again",
+        )
+    }
+}
+
+/// Group membership, and whether the device nodes can actually be opened
+/// (logind ACLs grant the seat's user access without the group)
+fn access() -> Vec<Check> {
+    let mut checks = Vec::new();
+    let name = CString::new("audio").unwrap();
+    let group = unsafe { libc::getgrnam(name.as_ptr()) };
+    if group.is_null() {
+        checks.push(Check::ok("No audio group on this system"));
+    } else {
+        let gid = unsafe { (*group).gr_gid };
+        let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) }.max(0);
+        let mut groups = vec![0; count as usize];
+        let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) }.max(0);
+        groups.truncate(count as usize);
+        if groups.contains(&gid) || unsafe { libc::getegid() } == gid {
+            checks.push(Check::ok("Member of the audio group"));
+        } else {
+            checks.push(Check::warn(
+                "Not a member of the audio group",
+                "sudo usermod -aG audio $USER, then log in again (needed for the limits.conf @audio lines)",
+            ));
+        }
+    }
+
+    let nodes: Vec<_> = fs::read_dir("/dev/snd").map(|dir| {
+        dir.filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("pcmC") && n.ends_with('p')))
+            .collect()
+    }).unwrap_or_default();
+    let writable = nodes.iter().filter(|node| {
+        CString::new(node.as_os_str().as_encoded_bytes())
+            .is_ok_and(|path| unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) } == 0)
+    }).count();
+    if nodes.is_empty() {
+        // Reported by the device check
+    } else if writable == 0 {
+        checks.push(Check::fail(
+            "No permission to open any playback device in /dev/snd",
+            "Join the audio group, or log in on the local seat so logind grants access",
+        ));
+    } else {
+        checks.push(Check::ok(format!("{} of {} playback device node(s) can be opened", writable, nodes.len())));
+    }
+    checks
+}
+
+fn sound_servers() -> Vec<Check> {
+    let servers = running(&["pipewire", "pulseaudio", "jackd", "jackdbus"]);
+    if servers.is_empty() {
+        return vec![Check::ok("No sound server is running")];
+    }
+    let names: Vec<String> = servers.iter().map(|s| s.to_string()).collect();
+    let mut checks = vec![Check::warn(
+        format!("Sound server running: {}", names.join(", ")),
+        "It may hold a card while elitebox wants it; PipeWire lets idle cards go after a few seconds, and --reserve asks it to",
+    )];
+    for d in playback_devices() {
+        let held = holders(d.card, d.device);
+        if !held.is_empty() {
+            let names: Vec<String> = held.iter().map(|h| h.to_string()).collect();
+            checks.push(Check::warn(
+                format!("{} is open by {}", d, names.join(", ")),
+                "Stop playback there, or start elitebox with --reserve",
+            ));
+        }
+    }
+    checks
+}
+
+/// Where ALSA's "default" device leads
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+    PipeWire,
+    PulseAudio,
+    Dmix,
+    /// plug straight onto a card: converts only what the card cannot take
+    Plug,
+    Hw,
+    Other(String),
+}
+
+fn classify(dump: &str) -> Route {
+    if dump.contains("PipeWire") {
+        Route::PipeWire
+    } else if dump.contains("PulseAudio") {
+        Route::PulseAudio
+    } else if dump.contains("Direct Stream Mixing") {
+        Route::Dmix
+    } else if dump.contains("Hardware PCM") {
+        if dump.contains("Plug PCM") { Route::Plug } else { Route::Hw }
+    } else {
+        Route::Other(dump.lines().next().unwrap_or("unknown").trim().to_string())
+    }
+}
+
+fn default_route() -> Check {
+    let dump = PCM::new("default", Direction::Playback, true).and_then(|pcm| {
+        let mut out = Output::buffer_open()?;
+        pcm.dump(&mut out)?;
+        Ok(out.buffer_string(|b| String::from_utf8_lossy(b).into_owned()))
+    });
+    let dump = match dump {
+        Ok(dump) => dump,
+        Err(e) => return Check::warn(
+            format!("ALSA \"default\" cannot be opened: {}", e),
+            "Only matters for the fallback when no hw: device is found",
+        ),
+    };
+    let advice = "elitebox opens hw: devices directly; keep --card pointed at one rather than \"default\"";
+    match classify(&dump) {
+        Route::PipeWire => Check::warn("ALSA \"default\" goes through PipeWire, which resamples and mixes", advice),
+        Route::PulseAudio => Check::warn("ALSA \"default\" goes through PulseAudio, which resamples and mixes", advice),
+        Route::Dmix => Check::warn("ALSA \"default\" goes through dmix, which resamples to a fixed rate", advice),
+        Route::Plug => Check::ok("ALSA \"default\" is plug on a card (converts only formats the card lacks)"),
+        Route::Hw => Check::ok("ALSA \"default\" is a hardware device"),
+        Route::Other(kind) => Check::warn(format!("ALSA \"default\" is {}", kind), advice),
+    }
+}
+
+fn governors() -> Vec<String> {
+    let Ok(cpus) = fs::read_dir("/sys/devices/system/cpu") else { return Vec::new() };
+    cpus.filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_str().is_some_and(|n| n.strip_prefix("cpu").is_some_and(|i| i.parse::<u32>().is_ok())))
+        .filter_map(|e| fs::read_to_string(e.path().join("cpufreq/scaling_governor")).ok())
+        .map(|g| g.trim().to_string())
+        .collect()
+}
+
+fn governor_check(governors: &[String]) -> Check {
+    if governors.is_empty() {
+        return Check::ok("No CPU frequency scaling");
+    }
+    let slow: Vec<&String> = governors.iter().filter(|g| *g != "performance").collect();
+    if slow.is_empty() {
+        return Check::ok(format!("CPU governor is performance on all {} cores", governors.len()));
+    }
+    Check::warn(
+        format!("CPU governor is {} on {} of {} cores", slow[0], slow.len(), governors.len()),
+        "sudo cpupower frequency-set -g performance avoids wake-up latency while the clock ramps",
+    )
+}
+
+fn devices() -> Vec<Check> {
+    let devices = playback_devices();
+    if devices.is_empty() {
+        return vec![Check::fail(
+            "No ALSA playback devices",
+            "Check that the DAC is plugged in and powered, and that the snd modules are loaded",
+        )];
+    }
+    devices.into_iter().map(|d| match BitPerfectDevice::probe(&d.card.to_string(), d.device) {
+        Ok(caps) => Check::ok(format!("{}: {}", d, caps)),
+        Err(e) if e.errno() == libc::EBUSY => Check::warn(
+            format!("{} is busy, capabilities unknown", d),
+            "Run doctor again with nothing playing",
+        ),
+        Err(e) => Check::fail(format!("{} cannot be opened: {}", d, e), "Check permissions on /dev/snd"),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_default_route() {
+        assert_eq!(classify("ALSA <-> PipeWire PCM I/O Plugin\n"), Route::PipeWire);
+        assert_eq!(classify("Plug PCM: Direct Stream Mixing PCM\n"), Route::Dmix);
+        assert_eq!(classify("Plug PCM: Hardware PCM card 0 'HDA Intel' device 0 subdevice 0\n"), Route::Plug);
+        assert_eq!(classify("Hardware PCM card 2 'USB Audio' device 0 subdevice 0\n"), Route::Hw);
+        assert_eq!(classify("Soft volume PCM\n"), Route::Other("Soft volume PCM".into()));
+    }
+
+    #[test]
+    fn test_limit_and_governor_checks() {
+        assert_eq!(rtprio_check(95, false, 95).status, Status::Ok);
+        assert_eq!(rtprio_check(0, false, 95).status, Status::Warn);
+        assert_eq!(rtprio_check(0, true, 95).status, Status::Ok);
+        assert_eq!(memlock_check(8 << 20, false).summary, "memlock limit is 8 MiB; locking the player in memory may fail");
+
+        assert_eq!(governor_check(&[]).status, Status::Ok);
+        let governors = ["performance".to_string(), "powersave".to_string()];
+        let check = governor_check(&governors);
+        assert_eq!(check.status, Status::Warn);
+        assert_eq!(check.summary, "CPU governor is powersave on 1 of 2 cores");
+    }
+}
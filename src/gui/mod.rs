@@ -1,59 +1,282 @@
 use eframe::egui;
 use std::path::{PathBuf, Path};
 use std::sync::{Arc, Mutex, mpsc};
+use std::time::Instant;
+
+use crate::alsa::hotplug::{PcmDevice, Target};
+use crate::config::{Config, Rgb, Theme};
+use crate::player::art::AlbumArt;
+use crate::player::cue;
+use crate::player::lyrics::Lyrics;
+use crate::player::track::Track;
 
 pub enum GuiMessage {
-    AddToPlaylist(PathBuf),
+    AddToPlaylist(Track),
 }
 
 pub struct PlayerState {
     pub current_track: Option<TrackInfo>,
     pub is_playing: bool,
     pub position_secs: f64,
+    /// When `position_secs` was measured, while it is advancing
+    pub position_at: Option<Instant>,
     pub duration_secs: f64,
     pub volume_db: f64,
-    pub playlist: Vec<PathBuf>,
+    pub playlist: Vec<Track>,
     pub command: Option<PlayerCommand>,
     pub error_message: Option<String>,
-    pub album_art: Option<Vec<u8>>,
+    pub album_art: Option<AlbumArt>,
+    pub lyrics: Option<Arc<Lyrics>>,
+    pub repeat: RepeatMode,
+    pub shuffle: ShuffleMode,
+    /// Playlist index the engine is actually playing
+    pub current_index: Option<usize>,
+    pub replaygain: ReplayGainMode,
+    /// ReplayGain adjustment in effect for the current track
+    pub replaygain_db: Option<f64>,
+    /// False while any software processing touches the samples
+    pub bit_perfect: bool,
+    /// Playback PCMs currently plugged in
+    pub devices: Vec<PcmDevice>,
+    /// The output vanished mid-track; playback picks up at `position_secs` when it is back
+    pub device_lost: bool,
+    /// The DAC the engine plays to; change it with `PlayerCommand::SwitchOutput`
+    pub output: Target,
+}
+
+impl PlayerState {
+    /// State for driving the engine without a GUI: playing, at unity gain
+    pub fn headless() -> Self {
+        Self {
+            current_track: None,
+            is_playing: true,
+            position_secs: 0.0,
+            position_at: None,
+            duration_secs: 0.0,
+            volume_db: 0.0,
+            playlist: Vec::new(),
+            command: None,
+            error_message: None,
+            album_art: None,
+            lyrics: None,
+            repeat: RepeatMode::Off,
+            shuffle: ShuffleMode::Off,
+            current_index: None,
+            replaygain: ReplayGainMode::Off,
+            replaygain_db: None,
+            bit_perfect: true,
+            devices: Vec::new(),
+            device_lost: false,
+            output: Target::auto(),
+        }
+    }
+
+    /// Position of what is heard right now. The engine measures it once per
+    /// packet; in between, the time since then is added.
+    pub fn position_now(&self) -> f64 {
+        match self.position_at {
+            // A stalled engine (DAC gone, disk asleep) must not run the clock on
+            Some(at) if self.is_playing => self.position_secs + at.elapsed().as_secs_f64().min(MAX_EXTRAPOLATION),
+            _ => self.position_secs,
+        }
+    }
 }
 
+/// Longest the GUI counts on from the last measured position
+const MAX_EXTRAPOLATION: f64 = 0.5;
+
 #[derive(PartialEq, Clone)]
 pub enum PlayerCommand {
     Next,
     Prev,
     PlayIndex(usize),
+    /// Halt now, rewind and release the device
+    Stop,
+    /// Move playback to another DAC, carrying on from the same position
+    SwitchOutput(Target),
 }
 
-#[derive(PartialEq, Clone)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum RepeatMode {
+    Off,
+    All,
+    One,
+}
+
+impl RepeatMode {
+    pub fn cycle(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            RepeatMode::Off => "🔁 OFF",
+            RepeatMode::All => "🔁 ALL",
+            RepeatMode::One => "🔂 ONE",
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ShuffleMode {
+    Off,
+    Track,
+    Album,
+}
+
+impl ShuffleMode {
+    pub fn cycle(self) -> Self {
+        match self {
+            ShuffleMode::Off => ShuffleMode::Track,
+            ShuffleMode::Track => ShuffleMode::Album,
+            ShuffleMode::Album => ShuffleMode::Off,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ShuffleMode::Off => "🔀 OFF",
+            ShuffleMode::Track => "🔀 TRACK",
+            ShuffleMode::Album => "🔀 ALBUM",
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+}
+
+impl ReplayGainMode {
+    pub fn cycle(self) -> Self {
+        match self {
+            ReplayGainMode::Off => ReplayGainMode::Track,
+            ReplayGainMode::Track => ReplayGainMode::Album,
+            ReplayGainMode::Album => ReplayGainMode::Off,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ReplayGainMode::Off => "RG OFF",
+            ReplayGainMode::Track => "RG TRACK",
+            ReplayGainMode::Album => "RG ALBUM",
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Default)]
 pub struct TrackInfo {
+    pub path: PathBuf,
     pub filename: String,
     pub sample_rate: u32,
     pub bit_depth: u16,
     pub title: Option<String>,
     pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub track_total: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub disc_total: Option<u32>,
+    pub year: Option<u16>,
+    pub genre: Option<String>,
+    pub composer: Option<String>,
+    /// Codec name as the decoder reports it, e.g. "Free Lossless Audio Codec"
+    pub codec: Option<String>,
+    /// Average over the whole file
+    pub bitrate_kbps: Option<u32>,
+    /// "Mono", "Stereo", "5.1", ...
+    pub channels: Option<String>,
+    pub file_size: Option<u64>,
+    pub encoder: Option<String>,
+    /// Every tag in the file, standard or not, as (key, value)
+    pub tags: Vec<(String, String)>,
+}
+
+impl TrackInfo {
+    /// Fill whatever is missing here from another source, e.g. a CUE track
+    /// from the file it is cut from
+    pub fn or(self, other: TrackInfo) -> Self {
+        Self {
+            path: self.path,
+            filename: self.filename,
+            sample_rate: if self.sample_rate > 0 { self.sample_rate } else { other.sample_rate },
+            bit_depth: if self.bit_depth > 0 { self.bit_depth } else { other.bit_depth },
+            title: self.title.or(other.title),
+            artist: self.artist.or(other.artist),
+            album: self.album.or(other.album),
+            album_artist: self.album_artist.or(other.album_artist),
+            track_number: self.track_number.or(other.track_number),
+            track_total: self.track_total.or(other.track_total),
+            disc_number: self.disc_number.or(other.disc_number),
+            disc_total: self.disc_total.or(other.disc_total),
+            year: self.year.or(other.year),
+            genre: self.genre.or(other.genre),
+            composer: self.composer.or(other.composer),
+            codec: self.codec.or(other.codec),
+            bitrate_kbps: self.bitrate_kbps.or(other.bitrate_kbps),
+            channels: self.channels.or(other.channels),
+            file_size: self.file_size.or(other.file_size),
+            encoder: self.encoder.or(other.encoder),
+            tags: if self.tags.is_empty() { other.tags } else { self.tags },
+        }
+    }
 }
 
 pub struct SucklessPlayer {
-    tx: mpsc::Sender<GuiMessage>,
+    /// Files and folders to add, for the thread that turns them into tracks
+    adder: mpsc::Sender<PathBuf>,
     player: Arc<Mutex<PlayerState>>,
     current_dir: PathBuf,
     files: Vec<PathBuf>,
-    current_track: usize,
+    scrolled_to: Option<usize>,
     selected_idx: usize,
     dragging_path: Option<PathBuf>,
+    theme: Theme,
+    keys: Vec<(egui::Key, &'static str)>,
+    roots: Vec<PathBuf>,
+    max_volume_db: f64,
+    /// Decoded covers by album key, least recently shown first
+    covers: Vec<(String, Option<egui::TextureHandle>)>,
+    /// Lyrics line the panel last scrolled to
+    lyrics_line: Option<usize>,
+}
+
+/// Covers kept decoded
+const COVER_CACHE: usize = 16;
+/// Covers are scaled to this; the panel shows them at 300 points, twice that on HiDPI screens
+const COVER_PIXELS: u32 = 600;
+
+fn color(c: Rgb) -> egui::Color32 {
+    egui::Color32::from_rgb(c.0, c.1, c.2)
 }
 
 impl SucklessPlayer {
-    pub fn new(tx: mpsc::Sender<GuiMessage>, player: Arc<Mutex<PlayerState>>) -> Self {
+    pub fn new(tx: mpsc::Sender<GuiMessage>, player: Arc<Mutex<PlayerState>>, config: &Config) -> Self {
         let mut player = Self {
-            tx,
+            adder: spawn_adder(tx),
             player,
-            current_dir: PathBuf::from("."),
+            current_dir: config.library.roots.first().cloned().unwrap_or_else(|| PathBuf::from(".")),
             files: Vec::new(),
-            current_track: 0,
+            scrolled_to: None,
             selected_idx: 0,
             dragging_path: None,
+            theme: config.theme.clone(),
+            // Names were checked when the config was loaded
+            keys: config.keys.actions().into_iter()
+                .filter_map(|(action, name)| egui::Key::from_name(name).map(|key| (key, action)))
+                .collect(),
+            roots: config.library.roots.clone(),
+            max_volume_db: config.volume.max_db,
+            covers: Vec::new(),
+            lyrics_line: None,
         };
         player.refresh_files();
         player
@@ -96,10 +319,10 @@ impl SucklessPlayer {
             (TextStyle::Monospace, FontId::new(14.0, FontFamily::Proportional)),
         ].into();
         style.visuals = egui::Visuals::dark();
-        style.visuals.override_text_color = Some(egui::Color32::from_rgb(0xeb, 0xdb, 0xb2));
-        style.visuals.panel_fill = egui::Color32::from_rgb(0x1d, 0x20, 0x21);
-        style.visuals.widgets.inactive.bg_fill = egui::Color32::from_rgb(0x28, 0x28, 0x28);
-        style.visuals.widgets.active.bg_fill = egui::Color32::from_rgb(0x45, 0x85, 0x88);
+        style.visuals.override_text_color = Some(color(self.theme.text));
+        style.visuals.panel_fill = color(self.theme.background);
+        style.visuals.widgets.inactive.bg_fill = color(self.theme.widget);
+        style.visuals.widgets.active.bg_fill = color(self.theme.active);
         style.visuals.window_rounding = 0.0.into();
         style.visuals.widgets.inactive.rounding = 0.0.into();
         style.spacing.item_spacing = egui::vec2(8.0, 4.0);
@@ -111,7 +334,7 @@ impl SucklessPlayer {
             if !i.raw.dropped_files.is_empty() {
                 for file in &i.raw.dropped_files {
                     if let Some(path) = &file.path {
-                        self.add_path_to_playlist_recursive(path);
+                        self.add_to_playlist(path);
                     }
                 }
             }
@@ -119,15 +342,13 @@ impl SucklessPlayer {
 
         let mut cmd = None;
         ctx.input(|i| {
-            if i.key_pressed(egui::Key::Space) { cmd = Some("toggle"); }
-            if i.key_pressed(egui::Key::J) || i.key_pressed(egui::Key::ArrowDown) { cmd = Some("down"); }
-            if i.key_pressed(egui::Key::K) || i.key_pressed(egui::Key::ArrowUp) { cmd = Some("up"); }
-            if i.key_pressed(egui::Key::L) || i.key_pressed(egui::Key::Enter) { cmd = Some("enter"); }
-            if i.key_pressed(egui::Key::H) || i.key_pressed(egui::Key::Backspace) { cmd = Some("back"); }
-            if i.key_pressed(egui::Key::N) { cmd = Some("next"); }
-            if i.key_pressed(egui::Key::P) { cmd = Some("prev"); }
-            if i.key_pressed(egui::Key::S) { cmd = Some("stop"); }
-            if i.key_pressed(egui::Key::Q) { ctx.send_viewport_cmd(egui::ViewportCommand::Close); }
+            for (key, action) in &self.keys {
+                if i.key_pressed(*key) { cmd = Some(*action); }
+            }
+            if i.key_pressed(egui::Key::ArrowDown) { cmd = Some("down"); }
+            if i.key_pressed(egui::Key::ArrowUp) { cmd = Some("up"); }
+            if i.key_pressed(egui::Key::Enter) { cmd = Some("enter"); }
+            if i.key_pressed(egui::Key::Backspace) { cmd = Some("back"); }
         });
         match cmd {
             Some("toggle") => self.toggle_playback(),
@@ -138,14 +359,26 @@ impl SucklessPlayer {
             Some("next") => self.next(),
             Some("prev") => self.prev(),
             Some("stop") => self.stop(),
+            Some("repeat") => self.cycle_repeat(),
+            Some("shuffle") => self.cycle_shuffle(),
+            Some("replaygain") => self.cycle_replaygain(),
+            Some("quit") => ctx.send_viewport_cmd(egui::ViewportCommand::Close),
             _ => {}
         }
     }
 
     fn render_transport_controls(&mut self, ui: &mut egui::Ui) {
-        let (playing, current_track, position, duration) = {
+        let (playing, current_track, position, duration, repeat, shuffle) = {
             let state = self.player.lock().unwrap();
-            (state.is_playing, state.current_track.clone(), state.position_secs, state.duration_secs)
+            (state.is_playing, state.current_track.clone(), state.position_now(), state.duration_secs, state.repeat, state.shuffle)
+        };
+        let (replaygain, replaygain_db, bit_perfect) = {
+            let state = self.player.lock().unwrap();
+            (state.replaygain, state.replaygain_db, state.bit_perfect)
+        };
+        let (devices, device_lost, output) = {
+            let state = self.player.lock().unwrap();
+            (state.devices.clone(), state.device_lost, state.output.clone())
         };
 
         ui.vertical(|ui| {
@@ -157,8 +390,16 @@ impl SucklessPlayer {
                     } else {
                         track.filename.clone()
                     };
-                    ui.label(egui::RichText::new(display_name).color(egui::Color32::from_rgb(0xba, 0xbd, 0x2f)));
+                    ui.label(egui::RichText::new(display_name).color(color(self.theme.accent)));
                     ui.label(format!("| {}Hz / {}bit", track.sample_rate, track.bit_depth));
+                    if let Some(db) = replaygain_db {
+                        ui.label(format!("| RG {:+.1} dB", db));
+                    }
+                    if bit_perfect {
+                        ui.label(egui::RichText::new("| BIT-PERFECT").color(color(self.theme.bit_perfect)));
+                    } else {
+                        ui.label(egui::RichText::new("| MODIFIED (software gain)").color(color(self.theme.warning)));
+                    }
                 } else { ui.label("[Stopped]"); }
             });
 
@@ -176,39 +417,145 @@ impl SucklessPlayer {
                 if ui.button(btn_text).clicked() { self.toggle_playback(); }
                 if ui.button("⏹ STOP").clicked() { self.stop(); }
                 if ui.button("⏭ NEXT").clicked() { self.next(); }
-                
+                if ui.button(repeat.label()).clicked() { self.cycle_repeat(); }
+                if ui.button(shuffle.label()).clicked() { self.cycle_shuffle(); }
+                if ui.button(replaygain.label()).clicked() { self.cycle_replaygain(); }
+
+                ui.add_space(20.0);
+                let selected = devices.iter().find(|d| d.is(&output))
+                    .map(|d| format!("{}: {}", d.card_id, d.name))
+                    .unwrap_or_else(|| output.to_string());
+                egui::ComboBox::from_id_source("output_device")
+                    .selected_text(selected)
+                    .width(220.0)
+                    .show_ui(ui, |ui| {
+                        if ui.selectable_label(output.is_auto(), "Auto").clicked() && !output.is_auto() {
+                            self.switch_output(Target::auto());
+                        }
+                        for d in &devices {
+                            let caps = d.caps.as_ref().map_or("busy, capabilities unknown".to_string(), |c| c.to_string());
+                            let label = format!("{}\n    {}", d, caps);
+                            if ui.selectable_label(d.is(&output), label).clicked() && !d.is(&output) {
+                                self.switch_output(Target { card: d.card_id.clone(), device: d.device });
+                            }
+                        }
+                    });
+                if device_lost {
+                    ui.label(egui::RichText::new("DAC DISCONNECTED").color(color(self.theme.warning)));
+                }
+
                 ui.add_space(20.0);
                 ui.label("Volume:");
                 let mut state = self.player.lock().unwrap();
-                ui.add(egui::Slider::new(&mut state.volume_db, -60.0..=0.0).show_value(true));
+                ui.add(egui::Slider::new(&mut state.volume_db, -60.0..=self.max_volume_db).show_value(true));
             });
         });
     }
 
     fn render_album_art(&mut self, ui: &mut egui::Ui) {
-        let (art, filename) = {
-            let state = self.player.lock().unwrap();
-            (state.album_art.clone(), state.current_track.as_ref().map(|t| t.filename.clone()))
-        };
+        let art = self.player.lock().unwrap().album_art.clone();
+        let texture = art.and_then(|art| self.cover_texture(ui.ctx(), &art));
 
-        if let Some(data) = art {
-            let uri = format!("bytes://{}.jpg", filename.unwrap_or_default());
-            let image = egui::Image::from_bytes(uri, data)
+        if let Some(texture) = texture {
+            let image = egui::Image::from_texture(&texture)
                 .rounding(4.0)
                 .fit_to_exact_size(egui::vec2(300.0, 300.0));
             ui.add(image);
         } else {
             // Placeholder
             let (rect, _) = ui.allocate_at_least(egui::vec2(300.0, 300.0), egui::Sense::hover());
-            ui.painter().rect_filled(rect, 4.0, egui::Color32::from_rgb(0x28, 0x28, 0x28));
-            ui.painter().text(rect.center(), egui::Align2::CENTER_CENTER, "♫", egui::FontId::proportional(64.0), egui::Color32::from_rgb(0x1d, 0x20, 0x21));
+            ui.painter().rect_filled(rect, 4.0, color(self.theme.widget));
+            ui.painter().text(rect.center(), egui::Align2::CENTER_CENTER, "♫", egui::FontId::proportional(64.0), color(self.theme.background));
+        }
+    }
+
+    /// Each album's cover is decoded once, scaled down to the panel; the
+    /// most recently shown ones stay loaded. Covers that fail to decode are
+    /// remembered too, so they are not retried every frame.
+    fn cover_texture(&mut self, ctx: &egui::Context, art: &AlbumArt) -> Option<egui::TextureHandle> {
+        if let Some(i) = self.covers.iter().position(|(key, _)| *key == art.key) {
+            let entry = self.covers.remove(i);
+            self.covers.push(entry);
+            return self.covers.last().and_then(|(_, texture)| texture.clone());
+        }
+        let texture = decode_cover(art)
+            .map(|image| ctx.load_texture(format!("cover:{}", art.key), image, egui::TextureOptions::LINEAR));
+        if self.covers.len() == COVER_CACHE {
+            self.covers.remove(0);
         }
+        self.covers.push((art.key.clone(), texture.clone()));
+        texture
+    }
+
+    fn render_track_details(&mut self, ui: &mut egui::Ui) {
+        let Some(track) = self.player.lock().unwrap().current_track.clone() else { return };
+        let of = |n: Option<u32>, total: Option<u32>| match (n, total) {
+            (Some(n), Some(t)) => Some(format!("{}/{}", n, t)),
+            (n, _) => n.map(|n| n.to_string()),
+        };
+        let rows = [
+            ("Title", track.title.clone()),
+            ("Artist", track.artist.clone()),
+            ("Album", track.album.clone()),
+            ("Album artist", track.album_artist.clone()),
+            ("Track", of(track.track_number, track.track_total)),
+            ("Disc", of(track.disc_number, track.disc_total)),
+            ("Year", track.year.map(|y| y.to_string())),
+            ("Genre", track.genre.clone()),
+            ("Composer", track.composer.clone()),
+            ("Codec", track.codec.clone()),
+            ("Format", Some(format!("{} Hz / {} bit", track.sample_rate, track.bit_depth))),
+            ("Channels", track.channels.clone()),
+            ("Bitrate", track.bitrate_kbps.map(|b| format!("{} kbps", b))),
+            ("File size", track.file_size.map(|b| format!("{:.1} MB", b as f64 / 1_048_576.0))),
+            ("Encoder", track.encoder.clone()),
+            ("File", Some(track.filename.clone())),
+        ];
+
+        egui::CollapsingHeader::new("Track details").id_source("track_details").show(ui, |ui| {
+            egui::Grid::new("track_details_grid").num_columns(2).striped(true).show(ui, |ui| {
+                for (name, value) in rows.iter().filter_map(|(n, v)| v.as_ref().map(|v| (n, v))) {
+                    ui.label(egui::RichText::new(*name).color(egui::Color32::GRAY));
+                    ui.label(value);
+                    ui.end_row();
+                }
+            });
+            egui::CollapsingHeader::new(format!("All tags ({})", track.tags.len()))
+                .id_source("all_tags")
+                .show(ui, |ui| {
+                    egui::ScrollArea::vertical().id_source("all_tags_scroll").max_height(200.0).show(ui, |ui| {
+                        egui::Grid::new("all_tags_grid").num_columns(2).striped(true).show(ui, |ui| {
+                            for (key, value) in &track.tags {
+                                ui.label(egui::RichText::new(key).color(egui::Color32::GRAY));
+                                ui.label(value);
+                                ui.end_row();
+                            }
+                        });
+                    });
+                });
+        });
     }
 
     fn render_file_browser(&mut self, ui: &mut egui::Ui) {
         ui.add_space(8.0);
-        ui.label(egui::RichText::new(format!("📁 BROWSER")).strong());
+        ui.label(egui::RichText::new("📁 BROWSER").strong());
         ui.label(egui::RichText::new(format!("{}", self.current_dir.display())).size(12.0).color(egui::Color32::GRAY));
+        if !self.roots.is_empty() {
+            let mut go_to = None;
+            ui.horizontal_wrapped(|ui| {
+                for root in &self.roots {
+                    let name = root.file_name().map_or_else(|| root.to_string_lossy(), |n| n.to_string_lossy());
+                    if ui.small_button(format!("⌂ {}", name)).on_hover_text(root.display().to_string()).clicked() {
+                        go_to = Some(root.clone());
+                    }
+                }
+            });
+            if let Some(root) = go_to {
+                self.current_dir = root;
+                self.refresh_files();
+                self.selected_idx = 0;
+            }
+        }
         ui.separator();
         let files = self.files.clone();
         egui::ScrollArea::vertical()
@@ -232,7 +579,7 @@ impl SucklessPlayer {
     fn render_playlist(&mut self, ui: &mut egui::Ui) {
         let (playlist, cur_idx) = {
             let state = self.player.lock().unwrap();
-            (state.playlist.clone(), self.current_track)
+            (state.playlist.clone(), state.current_index)
         };
         
         let rect = ui.available_rect_before_wrap();
@@ -245,23 +592,57 @@ impl SucklessPlayer {
             .id_source("playlist")
             .auto_shrink([false; 2])
             .show(ui, |ui| {
-                for (idx, path) in playlist.iter().enumerate() {
-                    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
-                    let is_current = idx == cur_idx;
+                for (idx, track) in playlist.iter().enumerate() {
+                    let name = track.name();
+                    let is_current = Some(idx) == cur_idx;
                     let text = if is_current { format!("▶ {}", name) } else { format!("  {}", name) };
-                    if ui.selectable_label(is_current, text).clicked() { self.play_index(idx); }
+                    let response = ui.selectable_label(is_current, text);
+                    // Follow the engine to the new row once, then leave scrolling to the user
+                    if is_current && self.scrolled_to != cur_idx {
+                        response.scroll_to_me(Some(egui::Align::Center));
+                    }
+                    if response.clicked() { self.play_index(idx); }
                 }
             });
+        self.scrolled_to = cur_idx;
 
-        if ui.input(|i| i.pointer.any_released()) {
-            if let Some(path) = self.dragging_path.take() {
-                if ui.rect_contains_pointer(rect) {
-                    self.add_path_to_playlist_recursive(&path);
-                }
-            }
+        if ui.input(|i| i.pointer.any_released())
+            && let Some(path) = self.dragging_path.take()
+            && ui.rect_contains_pointer(rect)
+        {
+            self.add_to_playlist(&path);
         }
     }
 
+    fn render_lyrics(&mut self, ui: &mut egui::Ui, lyrics: &Lyrics) {
+        let position = self.player.lock().unwrap().position_now();
+        let current = lyrics.current_line(position);
+
+        ui.add_space(8.0);
+        ui.label(egui::RichText::new("LYRICS").strong());
+        ui.separator();
+        egui::ScrollArea::vertical()
+            .id_source("lyrics")
+            .auto_shrink([false; 2])
+            .show(ui, |ui| {
+                for (idx, line) in lyrics.lines.iter().enumerate() {
+                    let text = egui::RichText::new(&line.text);
+                    let response = if Some(idx) == current {
+                        ui.label(text.strong().color(color(self.theme.accent)))
+                    } else if lyrics.is_synced() {
+                        ui.label(text.color(egui::Color32::GRAY))
+                    } else {
+                        ui.label(text)
+                    };
+                    // Keep the sung line in the middle as it changes
+                    if Some(idx) == current && self.lyrics_line != current {
+                        response.scroll_to_me(Some(egui::Align::Center));
+                    }
+                }
+            });
+        self.lyrics_line = current;
+    }
+
     fn toggle_playback(&mut self) { 
         let mut state = self.player.lock().unwrap();
         if !state.playlist.is_empty() { state.is_playing = !state.is_playing; }
@@ -285,23 +666,13 @@ impl SucklessPlayer {
             self.current_dir = path;
             self.refresh_files();
             self.selected_idx = 0;
-        } else if is_audio_file(&path) {
-            self.tx.send(GuiMessage::AddToPlaylist(path)).unwrap();
+        } else if is_audio_file(&path) || cue::is_cue_file(&path) {
+            self.add_to_playlist(&path);
         }
     }
 
-    fn add_path_to_playlist_recursive(&self, path: &Path) {
-        if path.is_dir() {
-            if let Ok(entries) = std::fs::read_dir(path) {
-                let mut entries: Vec<_> = entries.filter_map(|e| e.ok()).collect();
-                entries.sort_by_key(|e| e.path());
-                for entry in entries {
-                    self.add_path_to_playlist_recursive(&entry.path());
-                }
-            }
-        } else if is_audio_file(path) {
-            self.tx.send(GuiMessage::AddToPlaylist(path.to_path_buf())).unwrap();
-        }
+    fn add_to_playlist(&self, path: &Path) {
+        self.adder.send(path.to_path_buf()).unwrap();
     }
 
     fn prev(&mut self) { self.player.lock().unwrap().command = Some(PlayerCommand::Prev); }
@@ -309,10 +680,24 @@ impl SucklessPlayer {
     fn stop(&mut self) { 
         let mut state = self.player.lock().unwrap();
         state.is_playing = false;
-        state.command = Some(PlayerCommand::PlayIndex(0)); 
+        state.command = Some(PlayerCommand::Stop); 
+    }
+    fn switch_output(&mut self, output: Target) {
+        self.player.lock().unwrap().command = Some(PlayerCommand::SwitchOutput(output));
+    }
+    fn cycle_repeat(&mut self) {
+        let mut state = self.player.lock().unwrap();
+        state.repeat = state.repeat.cycle();
+    }
+    fn cycle_shuffle(&mut self) {
+        let mut state = self.player.lock().unwrap();
+        state.shuffle = state.shuffle.cycle();
+    }
+    fn cycle_replaygain(&mut self) {
+        let mut state = self.player.lock().unwrap();
+        state.replaygain = state.replaygain.cycle();
     }
     fn play_index(&mut self, idx: usize) {
-        self.current_track = idx;
         self.player.lock().unwrap().command = Some(PlayerCommand::PlayIndex(idx));
     }
 }
@@ -329,7 +714,7 @@ impl eframe::App for SucklessPlayer {
             ui.add_space(4.0);
             let error = self.player.lock().unwrap().error_message.clone();
             if let Some(msg) = error {
-                ui.colored_label(egui::Color32::from_rgb(0xfb, 0x49, 0x34), format!("⚠ {}", msg));
+                ui.colored_label(color(self.theme.error), format!("⚠ {}", msg));
             }
             self.render_transport_controls(ui);
             ui.add_space(4.0);
@@ -343,11 +728,25 @@ impl eframe::App for SucklessPlayer {
             .show(ctx, |ui| {
                 ui.add_space(8.0);
                 self.render_album_art(ui);
+                self.render_track_details(ui);
                 ui.add_space(8.0);
                 ui.separator();
                 self.render_file_browser(ui);
             });
 
+        // Lyrics, when the current track has any
+        let lyrics = {
+            let state = self.player.lock().unwrap();
+            state.current_track.as_ref().and(state.lyrics.clone())
+        };
+        if let Some(lyrics) = lyrics {
+            egui::SidePanel::right("lyrics_panel")
+                .resizable(true)
+                .default_width(320.0)
+                .width_range(200.0..=600.0)
+                .show(ctx, |ui| self.render_lyrics(ui, &lyrics));
+        }
+
         // 3. Central Panel: Playlist (Fills remaining space)
         egui::CentralPanel::default().show(ctx, |ui| {
             self.render_playlist(ui);
@@ -370,7 +769,59 @@ impl eframe::App for SucklessPlayer {
     }
 }
 
-fn is_audio_file(path: &Path) -> bool {
-    matches!(path.extension().and_then(|s| s.to_str().map(|s| s.to_lowercase())), 
-        Some(ext) if ext == "flac" || ext == "wav" || ext == "mp3" || ext == "aac")
+/// Folder images are read here, once per album, never on the audio thread
+fn decode_cover(art: &AlbumArt) -> Option<egui::ColorImage> {
+    let (mime, data) = art.load()?;
+    let format = image::ImageFormat::from_mime_type(mime)?;
+    let image = image::load_from_memory_with_format(&data, format)
+        .inspect_err(|e| eprintln!("Cannot decode {} cover art: {}", mime, e))
+        .ok()?
+        .thumbnail(COVER_PIXELS, COVER_PIXELS)
+        .to_rgba8();
+    let size = [image.width() as usize, image.height() as usize];
+    Some(egui::ColorImage::from_rgba_unmultiplied(size, image.as_raw()))
+}
+
+/// Adds files and folders to the playlist in order, on a thread of its own:
+/// walking folders and expanding CUE sheets, which means probing the audio
+/// they describe, would hold up the GUI
+fn spawn_adder(tx: mpsc::Sender<GuiMessage>) -> mpsc::Sender<PathBuf> {
+    let (adder, paths) = mpsc::channel::<PathBuf>();
+    std::thread::spawn(move || {
+        for path in paths {
+            if add_recursive(&tx, &path).is_err() {
+                break;
+            }
+        }
+    });
+    adder
+}
+
+/// Stops once the engine has gone
+fn add_recursive(tx: &mpsc::Sender<GuiMessage>, path: &Path) -> Result<(), mpsc::SendError<GuiMessage>> {
+    if path.is_dir() {
+        if let Ok(entries) = std::fs::read_dir(path) {
+            // An album rip with its .cue is added once, as the sheet's tracks
+            let covered = cue::covered_files(path);
+            let mut entries: Vec<_> = entries.filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| !covered.contains(p))
+                .collect();
+            entries.sort();
+            for entry in entries {
+                add_recursive(tx, &entry)?;
+            }
+        }
+    } else if is_audio_file(path) || cue::is_cue_file(path) {
+        // The file as a whole, or the tracks of its CUE sheet
+        for track in cue::tracks_for(path) {
+            tx.send(GuiMessage::AddToPlaylist(track))?;
+        }
+    }
+    Ok(())
+}
+
+/// Accept files by what they contain, not by their extension, and only
+/// formats the player can decode
+pub fn is_audio_file(path: &Path) -> bool {
+    path.is_file() && crate::player::formats::sniff_file(path).is_some_and(|f| f.is_decodable())
 }
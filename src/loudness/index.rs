@@ -0,0 +1,97 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::loudness::Loudness;
+use crate::player::replaygain::ReplayGain;
+
+/// One index per album directory, next to the audio files
+pub const INDEX_FILE: &str = ".elitebox-loudness";
+
+const HEADER: &str = "# elitebox loudness index v1\n\
+# file\tintegrated_lufs\tlra_lu\ttrue_peak_dbtp\ttrack_gain_db\ttrack_peak\talbum_gain_db\talbum_peak\n";
+
+pub struct IndexEntry {
+    pub file: String,
+    pub track: Loudness,
+    pub album: Loudness,
+}
+
+/// Write (replace) the index of `dir`
+pub fn write(dir: &Path, entries: &[IndexEntry]) -> io::Result<()> {
+    let mut out = fs::File::create(dir.join(INDEX_FILE))?;
+    out.write_all(HEADER.as_bytes())?;
+    for e in entries {
+        writeln!(
+            out,
+            "{}\t{:.2}\t{:.2}\t{:.2}\t{}\t{:.6}\t{}\t{:.6}",
+            e.file,
+            e.track.integrated,
+            e.track.range,
+            e.track.true_peak_dbtp(),
+            gain_field(e.track.replaygain_db()),
+            e.track.true_peak,
+            gain_field(e.album.replaygain_db()),
+            e.album.true_peak,
+        )?;
+    }
+    Ok(())
+}
+
+/// Silent tracks have no gain; the field is left empty
+fn gain_field(gain: Option<f64>) -> String {
+    gain.map_or(String::new(), |g| format!("{:.2}", g))
+}
+
+/// ReplayGain for `path` from the index in its directory, if it was scanned
+pub fn lookup(path: &Path) -> Option<ReplayGain> {
+    let name = path.file_name()?.to_str()?;
+    let index = fs::read_to_string(path.parent()?.join(INDEX_FILE)).ok()?;
+    parse_line(&index, name)
+}
+
+fn parse_line(index: &str, name: &str) -> Option<ReplayGain> {
+    let line = index.lines()
+        .filter(|l| !l.starts_with('#'))
+        .find(|l| l.split('\t').next() == Some(name))?;
+    // By column: an empty or non-finite field is a missing value, not a shift
+    let fields: Vec<Option<f64>> = line.split('\t').skip(1)
+        .map(|f| f.parse::<f64>().ok().filter(|v| v.is_finite()))
+        .collect();
+    if fields.len() < 7 {
+        return None;
+    }
+    Some(ReplayGain {
+        track_gain: fields[3],
+        track_peak: fields[4],
+        album_gain: fields[5],
+        album_peak: fields[6],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::pcm::tests::TempPath;
+
+    #[test]
+    fn test_index_round_trip() {
+        let dir = TempPath::dir("index");
+        let track = Loudness { integrated: -12.0, range: 5.0, true_peak: 0.9 };
+        let album = Loudness { integrated: -11.0, range: 6.0, true_peak: 0.95 };
+        write(&dir, &[IndexEntry { file: "01 Intro.flac".into(), track, album }]).unwrap();
+
+        let rg = lookup(&dir.join("01 Intro.flac")).unwrap();
+        assert_eq!(rg.track_gain, Some(-6.0));
+        assert_eq!(rg.track_peak, Some(0.9));
+        assert_eq!(rg.album_gain, Some(-7.0));
+        assert!(lookup(&dir.join("02.flac")).is_none());
+
+        // A silent track is indexed, but without a gain to apply
+        let silent = Loudness { integrated: f64::NEG_INFINITY, range: 0.0, true_peak: 0.0 };
+        write(&dir, &[IndexEntry { file: "99 Silence.flac".into(), track: silent, album }]).unwrap();
+        let rg = lookup(&dir.join("99 Silence.flac")).unwrap();
+        assert_eq!((rg.track_gain, rg.track_peak), (None, Some(0.0)));
+        assert_eq!(rg.album_gain, Some(-7.0));
+    }
+}
@@ -0,0 +1,338 @@
+pub mod index;
+pub mod scan;
+
+use std::f64::consts::PI;
+
+use symphonia::core::audio::Channels;
+
+/// BS.1770 gating blocks are 400 ms, hopping every 100 ms
+const SUBBLOCKS_PER_BLOCK: usize = 4;
+/// EBU Tech 3342 short-term windows for loudness range are 3 s
+const SUBBLOCKS_PER_SHORT_TERM: usize = 30;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+/// Loudness figures of one track or album
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness, LUFS
+    pub integrated: f64,
+    /// Loudness range, LU
+    pub range: f64,
+    /// True peak, linear full scale
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// ReplayGain 2.0 puts the reference at -18 LUFS. Silence, or anything
+    /// below the absolute gate, has no loudness to correct and gets no gain.
+    pub fn replaygain_db(&self) -> Option<f64> {
+        self.integrated.is_finite().then(|| -18.0 - self.integrated)
+    }
+
+    pub fn true_peak_dbtp(&self) -> f64 {
+        20.0 * self.true_peak.log10()
+    }
+}
+
+/// ITU-R BS.1770 / EBU R128 meter for one track.
+///
+/// Feed interleaved float samples with `process`; the per-block energies are
+/// kept so several meters can be combined into an album measurement.
+pub struct LoudnessMeter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<KWeighting>,
+    oversampler: Vec<TruePeak>,
+    subblock_len: usize,
+    subblock_pos: usize,
+    subblock_sum: f64,
+    subblocks: Vec<f64>,
+    peak: f64,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channels: Channels) -> Self {
+        let count = channels.count().max(1);
+        let weights = if channels.bits() == 0 {
+            vec![1.0; count]
+        } else {
+            channels.iter().map(channel_weight).collect()
+        };
+        Self {
+            channels: count,
+            weights,
+            filters: (0..count).map(|_| KWeighting::new(sample_rate as f64)).collect(),
+            oversampler: (0..count).map(|_| TruePeak::new(sample_rate)).collect(),
+            subblock_len: (sample_rate as usize / 10).max(1),
+            subblock_pos: 0,
+            subblock_sum: 0.0,
+            subblocks: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, interleaved: &[f32]) {
+        for frame in interleaved.chunks_exact(self.channels) {
+            let mut sum = 0.0;
+            for (ch, &x) in frame.iter().enumerate() {
+                let x = x as f64;
+                let y = self.filters[ch].process(x);
+                sum += self.weights[ch] * y * y;
+                self.peak = self.peak.max(self.oversampler[ch].process(x));
+            }
+            self.subblock_sum += sum;
+            self.subblock_pos += 1;
+            if self.subblock_pos == self.subblock_len {
+                self.subblocks.push(self.subblock_sum / self.subblock_len as f64);
+                self.subblock_sum = 0.0;
+                self.subblock_pos = 0;
+            }
+        }
+    }
+
+    pub fn finish(&self) -> Loudness {
+        measure(std::slice::from_ref(self))
+    }
+}
+
+/// Combined measurement over several tracks, as if they were played back to back
+pub fn measure(meters: &[LoudnessMeter]) -> Loudness {
+    let blocks: Vec<f64> = meters.iter()
+        .flat_map(|m| windows(&m.subblocks, SUBBLOCKS_PER_BLOCK))
+        .collect();
+    let short_term: Vec<f64> = meters.iter()
+        .flat_map(|m| windows(&m.subblocks, SUBBLOCKS_PER_SHORT_TERM))
+        .collect();
+    Loudness {
+        integrated: gated_loudness(&blocks, -10.0),
+        range: loudness_range(&short_term),
+        true_peak: meters.iter().map(|m| m.peak).fold(0.0, f64::max),
+    }
+}
+
+/// Mean energy of every run of `len` consecutive 100 ms sub-blocks
+fn windows(subblocks: &[f64], len: usize) -> Vec<f64> {
+    subblocks.windows(len).map(|w| w.iter().sum::<f64>() / len as f64).collect()
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn lufs_to_energy(lufs: f64) -> f64 {
+    10.0f64.powf((lufs + 0.691) / 10.0)
+}
+
+fn gated_loudness(blocks: &[f64], relative_gate: f64) -> f64 {
+    let abs_gate = lufs_to_energy(ABSOLUTE_GATE_LUFS);
+    let above_abs: Vec<f64> = blocks.iter().copied().filter(|&e| e > abs_gate).collect();
+    if above_abs.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+    let rel_gate = lufs_to_energy(energy_to_lufs(mean(&above_abs)) + relative_gate);
+    let gated: Vec<f64> = above_abs.into_iter().filter(|&e| e > rel_gate).collect();
+    energy_to_lufs(mean(&gated))
+}
+
+/// EBU Tech 3342: spread between the 10th and 95th percentile of gated short-term loudness
+fn loudness_range(short_term: &[f64]) -> f64 {
+    let abs_gate = lufs_to_energy(ABSOLUTE_GATE_LUFS);
+    let above_abs: Vec<f64> = short_term.iter().copied().filter(|&e| e > abs_gate).collect();
+    if above_abs.is_empty() {
+        return 0.0;
+    }
+    let rel_gate = lufs_to_energy(energy_to_lufs(mean(&above_abs)) - 20.0);
+    let mut values: Vec<f64> = above_abs.into_iter()
+        .filter(|&e| e > rel_gate)
+        .map(energy_to_lufs)
+        .collect();
+    values.sort_by(f64::total_cmp);
+    let percentile = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+    percentile(0.95) - percentile(0.10)
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// BS.1770 channel weighting: surrounds count +1.5 dB, LFE is ignored
+fn channel_weight(channel: Channels) -> f64 {
+    if channel.intersects(Channels::LFE1 | Channels::LFE2) {
+        0.0
+    } else if channel.intersects(Channels::SIDE_LEFT | Channels::SIDE_RIGHT | Channels::REAR_LEFT | Channels::REAR_RIGHT) {
+        1.41
+    } else {
+        1.0
+    }
+}
+
+/// Direct form I biquad
+#[derive(Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0] - self.a[2] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// BS.1770 K-weighting: high shelf followed by the RLB high pass.
+/// Coefficients are derived for any rate the same way libebur128 does it.
+struct KWeighting {
+    shelf: Biquad,
+    highpass: Biquad,
+}
+
+impl KWeighting {
+    fn new(rate: f64) -> Self {
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10.0f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Default::default()
+        };
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let highpass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Default::default()
+        };
+        Self { shelf, highpass }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.highpass.process(self.shelf.process(x))
+    }
+}
+
+/// True peak per BS.1770 Annex 2: oversample to at least 192 kHz and take the
+/// largest absolute value of the interpolated signal
+struct TruePeak {
+    factor: usize,
+    taps: Vec<Vec<f64>>,
+    history: Vec<f64>,
+    pos: usize,
+}
+
+impl TruePeak {
+    const TAPS_PER_PHASE: usize = 12;
+
+    fn new(rate: u32) -> Self {
+        let factor = match rate {
+            0..=95_999 => 4,
+            96_000..=191_999 => 2,
+            _ => 1,
+        };
+        // Hann windowed sinc, split into one sub-filter per output phase
+        let len = factor * Self::TAPS_PER_PHASE;
+        let center = (len - 1) as f64 / 2.0;
+        let proto: Vec<f64> = (0..len).map(|n| {
+            let t = (n as f64 - center) / factor as f64;
+            let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+            let window = 0.5 - 0.5 * (2.0 * PI * n as f64 / (len - 1) as f64).cos();
+            sinc * window
+        }).collect();
+        let taps = (0..factor)
+            .map(|phase| proto.iter().skip(phase).step_by(factor).copied().collect())
+            .collect();
+        Self { factor, taps, history: vec![0.0; Self::TAPS_PER_PHASE], pos: 0 }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        if self.factor == 1 {
+            return x.abs();
+        }
+        self.pos = (self.pos + Self::TAPS_PER_PHASE - 1) % Self::TAPS_PER_PHASE;
+        self.history[self.pos] = x;
+        let mut peak = 0.0f64;
+        for phase in &self.taps {
+            let y: f64 = phase.iter().enumerate()
+                .map(|(i, c)| c * self.history[(self.pos + i) % Self::TAPS_PER_PHASE])
+                .sum();
+            peak = peak.max(y.abs());
+        }
+        peak
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(rate: u32, freq: f64, amplitude: f64, secs: f64, channels: usize) -> Vec<f32> {
+        let frames = (rate as f64 * secs) as usize;
+        (0..frames)
+            .flat_map(|n| {
+                let v = (amplitude * (2.0 * PI * freq * n as f64 / rate as f64).sin()) as f32;
+                std::iter::repeat_n(v, channels)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stereo_sine_integrated_loudness() {
+        // EBU Tech 3341: a 1 kHz stereo sine at -23 dBFS reads -23 LUFS
+        let amplitude = 10.0f64.powf(-23.0 / 20.0);
+        let mut meter = LoudnessMeter::new(48000, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        meter.process(&sine(48000, 1000.0, amplitude, 10.0, 2));
+        let l = meter.finish();
+        assert!((l.integrated + 23.0).abs() < 0.1, "{}", l.integrated);
+        assert!(l.range < 0.1);
+        assert!((l.replaygain_db().unwrap() - 5.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_silence_gets_no_gain() {
+        // Faint hiss, all of it below the -70 LUFS absolute gate
+        let mut meter = LoudnessMeter::new(48000, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        meter.process(&sine(48000, 1000.0, 1e-5, 5.0, 2));
+        let l = meter.finish();
+        assert_eq!(l.integrated, f64::NEG_INFINITY);
+        assert_eq!(l.replaygain_db(), None);
+    }
+
+    #[test]
+    fn test_album_combines_tracks() {
+        let stereo = Channels::FRONT_LEFT | Channels::FRONT_RIGHT;
+        let mut quiet = LoudnessMeter::new(44100, stereo);
+        quiet.process(&sine(44100, 1000.0, 10.0f64.powf(-30.0 / 20.0), 10.0, 2));
+        let mut loud = LoudnessMeter::new(44100, stereo);
+        loud.process(&sine(44100, 1000.0, 10.0f64.powf(-20.0 / 20.0), 10.0, 2));
+
+        let album = measure(&[quiet, loud]);
+        // Energy average of the two: 10*log10((0.1 + 1) / 2) below -20
+        assert!((album.integrated + 22.6).abs() < 0.2, "{}", album.integrated);
+        assert!(album.range > 9.0);
+    }
+
+    #[test]
+    fn test_true_peak_catches_intersample_overs() {
+        // fs/4 sine sampled at 45 degrees: samples peak at 0.707, the waveform at 1.0
+        let samples: Vec<f32> = (0..4800)
+            .map(|n| (2.0 * PI * n as f64 / 4.0 + PI / 4.0).sin() as f32)
+            .collect();
+        let mut meter = LoudnessMeter::new(48000, Channels::FRONT_LEFT);
+        meter.process(&samples);
+        let peak = meter.finish().true_peak;
+        assert!(peak > 0.95, "{}", peak);
+    }
+}
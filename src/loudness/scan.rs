@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use symphonia::core::audio::{Channels, SampleBuffer};
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::probe::Hint;
+
+use crate::gui::is_audio_file;
+use crate::loudness::index::{self, IndexEntry};
+use crate::loudness::{measure, LoudnessMeter};
+use crate::player::bitperfect::PlayerError;
+
+/// `elitebox scan-loudness`: measure every file under `paths` and write one
+/// index per album directory
+pub fn scan_paths(paths: &[PathBuf]) -> Result<(), PlayerError> {
+    let mut albums: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for path in paths {
+        collect(path, &mut albums);
+    }
+
+    for (dir, files) in albums {
+        println!("{}", dir.display());
+        let mut meters = Vec::new();
+        let mut names = Vec::new();
+        for file in &files {
+            match scan_file(file) {
+                Ok(meter) => {
+                    let l = meter.finish();
+                    println!(
+                        "  {:<48} {:>7.2} LUFS  LRA {:>5.2} LU  TP {:>6.2} dBTP  RG {}",
+                        file.file_name().unwrap_or_default().to_string_lossy(),
+                        l.integrated, l.range, l.true_peak_dbtp(), describe_gain(l.replaygain_db()),
+                    );
+                    meters.push(meter);
+                    names.push(file.file_name().unwrap_or_default().to_string_lossy().to_string());
+                }
+                Err(e) => eprintln!("  {}: {}", file.display(), e),
+            }
+        }
+        if meters.is_empty() {
+            continue;
+        }
+
+        let album = measure(&meters);
+        println!(
+            "  {:<48} {:>7.2} LUFS  LRA {:>5.2} LU  TP {:>6.2} dBTP  RG {}",
+            "[album]", album.integrated, album.range, album.true_peak_dbtp(), describe_gain(album.replaygain_db()),
+        );
+        let entries: Vec<IndexEntry> = names.into_iter().zip(&meters)
+            .map(|(file, m)| IndexEntry { file, track: m.finish(), album })
+            .collect();
+        index::write(&dir, &entries)?;
+    }
+    Ok(())
+}
+
+fn describe_gain(gain: Option<f64>) -> String {
+    gain.map_or("none (silent)".into(), |g| format!("{:+.2} dB", g))
+}
+
+fn collect(path: &Path, albums: &mut BTreeMap<PathBuf, Vec<PathBuf>>) {
+    if path.is_dir() {
+        if let Ok(entries) = std::fs::read_dir(path) {
+            let mut entries: Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+            entries.sort();
+            for entry in entries {
+                collect(&entry, albums);
+            }
+        }
+    } else if is_audio_file(path) {
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_else(|| PathBuf::from("."));
+        albums.entry(dir).or_default().push(path.to_path_buf());
+    }
+}
+
+/// Decode a whole file into a loudness meter
+pub fn scan_file(path: &Path) -> Result<LoudnessMeter, PlayerError> {
+    let mss = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut probed = symphonia::default::get_probe()
+        .format(&Hint::new(), mss, &Default::default(), &Default::default())?;
+    let track = probed.format.tracks().iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(PlayerError::NoAudioTrack)?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+    let mut decoder = symphonia::default::get_codecs().make(&params, &DecoderOptions::default())?;
+
+    let rate = params.sample_rate.ok_or(PlayerError::NoAudioTrack)?;
+    let channels = params.channels.unwrap_or(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+    let mut meter = LoudnessMeter::new(rate, channels);
+    let mut buf: Option<SampleBuffer<f32>> = None;
+
+    loop {
+        let packet = match probed.format.next_packet() {
+            Ok(p) => p,
+            Err(SymphoniaError::IoError(_)) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        match decoder.decode(&packet) {
+            Ok(decoded) => {
+                if buf.as_ref().is_none_or(|b| b.capacity() < decoded.capacity() * decoded.spec().channels.count()) {
+                    buf = Some(SampleBuffer::new(decoded.capacity() as u64, *decoded.spec()));
+                }
+                let buf = buf.as_mut().unwrap();
+                buf.copy_interleaved_ref(decoded);
+                meter.process(buf.samples());
+            }
+            Err(SymphoniaError::DecodeError(e)) => eprintln!("Decode error: {}", e),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(meter)
+}
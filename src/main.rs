@@ -2,84 +2,316 @@ mod alsa;
 mod player;
 mod rt;
 mod gui;
+mod loudness;
+mod output;
+mod config;
+mod doctor;
 
+use std::fs::File;
+use std::io::BufWriter;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc;
 use std::thread;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use eframe::egui;
 
-use crate::alsa::device::BitPerfectDevice;
-use crate::player::bitperfect::BitPerfectPlayer;
+use crate::alsa::device::BufferTimes;
+use crate::alsa::hotplug::{spawn_monitor, Target};
+use crate::alsa::sink::AlsaSink;
+use crate::config::Config;
+use crate::output::{AudioSink, OutputSpec};
+use crate::output::raw::RawSink;
+use crate::output::render::render_playlist;
+use crate::output::wav::WavSink;
 use crate::player::gapless::GaplessEngine;
-use crate::rt::{set_audio_thread_priority, pin_to_cpu, lock_memory};
+use crate::player::pcm::OutputEncoding;
+use crate::rt::SchedPolicy;
 use crate::gui::{SucklessPlayer, PlayerState};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// The files to play (optional)
     #[arg()]
     files: Vec<PathBuf>,
 
-    /// The ALSA card to use
-    #[arg(long, default_value = "default")]
-    card: String,
+    /// The ALSA card to use [config: output.card, default "default"]
+    #[arg(long)]
+    card: Option<String>,
+
+    /// The ALSA device to use [config: output.device, default 0]
+    #[arg(long)]
+    device: Option<u32>,
+
+    /// ALSA buffer length in milliseconds [config: output.buffer_ms]
+    #[arg(long, value_name = "MS")]
+    buffer_ms: Option<u32>,
+
+    /// ALSA period length in milliseconds [config: output.period_ms]
+    #[arg(long, value_name = "MS")]
+    period_ms: Option<u32>,
+
+    /// Pin the audio thread to this CPU core [config: realtime.cpu, default off]
+    #[arg(long)]
+    cpu: Option<usize>,
+
+    /// Scheduling policy for the audio thread [config: realtime.policy, default fifo]
+    #[arg(long, value_enum)]
+    sched: Option<SchedPolicy>,
+
+    /// Real-time priority, 1-99 [config: realtime.priority, default 95]
+    #[arg(long)]
+    priority: Option<i32>,
+
+    /// Close the DAC after this many idle seconds so other applications can use it
+    #[arg(long, value_name = "SECS")]
+    release_after: Option<u64>,
+
+    /// When the DAC is busy, ask PipeWire/PulseAudio to release it (D-Bus device reservation)
+    #[arg(long)]
+    reserve: bool,
+
+    /// Carry on playing when an unplugged DAC is plugged back in
+    #[arg(long)]
+    resume_on_reconnect: bool,
+
+    /// Load each track into locked RAM before it plays, and the next one while it does
+    /// (for spinning disks and network mounts) [config: library.read_ahead]
+    #[arg(long)]
+    read_ahead: bool,
 
-    /// The ALSA device to use
-    #[arg(long, default_value = "0")]
-    device: u32,
+    /// Read settings from this file instead of $XDG_CONFIG_HOME/elitebox/config.toml
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
 
-    /// The CPU core to pin the audio thread to
-    #[arg(long, default_value = "0")]
-    cpu: usize,
+    /// Show the settings in effect (config file plus flags) as TOML and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Where to send audio: alsa, file:<out.wav> or raw:<path|->.
+    /// Anything but alsa renders the files without the GUI and exits.
+    #[arg(long, default_value = "alsa")]
+    output: OutputSpec,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Measure EBU R128 loudness and store it for use as ReplayGain
+    ScanLoudness {
+        /// Files or directories to scan; each directory is treated as an album
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Check that files reach the output bit for bit (PCM MD5 and FLAC STREAMINFO)
+    Verify {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Force an output format (e.g. S16_LE, S24_3LE, S32_LE) instead of the best match
+        #[arg(long)]
+        format: Option<String>,
+    },
+    /// Check the system for bit-perfect, real-time readiness
+    Doctor,
+}
+
+/// config.toml, overridden by whatever was given on the command line
+fn load_config(args: &Args) -> Config {
+    let loaded = match &args.config {
+        Some(path) => Config::load(path, true),
+        None => Config::default_path().map_or(Ok(Config::default()), |path| Config::load(&path, false)),
+    };
+    let mut config = loaded.unwrap_or_else(|e| {
+        eprintln!("Config error: {}", e);
+        std::process::exit(2);
+    });
+
+    let output = &mut config.output;
+    if let Some(card) = &args.card {
+        output.card = card.clone();
+    }
+    if let Some(device) = args.device {
+        output.device = device;
+    }
+    if args.buffer_ms.is_some() {
+        output.buffer_ms = args.buffer_ms;
+    }
+    if args.period_ms.is_some() {
+        output.period_ms = args.period_ms;
+    }
+    if args.release_after.is_some() {
+        output.release_after = args.release_after;
+    }
+    output.reserve |= args.reserve;
+    output.resume_on_reconnect |= args.resume_on_reconnect;
+    config.library.read_ahead |= args.read_ahead;
+    let realtime = &mut config.realtime;
+    if args.cpu.is_some() {
+        realtime.cpu = args.cpu;
+    }
+    if let Some(policy) = args.sched {
+        realtime.policy = policy;
+    }
+    if let Some(priority) = args.priority {
+        realtime.priority = priority;
+    }
+    config
 }
 
 fn main() -> Result<(), eframe::Error> {
     let args = Args::parse();
+    let config = load_config(&args);
+
+    if args.print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+
+    if let Some(Command::ScanLoudness { paths }) = &args.command {
+        if let Err(e) = crate::loudness::scan::scan_paths(paths) {
+            eprintln!("Loudness scan failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Doctor) = &args.command {
+        if !crate::doctor::run(&config) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Verify { files, format }) = &args.command {
+        let encoding = format.as_deref().map(|name| {
+            OutputEncoding::from_name(name).unwrap_or_else(|| {
+                eprintln!("Unknown output format: {}", name);
+                std::process::exit(2);
+            })
+        });
+        match crate::player::verify::verify_paths(files, encoding) {
+            Ok(true) => return Ok(()),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Verification failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let sink: Box<dyn AudioSink> = match &args.output {
+        OutputSpec::Alsa => Box::new(
+            AlsaSink::new(config.output.card.clone(), config.output.device)
+                .with_reserve(config.output.reserve)
+                .with_buffer(BufferTimes { buffer_ms: config.output.buffer_ms, period_ms: config.output.period_ms }),
+        ),
+        OutputSpec::Wav(path) => Box::new(WavSink::new(path)),
+        OutputSpec::Raw(None) => Box::new(RawSink::new(Box::new(BufWriter::new(std::io::stdout())))),
+        OutputSpec::Raw(Some(path)) => match File::create(path) {
+            Ok(file) => Box::new(RawSink::new(Box::new(BufWriter::new(file)))),
+            Err(e) => {
+                eprintln!("Cannot create {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    if args.output != OutputSpec::Alsa {
+        if args.files.is_empty() {
+            eprintln!("Nothing to render: pass the files to play");
+            std::process::exit(2);
+        }
+        if render_playlist(&args.files, sink) > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let (tx, rx) = mpsc::channel();
     let player_state = Arc::new(Mutex::new(PlayerState {
-        current_track: None,
         is_playing: false,
-        position_secs: 0.0,
-        duration_secs: 0.0,
-        volume_db: -10.0,
-        playlist: Vec::new(),
-        command: None,
-        error_message: None,
-        album_art: None,
+        volume_db: config.volume.initial_db.min(config.volume.max_db),
+        output: Target { card: config.output.card.clone(), device: config.output.device },
+        ..PlayerState::headless()
     }));
 
+    spawn_monitor(player_state.clone(), config.output.resume_on_reconnect);
+
     let player_state_audio = player_state.clone();
+    let audio_config = config.clone();
     thread::spawn(move || {
-        let mut engine = GaplessEngine::new(player_state_audio.clone(), args.card.clone(), args.device);
+        let config = audio_config;
+        let mut engine = GaplessEngine::new(player_state_audio.clone(), sink);
+        engine.set_idle_release(config.output.release_after.map(Duration::from_secs));
+        engine.set_read_ahead(config.library.read_ahead);
 
         for file in args.files {
-            engine.add_to_playlist(&file);
+            for track in crate::player::cue::tracks_for(&file) {
+                engine.add_track(track);
+            }
         }
 
-        if let Err(e) = set_audio_thread_priority() {
-            eprintln!("Warning: Failed to set audio thread priority: {}. Try running with sudo.", e);
-        }
-        pin_to_cpu(args.cpu);
-        lock_memory();
+        eprint!("{}", crate::rt::apply(&config.realtime));
 
+        // Files passed over since a track last played
+        let mut skipped = 0;
         loop {
             match rx.try_recv() {
                 Ok(msg) => match msg {
-                    crate::gui::GuiMessage::AddToPlaylist(path) => {
-                        engine.add_to_playlist(&path);
+                    crate::gui::GuiMessage::AddToPlaylist(track) => {
+                        engine.add_track(track.clone());
                         let mut state = player_state_audio.lock().unwrap();
-                        state.playlist.push(path);
+                        state.playlist.push(track);
                     }
                 },
                 Err(mpsc::TryRecvError::Empty) => {}
                 Err(mpsc::TryRecvError::Disconnected) => break,
             }
             
-            if let Err(e) = engine.play() {
+            let result = engine.play();
+            if result.is_ok() {
+                skipped = 0;
+            }
+            if let Err(e) = result {
+                if e.is_device_busy() {
+                    // Stop rather than spin; Play tries again once the other application is gone
+                    if let Ok(mut state) = player_state_audio.lock() {
+                        state.is_playing = false;
+                        state.error_message = Some(e.to_string());
+                    }
+                    continue;
+                }
+                if e.is_unsupported_format() {
+                    // The engine has said which file; move on to the next one, unless
+                    // a whole pass found nothing to play. Then stop with the message up.
+                    skipped += 1;
+                    if let Ok(mut state) = player_state_audio.lock() {
+                        if skipped < engine.playlist_len() {
+                            state.command.get_or_insert(crate::gui::PlayerCommand::Next);
+                        } else {
+                            state.is_playing = false;
+                            skipped = 0;
+                        }
+                    }
+                    continue;
+                }
+                if e.is_device_gone() {
+                    // The engine has paused and kept its place; the hotplug monitor notices the return
+                    let msg = if config.output.resume_on_reconnect {
+                        "Output device disconnected. Playback resumes when it is plugged back in"
+                    } else {
+                        "Output device disconnected. Plug it back in and press Play"
+                    };
+                    if let Ok(mut state) = player_state_audio.lock() {
+                        state.error_message = Some(msg.into());
+                    }
+                    continue;
+                }
                 // If it's a hardware error, report it and wait
                 let msg = format!("Playback Error: {}. Retrying...", e);
                 if let Ok(mut state) = player_state_audio.lock() {
@@ -97,6 +329,6 @@ fn main() -> Result<(), eframe::Error> {
     eframe::run_native(
         "elitebox",
         native_options,
-        Box::new(|_cc| Ok(Box::new(SucklessPlayer::new(tx, player_state)))),
+        Box::new(move |_cc| Ok(Box::new(SucklessPlayer::new(tx, player_state, &config)))),
     )
 }
@@ -0,0 +1,105 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::alsa::hotplug::Target;
+use crate::output::{AudioSink, SinkError, StreamFormat};
+use crate::player::pcm::{choose_encoding, OutputEncoding, SourceFormat};
+
+/// Everything a `MemorySink` was asked to do
+#[derive(Default)]
+pub struct Capture {
+    pub streams: Vec<StreamFormat>,
+    pub data: Vec<u8>,
+    pub drains: usize,
+    pub releases: usize,
+    /// The clock is stopped
+    pub paused: bool,
+    /// Writes that succeed before the device "goes away"; `None` stays connected
+    pub writes_left: Option<usize>,
+    pub selected: Vec<Target>,
+}
+
+/// Records the output instead of playing it. Clones share one capture, so a
+/// test can keep a handle while the engine owns the sink.
+#[derive(Clone)]
+pub struct MemorySink {
+    capture: Arc<Mutex<Capture>>,
+    supported: Vec<OutputEncoding>,
+}
+
+/// A sink that accepts every format
+impl Default for MemorySink {
+    fn default() -> Self {
+        Self::supporting(&[
+            OutputEncoding::U8, OutputEncoding::S16LE, OutputEncoding::S24LE,
+            OutputEncoding::S24_3LE, OutputEncoding::S32LE, OutputEncoding::FloatLE,
+        ])
+    }
+}
+
+impl MemorySink {
+    /// A sink that behaves like a DAC limited to `encodings`
+    pub fn supporting(encodings: &[OutputEncoding]) -> Self {
+        Self { capture: Arc::default(), supported: encodings.to_vec() }
+    }
+
+    pub fn capture(&self) -> MutexGuard<'_, Capture> {
+        self.capture.lock().unwrap()
+    }
+}
+
+impl AudioSink for MemorySink {
+    fn configure(&mut self, sample_rate: u32, source: SourceFormat, channels: u8) -> Result<OutputEncoding, SinkError> {
+        if self.capture().writes_left == Some(0) {
+            return Err(unplugged());
+        }
+        let encoding = choose_encoding(source, |e| self.supported.contains(&e))
+            .ok_or(SinkError::UnsupportedFormat(source))?;
+        self.capture().streams.push(StreamFormat { sample_rate, encoding, channels });
+        Ok(encoding)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), SinkError> {
+        let mut capture = self.capture();
+        if capture.streams.is_empty() {
+            return Err(SinkError::NotConfigured);
+        }
+        match &mut capture.writes_left {
+            Some(0) => return Err(unplugged()),
+            Some(n) => *n -= 1,
+            None => {}
+        }
+        capture.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<(), SinkError> {
+        self.capture().drains += 1;
+        Ok(())
+    }
+
+    fn drop_queued(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn pause(&mut self, pause: bool) -> Result<(), SinkError> {
+        self.capture().paused = pause;
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        self.capture().releases += 1;
+    }
+
+    fn select(&mut self, target: &Target) -> Result<(), SinkError> {
+        self.capture().selected.push(target.clone());
+        Ok(())
+    }
+
+    fn delay(&self) -> Result<i64, SinkError> {
+        Ok(0)
+    }
+}
+
+fn unplugged() -> SinkError {
+    alsa::Error::new("snd_pcm_writei", libc::ENODEV).into()
+}
@@ -0,0 +1,170 @@
+#[cfg(test)]
+pub mod memory;
+pub mod raw;
+pub mod render;
+pub mod wav;
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::alsa::busy::Holder;
+use crate::alsa::hotplug::Target;
+use crate::player::pcm::{OutputEncoding, SourceFormat};
+
+#[derive(Debug, Error)]
+pub enum SinkError {
+    #[error("ALSA error: {0}")]
+    Alsa(#[from] alsa::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("No output format can carry {0:?} without loss")]
+    UnsupportedFormat(SourceFormat),
+    #[error("Output is not configured")]
+    NotConfigured,
+    #[error("This output cannot switch devices")]
+    NotSwitchable,
+    #[error("{device} is in use by {}. Close it, or start elitebox with --reserve to ask PipeWire/PulseAudio to release the card", describe(.holders))]
+    Busy { device: String, holders: Vec<Holder> },
+}
+
+impl SinkError {
+    /// The device is gone: unplugged mid-stream, or not there to open
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self, SinkError::Alsa(e) if matches!(e.errno(), libc::ENODEV | libc::ENOENT | libc::ENXIO | libc::EBADFD))
+    }
+}
+
+fn describe(holders: &[Holder]) -> String {
+    if holders.is_empty() {
+        return "another application".into();
+    }
+    holders.iter().map(Holder::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// Where decoded, formatted PCM goes: the DAC, a file, or memory in tests
+pub trait AudioSink: Send {
+    /// Set up for a new stream and return the sample layout to write
+    fn configure(&mut self, sample_rate: u32, source: SourceFormat, channels: u8) -> Result<OutputEncoding, SinkError>;
+
+    /// Queue interleaved frames in the configured layout
+    fn write(&mut self, data: &[u8]) -> Result<(), SinkError>;
+
+    /// Block until everything written has been played
+    fn drain(&mut self) -> Result<(), SinkError>;
+
+    /// Throw away whatever is queued, right now
+    fn drop_queued(&mut self) -> Result<(), SinkError>;
+
+    /// Stop (or restart) the clock without losing what is queued. Sinks that
+    /// do not play in real time have nothing to do.
+    fn pause(&mut self, _pause: bool) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    /// Let go of the device so other applications can use it; the next
+    /// `configure` opens it again
+    fn release(&mut self) {}
+
+    /// Close the current device and open `target` instead. On failure the
+    /// sink stays with the device it had.
+    fn select(&mut self, _target: &Target) -> Result<(), SinkError> {
+        Err(SinkError::NotSwitchable)
+    }
+
+    /// Frames written but not yet heard
+    fn delay(&self) -> Result<i64, SinkError>;
+
+    /// ALSA card behind the sink, for its hardware mixer
+    fn card(&self) -> Option<i32> {
+        None
+    }
+}
+
+impl AudioSink for Box<dyn AudioSink> {
+    fn configure(&mut self, sample_rate: u32, source: SourceFormat, channels: u8) -> Result<OutputEncoding, SinkError> {
+        (**self).configure(sample_rate, source, channels)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), SinkError> {
+        (**self).write(data)
+    }
+
+    fn drain(&mut self) -> Result<(), SinkError> {
+        (**self).drain()
+    }
+
+    fn drop_queued(&mut self) -> Result<(), SinkError> {
+        (**self).drop_queued()
+    }
+
+    fn pause(&mut self, pause: bool) -> Result<(), SinkError> {
+        (**self).pause(pause)
+    }
+
+    fn release(&mut self) {
+        (**self).release()
+    }
+
+    fn select(&mut self, target: &Target) -> Result<(), SinkError> {
+        (**self).select(target)
+    }
+
+    fn delay(&self) -> Result<i64, SinkError> {
+        (**self).delay()
+    }
+
+    fn card(&self) -> Option<i32> {
+        (**self).card()
+    }
+}
+
+/// What a sink was configured for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub sample_rate: u32,
+    pub encoding: OutputEncoding,
+    pub channels: u8,
+}
+
+/// Where `--output` sends the audio
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputSpec {
+    /// The DAC picked by `--card`/`--device`
+    Alsa,
+    /// `file:out.wav`
+    Wav(PathBuf),
+    /// `raw:out.pcm`, or `raw:-` for stdout
+    Raw(Option<PathBuf>),
+}
+
+impl FromStr for OutputSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "alsa" => Ok(OutputSpec::Alsa),
+            None if s == "raw" => Ok(OutputSpec::Raw(None)),
+            Some(("file", path)) if !path.is_empty() => Ok(OutputSpec::Wav(path.into())),
+            Some(("raw", "-")) => Ok(OutputSpec::Raw(None)),
+            Some(("raw", path)) if !path.is_empty() => Ok(OutputSpec::Raw(Some(path.into()))),
+            _ => Err(format!("expected alsa, file:<path.wav> or raw:<path|->, got {:?}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output_spec() {
+        assert_eq!("alsa".parse(), Ok(OutputSpec::Alsa));
+        assert_eq!("file:out.wav".parse(), Ok(OutputSpec::Wav("out.wav".into())));
+        assert_eq!("raw:-".parse(), Ok(OutputSpec::Raw(None)));
+        assert_eq!("raw:/tmp/x.pcm".parse(), Ok(OutputSpec::Raw(Some("/tmp/x.pcm".into()))));
+        assert!("file:".parse::<OutputSpec>().is_err());
+        assert!("pulse".parse::<OutputSpec>().is_err());
+    }
+}
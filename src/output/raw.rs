@@ -0,0 +1,48 @@
+use std::io::Write;
+
+use crate::output::{AudioSink, SinkError, StreamFormat};
+use crate::player::pcm::{choose_encoding, OutputEncoding, SourceFormat};
+
+/// Headerless PCM to a file or stdout. Each format change is announced on
+/// stderr, since nothing in the stream says how to read it.
+pub struct RawSink {
+    out: Box<dyn Write + Send>,
+    format: Option<StreamFormat>,
+}
+
+impl RawSink {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self { out, format: None }
+    }
+}
+
+impl AudioSink for RawSink {
+    fn configure(&mut self, sample_rate: u32, source: SourceFormat, channels: u8) -> Result<OutputEncoding, SinkError> {
+        let encoding = choose_encoding(source, |_| true).ok_or(SinkError::UnsupportedFormat(source))?;
+        let format = StreamFormat { sample_rate, encoding, channels };
+        if self.format != Some(format) {
+            eprintln!("raw output: {} {} Hz {} ch", encoding.name(), sample_rate, channels);
+            self.format = Some(format);
+        }
+        Ok(encoding)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), SinkError> {
+        if self.format.is_none() {
+            return Err(SinkError::NotConfigured);
+        }
+        Ok(self.out.write_all(data)?)
+    }
+
+    fn drain(&mut self) -> Result<(), SinkError> {
+        Ok(self.out.flush()?)
+    }
+
+    fn drop_queued(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn delay(&self) -> Result<i64, SinkError> {
+        Ok(0)
+    }
+}
@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::gui::{PlayerCommand, PlayerState};
+use crate::output::AudioSink;
+use crate::player::gapless::GaplessEngine;
+
+/// Play `files` in order into `sink` at unity gain, as fast as it accepts them.
+/// Files that fail are reported and skipped; returns how many did.
+pub fn render_playlist(files: &[PathBuf], sink: Box<dyn AudioSink>) -> usize {
+    let state = Arc::new(Mutex::new(PlayerState::headless()));
+    let mut engine = GaplessEngine::new(state.clone(), sink);
+    for file in files {
+        engine.add_to_playlist(file);
+    }
+
+    // Without shuffle or repeat every call to play() handles exactly one track
+    state.lock().unwrap().command = Some(PlayerCommand::PlayIndex(0));
+    let mut failed = 0;
+    for (i, file) in files.iter().enumerate() {
+        eprintln!("[{}/{}] {}", i + 1, files.len(), file.display());
+        if let Err(e) = engine.play() {
+            eprintln!("  {}", e);
+            failed += 1;
+            state.lock().unwrap().command = Some(PlayerCommand::Next);
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::wav::WavSink;
+    use crate::player::pcm::OutputEncoding;
+    use crate::player::pcm::tests::{render, wav, TempPath};
+
+    #[test]
+    fn test_renders_playlist_joined_and_skips_failures() {
+        let dir = TempPath::dir("render");
+        let a = dir.join("a.wav");
+        let b = dir.join("b.wav");
+        std::fs::write(&a, wav(false, 16, None, 2, 44100, &[0x01, 0x00, 0x02, 0x00])).unwrap();
+        std::fs::write(&b, wav(false, 16, None, 2, 44100, &[0x03, 0x00, 0x04, 0x00])).unwrap();
+        // Recognised, but there is no Opus decoder
+        let opus = dir.join("c.opus");
+        let mut ogg = b"OggS".to_vec();
+        ogg.resize(28, 0);
+        ogg.extend(b"OpusHead");
+        std::fs::write(&opus, ogg).unwrap();
+        let out = dir.join("out.wav");
+
+        let files = [a, dir.join("missing.wav"), opus, b];
+        assert_eq!(render_playlist(&files, Box::new(WavSink::new(&out))), 2);
+
+        let (_, _, data) = render(std::fs::read(&out).unwrap(), Some(OutputEncoding::S16LE), &[]);
+        assert_eq!(data, [0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04, 0x00]);
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::output::{AudioSink, SinkError, StreamFormat};
+use crate::player::pcm::{choose_encoding, OutputEncoding, SourceFormat};
+
+/// Writes the stream to a WAV file exactly as it would reach the DAC.
+///
+/// Tracks in the same format are appended to one file, so gapless joins can
+/// be inspected. A format change starts a new file: `out.wav`, `out-2.wav`, ...
+pub struct WavSink {
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+    format: Option<StreamFormat>,
+    data_len: u64,
+    files: usize,
+}
+
+impl WavSink {
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf(), file: None, format: None, data_len: 0, files: 0 }
+    }
+
+    fn next_path(&self) -> PathBuf {
+        if self.files == 0 {
+            return self.path.clone();
+        }
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{}-{}.{}", stem, self.files + 1, ext.to_string_lossy()),
+            None => format!("{}-{}", stem, self.files + 1),
+        };
+        self.path.with_file_name(name)
+    }
+
+    /// Patch the RIFF and data sizes for what has been written so far
+    fn finish_header(&mut self) -> io::Result<()> {
+        let Some(file) = self.file.as_mut() else { return Ok(()) };
+        let data_len = self.data_len.min(u32::MAX as u64 - 36) as u32;
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(36 + data_len).to_le_bytes())?;
+        file.seek(SeekFrom::Start(40))?;
+        file.write_all(&data_len.to_le_bytes())?;
+        file.seek(SeekFrom::End(0))?;
+        file.flush()
+    }
+}
+
+fn header(format: StreamFormat) -> Vec<u8> {
+    let width = format.encoding.width() as u16;
+    let block_align = format.channels as u16 * width;
+    let tag: u16 = if format.encoding == OutputEncoding::FloatLE { 3 } else { 1 };
+
+    let mut out = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&tag.to_le_bytes());
+    out.extend_from_slice(&(format.channels as u16).to_le_bytes());
+    out.extend_from_slice(&format.sample_rate.to_le_bytes());
+    out.extend_from_slice(&(format.sample_rate * block_align as u32).to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&(width * 8).to_le_bytes());
+    out.extend_from_slice(b"data\0\0\0\0");
+    out
+}
+
+impl AudioSink for WavSink {
+    fn configure(&mut self, sample_rate: u32, source: SourceFormat, channels: u8) -> Result<OutputEncoding, SinkError> {
+        // WAV has no 24-in-32 low-aligned layout
+        let encoding = choose_encoding(source, |e| e != OutputEncoding::S24LE)
+            .ok_or(SinkError::UnsupportedFormat(source))?;
+        let format = StreamFormat { sample_rate, encoding, channels };
+        if self.format == Some(format) {
+            return Ok(encoding);
+        }
+
+        self.finish_header()?;
+        let mut file = BufWriter::new(File::create(self.next_path())?);
+        file.write_all(&header(format))?;
+        self.file = Some(file);
+        self.format = Some(format);
+        self.data_len = 0;
+        self.files += 1;
+        Ok(encoding)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), SinkError> {
+        let file = self.file.as_mut().ok_or(SinkError::NotConfigured)?;
+        file.write_all(data)?;
+        self.data_len += data.len() as u64;
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<(), SinkError> {
+        Ok(self.finish_header()?)
+    }
+
+    fn drop_queued(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn delay(&self) -> Result<i64, SinkError> {
+        Ok(0)
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        let _ = self.finish_header();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::pcm::tests::{render, TempPath};
+
+    #[test]
+    fn test_joins_same_format_and_splits_on_change() {
+        let dir = TempPath::dir("wavsink");
+        let mut sink = WavSink::new(&dir.join("out.wav"));
+
+        assert_eq!(sink.configure(96000, SourceFormat::Int(24), 2).unwrap(), OutputEncoding::S32LE);
+        sink.write(&[0x00, 0x56, 0x34, 0x12, 0x00, 0xfe, 0xff, 0xff]).unwrap();
+        sink.configure(96000, SourceFormat::Int(24), 2).unwrap();
+        sink.write(&[0x00, 0x01, 0x00, 0x80, 0x00, 0xff, 0xff, 0x7f]).unwrap();
+        sink.drain().unwrap();
+        assert_eq!(sink.configure(44100, SourceFormat::Int(16), 1).unwrap(), OutputEncoding::S16LE);
+        sink.write(&[0x34, 0x12]).unwrap();
+        drop(sink);
+
+        let (_, _, joined) = render(std::fs::read(dir.join("out.wav")).unwrap(), Some(OutputEncoding::S32LE), &[]);
+        assert_eq!(joined, [
+            0x00, 0x56, 0x34, 0x12, 0x00, 0xfe, 0xff, 0xff,
+            0x00, 0x01, 0x00, 0x80, 0x00, 0xff, 0xff, 0x7f,
+        ]);
+        let (source, _, second) = render(std::fs::read(dir.join("out-2.wav")).unwrap(), None, &[OutputEncoding::S16LE]);
+        assert_eq!(source, SourceFormat::Int(16));
+        assert_eq!(second, [0x34, 0x12]);
+    }
+}
@@ -0,0 +1,179 @@
+//! Cover art: the front cover embedded in the file, or an image in the album folder
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use symphonia::core::meta::{StandardVisualKey, Visual};
+
+use crate::gui::TrackInfo;
+
+/// Folder images are not read past this; anything larger is not a cover
+const MAX_FOLDER_ART: u64 = 32 << 20;
+
+/// Names of cover images in an album folder, in order of preference
+const FOLDER_NAMES: [&str; 5] = ["cover", "folder", "front", "albumart", "album"];
+
+/// Cover of the current track
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlbumArt {
+    /// The same for every track of an album, so the GUI decodes a cover once
+    pub key: String,
+    pub source: ArtSource,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArtSource {
+    /// A picture from the file's tags
+    Embedded(Arc<[u8]>),
+    /// The album folder, searched for an image by whoever shows the cover;
+    /// the audio thread does not touch the disk for it
+    Folder(PathBuf),
+}
+
+impl AlbumArt {
+    /// The image and its type, taken from the data; what files declare is
+    /// often wrong or missing. Reads the folder image, if that is the source.
+    pub fn load(&self) -> Option<(&'static str, Arc<[u8]>)> {
+        let data: Arc<[u8]> = match &self.source {
+            ArtSource::Embedded(data) => data.clone(),
+            ArtSource::Folder(dir) => fs::read(folder_image(dir)?).ok()?.into(),
+        };
+        Some((sniff_mime(&data)?, data))
+    }
+}
+
+/// Cover for a track whose embedded pictures are `visuals`. `current` is the
+/// art shown now; when it is for the same album it is kept as it is.
+pub fn find(info: &TrackInfo, visuals: &[&Visual], current: Option<&AlbumArt>) -> Option<AlbumArt> {
+    let key = album_key(info);
+    if let Some(art) = current.filter(|a| a.key == key) {
+        return Some(art.clone());
+    }
+    let source = match front_cover(visuals) {
+        Some(visual) => ArtSource::Embedded(visual.data.as_ref().into()),
+        None => ArtSource::Folder(info.path.parent()?.to_path_buf()),
+    };
+    Some(AlbumArt { key, source })
+}
+
+/// Tracks tagged with the same album share a key; untagged ones share their folder
+pub fn album_key(info: &TrackInfo) -> String {
+    let folder = info.path.parent().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default();
+    match (&info.album, info.album_artist.as_ref().or(info.artist.as_ref())) {
+        (Some(album), Some(artist)) => format!("{}\u{1f}{}", artist, album),
+        // "Greatest Hits" by nobody in particular: only the folder tells them apart
+        (Some(album), None) => format!("{}\u{1f}{}", folder, album),
+        (None, _) => folder,
+    }
+}
+
+/// The picture tagged as front cover, else an untagged one, else any but an
+/// icon; only pictures the GUI can decode count
+fn front_cover<'a>(visuals: &[&'a Visual]) -> Option<&'a Visual> {
+    let rank = |v: &Visual| match v.usage {
+        Some(StandardVisualKey::FrontCover) => 0,
+        None | Some(StandardVisualKey::Illustration) => 1,
+        Some(StandardVisualKey::FileIcon | StandardVisualKey::OtherIcon) => 3,
+        Some(_) => 2,
+    };
+    visuals.iter().copied()
+        .filter(|v| rank(v) < 3 && sniff_mime(&v.data).is_some())
+        .min_by_key(|v| rank(v))
+}
+
+/// `cover.jpg`, `Folder.png`, `front.jpeg`, ... in any case, recognised by content
+fn folder_image(dir: &Path) -> Option<PathBuf> {
+    let mut found: Vec<(usize, PathBuf)> = fs::read_dir(dir).ok()?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_string_lossy().to_lowercase();
+            let rank = FOLDER_NAMES.iter().position(|n| *n == stem)?;
+            Some((rank, path))
+        })
+        .filter(|(_, path)| fs::metadata(path).is_ok_and(|m| m.is_file() && m.len() <= MAX_FOLDER_ART))
+        .filter(|(_, path)| sniff_file(path).is_some())
+        .collect();
+    found.sort();
+    found.into_iter().next().map(|(_, path)| path)
+}
+
+fn sniff_file(path: &Path) -> Option<&'static str> {
+    use std::io::Read;
+    let mut head = [0u8; 12];
+    let n = fs::File::open(path).ok()?.read(&mut head).ok()?;
+    sniff_mime(&head[..n])
+}
+
+/// PNG and JPEG, the formats the GUI is built to decode
+pub fn sniff_mime(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xff, 0xd8, 0xff, ..] => Some("image/jpeg"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::pcm::tests::TempPath;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n";
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0";
+    const WEBP: &[u8] = b"RIFF\0\0\0\0WEBPVP8 ";
+
+    fn visual(usage: Option<StandardVisualKey>, data: &[u8]) -> Visual {
+        Visual {
+            media_type: "image/jpeg".into(),
+            dimensions: None,
+            bits_per_pixel: None,
+            color_mode: None,
+            usage,
+            tags: Vec::new(),
+            data: data.into(),
+        }
+    }
+
+    #[test]
+    fn test_embedded_front_cover() {
+        let icon = visual(Some(StandardVisualKey::FileIcon), JPEG);
+        let back = visual(Some(StandardVisualKey::BackCover), JPEG);
+        // Declared as JPEG, actually PNG
+        let front = visual(Some(StandardVisualKey::FrontCover), PNG);
+        let info = TrackInfo { path: "/music/a/01.flac".into(), album: Some("A".into()), ..Default::default() };
+
+        let art = find(&info, &[&icon, &back, &front], None).unwrap();
+        let (mime, data) = art.load().unwrap();
+        assert_eq!((mime, &data[..]), ("image/png", PNG));
+        assert_eq!(front_cover(&[&icon, &back]).map(|v| v.usage), Some(Some(StandardVisualKey::BackCover)));
+        assert!(front_cover(&[&icon]).is_none());
+        // A WebP front cover cannot be shown; the back cover can
+        let webp = visual(Some(StandardVisualKey::FrontCover), WEBP);
+        assert_eq!(front_cover(&[&back, &webp]).map(|v| v.usage), Some(Some(StandardVisualKey::BackCover)));
+
+        // The next track of the album keeps the cover already shown
+        let next = TrackInfo { path: "/music/a/02.flac".into(), ..info.clone() };
+        let kept = find(&next, &[&visual(None, JPEG)], Some(&art)).unwrap();
+        assert_eq!(kept, art);
+    }
+
+    #[test]
+    fn test_folder_image() {
+        let dir = TempPath::dir("art");
+        fs::write(dir.join("Front.JPG"), JPEG).unwrap();
+        fs::write(dir.join("folder.png"), PNG).unwrap();
+        // Named right, but not an image
+        fs::write(dir.join("cover.txt"), "scanned at 600 dpi").unwrap();
+        // An image, but not one the GUI decodes
+        fs::write(dir.join("COVER.webp"), WEBP).unwrap();
+
+        // Finding it only notes the folder; loading reads it
+        let info = TrackInfo { path: dir.join("01.flac"), ..Default::default() };
+        let art = find(&info, &[], None).unwrap();
+        assert_eq!(art.source, ArtSource::Folder(dir.to_path_buf()));
+        let (mime, data) = art.load().unwrap();
+        assert_eq!((mime, &data[..]), ("image/png", PNG));
+        assert_eq!(art.key, dir.to_string_lossy());
+    }
+}
@@ -1,16 +1,30 @@
 use std::path::Path;
 use std::fs::File;
+use std::ops::Range;
+use std::io::Cursor;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::{MetadataOptions, Visual};
 use symphonia::core::probe::Hint;
-use symphonia::core::formats::FormatOptions;
+use symphonia::core::formats::{FormatOptions, SeekMode, SeekTo};
+use symphonia::core::units::Time;
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
-use symphonia::core::audio::{SampleBuffer, AudioBufferRef};
+use symphonia::core::audio::{AudioBufferRef, SignalSpec};
 
-use crate::alsa::device::BitPerfectDevice;
-use crate::gui::PlayerState;
+use crate::alsa::hotplug::Target;
+use crate::alsa::mixer::HardwareVolume;
+use crate::gui::{PlayerCommand, PlayerState, TrackInfo};
+use crate::output::{AudioSink, SinkError};
+use crate::player::art;
+use crate::player::formats::sniff_file;
+use crate::player::lyrics;
+use crate::player::pcm::{Encoder, SourceFormat};
+use crate::player::readahead::ReadAhead;
+use crate::player::track::{Span, Track};
+use crate::player::replaygain::{Dither, ReplayGain};
+use crate::player::tags;
 
 #[derive(Debug, Error)]
 pub enum PlayerError {
@@ -22,27 +36,139 @@ pub enum PlayerError {
     Symphonia(#[from] symphonia::core::errors::Error),
     #[error("No audio track found")]
     NoAudioTrack,
+    #[error("{0} is not supported by this build")]
+    UnsupportedFormat(&'static str),
+    #[error("Output error: {0}")]
+    Sink(#[from] SinkError),
 }
 
-pub struct BitPerfectPlayer {
-    device: BitPerfectDevice,
+/// How a call to `play_track` ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Played {
+    /// The track ran out, or a command cut it short
+    Finished,
+    /// Paused for longer than the idle release; the device was closed with
+    /// the track this many seconds in
+    Released(f64),
 }
 
-impl BitPerfectPlayer {
-    pub fn new(device: BitPerfectDevice) -> Self {
-        Self { device }
+/// A track that runs on from the one playing, in the same file
+pub struct Following {
+    pub span: Span,
+    /// Its place in the playlist
+    pub index: usize,
+    pub info: TrackInfo,
+}
+
+/// What the GUI is told once playback has run on into a `Following` track
+struct Announce {
+    index: usize,
+    info: TrackInfo,
+    duration_secs: f64,
+    lyrics: Option<Arc<lyrics::Lyrics>>,
+}
+
+impl Announce {
+    fn publish(self, s: &mut PlayerState) {
+        s.current_index = Some(self.index);
+        s.current_track = Some(self.info);
+        s.duration_secs = self.duration_secs;
+        s.lyrics = self.lyrics;
+    }
+}
+
+pub struct BitPerfectPlayer<S: AudioSink> {
+    sink: S,
+    hw_volume: Option<HardwareVolume>,
+    dither: Dither,
+    /// Tracks are played from RAM instead of straight off the disk
+    read_ahead: Option<ReadAhead>,
+    /// Close the device after being paused this long
+    idle_release: Option<Duration>,
+}
+
+impl<S: AudioSink> BitPerfectPlayer<S> {
+    pub fn new(sink: S) -> Self {
+        Self { sink, hw_volume: None, dither: Dither::new(), read_ahead: None, idle_release: None }
+    }
+
+    pub fn set_idle_release(&mut self, after: Option<Duration>) {
+        self.idle_release = after;
+    }
+
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    pub fn set_read_ahead(&mut self, enabled: bool) {
+        self.read_ahead = enabled.then(ReadAhead::default);
     }
 
-    pub fn play_file(&mut self, path: &Path, state: Arc<Mutex<PlayerState>>) -> Result<(), PlayerError> {
-        let src_file = File::open(path).map_err(|e| {
+    /// The track expected after the next `play_file`, to be loaded while that one plays
+    pub fn set_next_track(&mut self, path: Option<&Path>) {
+        if let Some(read_ahead) = &mut self.read_ahead {
+            read_ahead.set_next(path);
+        }
+    }
+
+    /// Wait for the next track to be in memory
+    #[cfg(test)]
+    pub fn wait_read_ahead(&mut self) {
+        if let Some(read_ahead) = &mut self.read_ahead {
+            read_ahead.wait();
+        }
+    }
+
+    /// Close the output device and its mixer until the next track
+    pub fn release(&mut self) {
+        self.hw_volume = None;
+        self.sink.release();
+    }
+
+    /// Move to another device; its mixer is picked up with the next track
+    pub fn select_output(&mut self, target: &Target) -> Result<(), SinkError> {
+        self.hw_volume = None;
+        self.sink.select(target)
+    }
+
+    /// Play `path` from `start_secs` (0 for the beginning) until it ends or a
+    /// command interrupts it
+    pub fn play_file(&mut self, path: &Path, start_secs: f64, state: Arc<Mutex<PlayerState>>) -> Result<(), PlayerError> {
+        self.play_track(&Track::file(path), start_secs, state, |_| None).map(|_| ())
+    }
+
+    /// Play `track` from `start_secs` into it. When it reaches the end of its
+    /// span, `follow` is asked for a track that starts right there in the
+    /// same file; playback runs on into it without a break, which is what
+    /// keeps CUE tracks gapless.
+    pub fn play_track(
+        &mut self,
+        track: &Track,
+        start_secs: f64,
+        state: Arc<Mutex<PlayerState>>,
+        mut follow: impl FnMut(u64) -> Option<Following>,
+    ) -> Result<Played, PlayerError> {
+        let path = track.path.as_path();
+        if let Some(fmt) = sniff_file(path)
+            && !fmt.is_decodable()
+        {
+            if let Ok(mut s) = state.lock() {
+                s.error_message = Some(format!("No decoder for {}: {}", fmt.name(), path.display()));
+            }
+            return Err(PlayerError::UnsupportedFormat(fmt.name()));
+        }
+
+        let source = self.open_source(path).inspect_err(|_| {
             if let Ok(mut s) = state.lock() {
                 s.error_message = Some(format!("File not found: {}", path.display()));
             }
-            e
         })?;
-        let mss = MediaSourceStream::new(Box::new(src_file), Default::default());
+        let mss = MediaSourceStream::new(source, Default::default());
 
-        let hint = Hint::new();
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
         let meta_opts: MetadataOptions = Default::default();
         let fmt_opts: FormatOptions = Default::default();
         
@@ -58,9 +184,9 @@ impl BitPerfectPlayer {
         let mut format = probed.format;
         
         // Find the first audio track and copy its parameters to avoid borrowing 'format'
-        let track_params = format.tracks().iter()
+        let (track_id, track_params) = format.tracks().iter()
             .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-            .map(|t| t.codec_params.clone())
+            .map(|t| (t.id, t.codec_params.clone()))
             .ok_or_else(|| {
                 if let Ok(mut s) = state.lock() {
                     s.error_message = Some("No valid audio track found".into());
@@ -80,95 +206,163 @@ impl BitPerfectPlayer {
 
         let sample_rate = track_params.sample_rate.ok_or(PlayerError::NoAudioTrack)?;
         let channels = track_params.channels.ok_or(PlayerError::NoAudioTrack)?.count() as u8;
-        let bit_depth = track_params.bits_per_sample.unwrap_or(16) as u16;
+        let source = SourceFormat::from_codec_params(&track_params);
+
+        // Frames are counted from the start of the file. A CUE track starts
+        // part-way in, and so does resuming after the DAC was unplugged or
+        // switched. The reader lands on a packet boundary at or before the
+        // target; the frames up to it are decoded and thrown away.
+        let span = track.span.unwrap_or(Span { start: 0, end: None });
+        let mut track_start = span.start;
+        let mut end = span.end;
+        let mut frames_written = span.start + (start_secs * sample_rate as f64).round() as u64;
+        let mut skip_frames = 0u64;
+        if frames_written > 0 {
+            let seek_to = match track_params.time_base {
+                Some(tb) if tb.numer == 1 && tb.denom == sample_rate => SeekTo::TimeStamp { ts: frames_written, track_id },
+                _ => SeekTo::Time { time: Time::from(frames_written as f64 / sample_rate as f64), track_id: Some(track_id) },
+            };
+            let seeked = format.seek(SeekMode::Accurate, seek_to)?;
+            decoder.reset();
+            let actual = match track_params.time_base {
+                Some(tb) => {
+                    let t = tb.calc_time(seeked.actual_ts);
+                    t.seconds * sample_rate as u64 + (t.frac * sample_rate as f64).round() as u64
+                }
+                None => seeked.actual_ts,
+            };
+            skip_frames = frames_written.saturating_sub(actual);
+        }
+        let n_frames = track_params.n_frames;
+        let span_secs = move |start: u64, end: Option<u64>| {
+            end.or(n_frames).map_or(0.0, |end| end.saturating_sub(start) as f64 / sample_rate as f64)
+        };
 
-        self.device.configure_exact(sample_rate, bit_depth, channels).map_err(|e| {
+        let encoding = self.sink.configure(sample_rate, source, channels).inspect_err(|e| {
             if let Ok(mut s) = state.lock() {
-                s.error_message = Some(format!("ALSA hardware error: {}", e));
+                s.error_message = Some(format!("Output error: {}", e));
             }
-            e
         })?;
 
+        // ReplayGain can sit in either metadata source (e.g. ID3v2 in front of FLAC)
+        let mut replaygain = format.metadata().current()
+            .map(|m| ReplayGain::from_tags(m.tags()))
+            .unwrap_or_default();
+        if let Some(meta) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            replaygain = replaygain.or(ReplayGain::from_tags(meta.tags()));
+        }
+        // Untagged files may have been measured by `elitebox scan-loudness`
+        if let Some(scanned) = crate::loudness::index::lookup(path) {
+            replaygain = replaygain.or(scanned);
+        }
+        let rg_mode = state.lock().unwrap().replaygain;
+        let rg_db = replaygain.gain_db(rg_mode);
+        let software_rg = 10.0f64.powf(self.apply_replaygain(rg_db) / 20.0);
+
+        // Everything the packet loop needs is allocated here, once per track
+        let spec = SignalSpec::new(sample_rate, track_params.channels.ok_or(PlayerError::NoAudioTrack)?);
+        let mut encoder = Encoder::new(source, encoding, spec, track_params.max_frames_per_packet);
+
+        // The reader's own tags first, then any found while probing (e.g. ID3v2 in front of FLAC)
+        let (file_info, album_art, lyrics) = {
+            let format_meta = format.metadata();
+            let probe_meta = probed.metadata.get();
+            let format_rev = format_meta.current();
+            let probe_rev = probe_meta.as_ref().and_then(|m| m.current());
+            let format_tags = format_rev.map_or(&[][..], |m| m.tags());
+            let probe_tags = probe_rev.map_or(&[][..], |m| m.tags());
+            let info = tags::read(path, &track_params, &[format_tags, probe_tags]);
+            let visuals: Vec<&Visual> = format_rev.into_iter().chain(probe_rev).flat_map(|m| m.visuals()).collect();
+            let shown = state.lock().unwrap().album_art.clone();
+            let album_art = art::find(&info, &visuals, shown.as_ref());
+            (info, album_art, lyrics::find(path, &[format_tags, probe_tags]))
+        };
+        // Lyrics are timed from the start of the file; a CUE track gets its own part of them
+        let lyrics_for = |start: u64, end: Option<u64>| {
+            let secs = |frames: u64| frames as f64 / sample_rate as f64;
+            lyrics.as_ref().map(|l| Arc::new(l.span(secs(start), end.map(secs))))
+        };
+
         // Update state with actual info
         {
             let mut s = state.lock().unwrap();
-            
-            // Extract metadata if available
-            let mut title = None;
-            let mut artist = None;
-            let mut album_art = None;
-
-            // 1. Check metadata in the format reader
-            if let Some(meta) = format.metadata().current() {
-                for tag in meta.tags() {
-                    if let Some(std_key) = tag.std_key {
-                        match std_key {
-                            symphonia::core::meta::StandardTagKey::TrackTitle => title = Some(tag.value.to_string()),
-                            symphonia::core::meta::StandardTagKey::Artist => artist = Some(tag.value.to_string()),
-                            _ => {}
-                        }
-                    }
-                }
-                if let Some(visual) = meta.visuals().first() {
-                    album_art = Some(visual.data.to_vec());
-                }
-            }
-            
-            // 2. If still missing info, check the probe metadata
-            if title.is_none() || album_art.is_none() {
-                if let Some(meta) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
-                    for tag in meta.tags() {
-                        if let Some(std_key) = tag.std_key {
-                            match std_key {
-                                symphonia::core::meta::StandardTagKey::TrackTitle => {
-                                    if title.is_none() { title = Some(tag.value.to_string()); }
-                                }
-                                symphonia::core::meta::StandardTagKey::Artist => {
-                                    if artist.is_none() { artist = Some(tag.value.to_string()); }
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    if album_art.is_none() {
-                        if let Some(visual) = meta.visuals().first() {
-                            album_art = Some(visual.data.to_vec());
-                        }
-                    }
-                }
-            }
 
-            if let Some(ref mut track_info) = s.current_track {
-                track_info.sample_rate = sample_rate;
-                track_info.bit_depth = bit_depth;
-                track_info.title = title;
-                track_info.artist = artist;
+            // A CUE sheet's titles win over the tags of the whole file
+            if let Some(info) = &mut s.current_track {
+                *info = std::mem::take(info).or(file_info.clone());
             }
             s.album_art = album_art;
+            s.lyrics = match track.span {
+                None => lyrics.clone().map(Arc::new),
+                Some(span) => lyrics_for(span.start, span.end),
+            };
+            s.replaygain_db = rg_db;
             
             s.error_message = None;
-            s.duration_secs = track_params.n_frames
-                .map(|f| f as f64 / sample_rate as f64)
-                .unwrap_or(0.0);
-            s.position_secs = 0.0;
+            s.duration_secs = span_secs(track_start, end);
+            s.position_secs = (frames_written - track_start) as f64 / sample_rate as f64;
+            s.position_at = None;
         }
 
+        let mut interrupted = false;
+        let mut switching = false;
+        let mut paused: Option<Instant> = None;
+        let mut volume_db = state.lock().unwrap().volume_db;
+        let mut bit_perfect = true;
+        // A track run on into, until the GUI has been told about it
+        let mut announce: Option<Announce> = None;
         loop {
-            // Check if we should stop or if we are paused
-            {
-                let s = state.lock().unwrap();
-                
+            // One look at the shared state per packet, without waiting: the GUI
+            // holds the lock while it draws. If it is busy, the last volume
+            // stands and the position is published with the next packet.
+            if let Ok(mut s) = state.try_lock() {
+                if let Some(next) = announce.take() {
+                    next.publish(&mut s);
+                }
+                if frames_written > track_start {
+                    s.position_secs = self.audible_secs(frames_written - track_start, sample_rate);
+                    s.position_at = Some(Instant::now());
+                    s.bit_perfect = bit_perfect;
+                }
+
                 // Break if a command (Next/Prev/PlayIndex) is pending
-                if s.command.is_some() {
+                if let Some(command) = &s.command {
+                    interrupted = true;
+                    switching = paused.is_none() && matches!(command, PlayerCommand::SwitchOutput(_));
                     break;
                 }
 
                 if !s.is_playing {
-                    // Pause or stop
+                    // Freeze the hardware with the buffer intact, so resuming
+                    // carries on from the exact frame
+                    match paused {
+                        None => {
+                            paused = Some(Instant::now());
+                            self.sink.pause(true)?;
+                            s.position_secs = self.audible_secs(frames_written - track_start, sample_rate);
+                            s.position_at = None;
+                        }
+                        // Hand the DAC back; playing on starts again from here
+                        Some(since) if self.idle_release.is_some_and(|after| since.elapsed() >= after) => {
+                            let position = s.position_secs;
+                            drop(s);
+                            self.release();
+                            return Ok(Played::Released(position));
+                        }
+                        Some(_) => {}
+                    }
                     drop(s);
-                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    std::thread::sleep(std::time::Duration::from_millis(20));
                     continue;
                 }
+                volume_db = s.volume_db;
+            } else if paused.is_some() {
+                // Stay paused until the GUI lets go and says otherwise
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                continue;
+            }
+            if paused.take().is_some() {
+                self.sink.pause(false)?;
             }
 
             let packet = match format.next_packet() {
@@ -179,13 +373,45 @@ impl BitPerfectPlayer {
 
             match decoder.decode(&packet) {
                 Ok(decoded) => {
-                    let volume = {
-                        state.lock().unwrap().volume_db
-                    };
-                    self.write_decoded_to_device(decoded, bit_depth, volume)?;
-                    
-                    let mut s = state.lock().unwrap();
-                    s.position_secs += packet.dur() as f64 / sample_rate as f64;
+                    let gain = db_to_multiplier(volume_db) * software_rg;
+                    let skip = skip_frames.min(decoded.frames() as u64);
+                    skip_frames -= skip;
+                    let mut take = decoded.frames() as u64 - skip;
+
+                    // Track boundaries inside this packet: run on into a track
+                    // that continues here, or cut the packet off at the end
+                    let mut finished = false;
+                    while let Some(e) = end
+                        && e < frames_written + take
+                    {
+                        match follow(e) {
+                            // Published with the next look at the shared state
+                            Some(next) => {
+                                track_start = next.span.start;
+                                end = next.span.end;
+                                announce = Some(Announce {
+                                    index: next.index,
+                                    info: next.info.or(file_info.clone()),
+                                    duration_secs: span_secs(track_start, end),
+                                    lyrics: lyrics_for(track_start, end),
+                                });
+                            }
+                            None => {
+                                take = e.saturating_sub(frames_written);
+                                finished = true;
+                                break;
+                            }
+                        }
+                    }
+
+                    frames_written += take;
+                    let frame_bytes = channels as usize * encoding.width();
+                    let bytes = skip as usize * frame_bytes..(skip + take) as usize * frame_bytes;
+                    self.write_decoded(&mut encoder, decoded, gain, bytes)?;
+                    bit_perfect = gain == 1.0;
+                    if finished {
+                        break;
+                    }
                 }
                 Err(symphonia::core::errors::Error::DecodeError(err)) => {
                     eprintln!("Decode error: {}", err);
@@ -194,54 +420,89 @@ impl BitPerfectPlayer {
             }
         }
 
-        self.device.drain()?;
-        Ok(())
+        // A skip should be heard now, not after the buffer has played out.
+        // The old DAC finishes what it has before a switch, so nothing is lost.
+        if interrupted && !switching {
+            self.sink.drop_queued()?;
+        } else {
+            self.sink.drain()?;
+            let mut s = state.lock().unwrap();
+            if let Some(next) = announce {
+                next.publish(&mut s);
+            }
+            s.position_secs = (frames_written - track_start) as f64 / sample_rate as f64;
+            s.position_at = None;
+        }
+        Ok(Played::Finished)
     }
 
-    fn write_decoded_to_device(&mut self, decoded: AudioBufferRef, bit_depth: u16, volume_db: f64) -> Result<(), PlayerError> {
-        let multiplier = db_to_multiplier(volume_db);
-
-        match bit_depth {
-            16 => {
-                let mut sample_buf = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
-                sample_buf.copy_interleaved_ref(decoded);
-                let samples = sample_buf.samples_mut();
-                
-                // Apply volume
-                if volume_db < 0.0 {
-                    for s in samples.iter_mut() {
-                        *s = (*s as f64 * multiplier) as i16;
-                    }
+    /// The file, from memory when reading ahead
+    fn open_source(&mut self, path: &Path) -> std::io::Result<Box<dyn MediaSource>> {
+        if let Some(read_ahead) = &mut self.read_ahead {
+            match read_ahead.open(path) {
+                Ok(track) => return Ok(Box::new(Cursor::new(track))),
+                Err(e) if e.kind() == std::io::ErrorKind::OutOfMemory => {
+                    eprintln!("Not enough memory to read {} ahead; streaming it from disk", path.display());
                 }
-
-                let bytes: &[u8] = unsafe {
-                    std::slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * 2)
-                };
-                self.device.write_raw(bytes)?;
+                Err(e) => return Err(e),
             }
-            24 | 32 => {
-                let mut sample_buf = SampleBuffer::<i32>::new(decoded.capacity() as u64, *decoded.spec());
-                sample_buf.copy_interleaved_ref(decoded);
-                let samples = sample_buf.samples_mut();
-
-                // Apply volume
-                if volume_db < 0.0 {
-                    for s in samples.iter_mut() {
-                        *s = (*s as f64 * multiplier) as i32;
-                    }
+        }
+        Ok(Box::new(File::open(path)?))
+    }
+
+    /// Time of the frame now leaving the DAC: what was written minus what is
+    /// still queued in the sink
+    fn audible_secs(&self, frames_written: u64, sample_rate: u32) -> f64 {
+        let delay = self.sink.delay().unwrap_or(0);
+        audible_frames(frames_written, delay) as f64 / sample_rate as f64
+    }
+
+    /// Put the ReplayGain adjustment on the card's mixer when it has one, so the
+    /// samples stay untouched. Returns the part left for the software stage.
+    fn apply_replaygain(&mut self, gain_db: Option<f64>) -> f64 {
+        if self.hw_volume.is_none() {
+            self.hw_volume = self.sink.card().and_then(HardwareVolume::open);
+        }
+        let gain = gain_db.unwrap_or(0.0);
+        match &self.hw_volume {
+            Some(hw) => match hw.set_gain_db(gain) {
+                // Most USB DACs top out at 0 dB: a positive gain, and whatever
+                // falls between mixer steps, is left to the software stage
+                Ok(applied) => software_remainder(gain, applied),
+                Err(e) => {
+                    eprintln!("Hardware mixer error, using software gain: {}", e);
+                    gain
                 }
+            },
+            None => gain,
+        }
+    }
 
-                let bytes: &[u8] = unsafe {
-                    std::slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * 4)
-                };
-                self.device.write_raw(bytes)?;
-            }
-            _ => eprintln!("Unsupported bit depth: {}", bit_depth),
+    /// Convert one packet and write the part of it in `bytes`
+    fn write_decoded(&mut self, encoder: &mut Encoder, decoded: AudioBufferRef, gain: f64, bytes: Range<usize>) -> Result<(), PlayerError> {
+        // Software gain (volume / ReplayGain without a hardware mixer) is dithered inside
+        let out = encoder.encode(decoded, gain, &mut self.dither);
+        let end = bytes.end.min(out.len());
+        if bytes.start < end {
+            self.sink.write(&out[bytes.start..end])?;
         }
         Ok(())
     }
 }
 
+/// ALSA reports a negative delay after an underrun, and a sink can never hold
+/// more than was written
+fn audible_frames(frames_written: u64, delay: i64) -> u64 {
+    frames_written - (delay.max(0) as u64).min(frames_written)
+}
+
+/// Part of a gain the hardware mixer did not apply. Differences below the
+/// mixer's 0.01 dB resolution are rounding, not gain.
+fn software_remainder(gain_db: f64, applied_db: f64) -> f64 {
+    let rest = gain_db - applied_db;
+    if rest.abs() < 0.005 { 0.0 } else { rest }
+}
+
 pub fn db_to_multiplier(db: f64) -> f64 {
     if db >= 0.0 { 1.0 }
     else if db <= -60.0 { 0.0 }
@@ -260,4 +521,119 @@ mod tests {
         let half = db_to_multiplier(-6.0206);
         assert!((half - 0.5).abs() < 0.001);
     }
+
+    #[test]
+    fn test_mixer_remainder_goes_to_software() {
+        // +4 dB on a control that stops at 0 dB
+        assert_eq!(software_remainder(4.0, 0.0), 4.0);
+        assert_eq!(software_remainder(-6.5, -6.5), 0.0);
+        assert_eq!(software_remainder(-6.504, -6.5), 0.0);
+        // A 0.5 dB step that was floored
+        assert!((software_remainder(-6.3, -6.5) - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_steady_state_write_does_not_allocate() {
+        use std::io::Cursor;
+        use crate::output::memory::MemorySink;
+        use crate::player::alloc_count::allocations;
+        use crate::player::pcm::tests::wav;
+
+        // Two seconds of 24-bit stereo: many packets
+        let data: Vec<u8> = (0..96000 * 2 * 3).map(|i| (i * 7) as u8).collect();
+        let mss = MediaSourceStream::new(Box::new(Cursor::new(wav(false, 24, None, 2, 48000, &data))), Default::default());
+        let mut format = symphonia::default::get_probe()
+            .format(&Hint::new(), mss, &Default::default(), &Default::default())
+            .unwrap()
+            .format;
+        let params = format.default_track().unwrap().codec_params.clone();
+        let max_frames = params.max_frames_per_packet.unwrap();
+        let mut decoder = symphonia::default::get_codecs().make(&params, &Default::default()).unwrap();
+
+        let sink = MemorySink::default();
+        sink.capture().data.reserve(data.len() * 2);
+        let mut player = BitPerfectPlayer::new(sink.clone());
+        let source = SourceFormat::from_codec_params(&params);
+        let encoding = player.sink.configure(48000, source, 2).unwrap();
+        let spec = SignalSpec::new(48000, params.channels.unwrap());
+        let mut encoder = Encoder::new(source, encoding, spec, params.max_frames_per_packet);
+
+        let mut packets = 0;
+        // Demuxing hands out a fresh packet each time, and the decoder sets up
+        // the short last one anew. Every full packet must decode, convert (at
+        // unity and dithered gain) and reach the sink without allocating.
+        while let Ok(packet) = format.next_packet() {
+            let full = packet.dur == max_frames;
+            let gain = if packets % 2 == 0 { 1.0 } else { 0.5 };
+            let (decoded, decode_count) = allocations(|| decoder.decode(&packet).unwrap());
+            let (result, write_count) = allocations(|| player.write_decoded(&mut encoder, decoded, gain, 0..usize::MAX));
+            result.unwrap();
+            assert_eq!(write_count, 0, "writing packet {} allocated", packets);
+            assert!(!full || decode_count == 0, "decoding packet {} allocated", packets);
+            packets += 1;
+        }
+        assert!(packets > 10);
+        assert_eq!(sink.capture().data.len(), data.len() / 3 * 4);
+    }
+
+    #[test]
+    fn test_pause_releases_device_when_idle() {
+        use crate::output::memory::MemorySink;
+        use crate::player::pcm::tests::{wav, TempPath};
+
+        let pcm: Vec<u8> = (0..8000u16).flat_map(|i| i.to_le_bytes()).collect();
+        let path = TempPath::file("pause-release.wav", wav(false, 16, None, 1, 8000, &pcm));
+        let sink = MemorySink::default();
+        let mut player = BitPerfectPlayer::new(sink.clone());
+        player.set_idle_release(Some(Duration::ZERO));
+        let state = Arc::new(Mutex::new(PlayerState { is_playing: false, ..PlayerState::headless() }));
+
+        // Paused before the first packet: the device is closed, not held
+        let played = player.play_track(&Track::file(&path), 0.0, state.clone(), |_| None).unwrap();
+        assert_eq!(played, Played::Released(0.0));
+        assert_eq!(sink.capture().releases, 1);
+        assert!(sink.capture().data.is_empty());
+
+        state.lock().unwrap().is_playing = true;
+        let played = player.play_track(&Track::file(&path), 0.0, state, |_| None).unwrap();
+        assert_eq!(played, Played::Finished);
+        assert_eq!(sink.capture().data, pcm);
+    }
+
+    #[test]
+    fn test_stays_paused_while_the_gui_holds_the_lock() {
+        use crate::output::memory::MemorySink;
+        use crate::player::pcm::tests::{wav, TempPath};
+
+        let pcm: Vec<u8> = (0..8000u16).flat_map(|i| i.to_le_bytes()).collect();
+        let path = TempPath::file("pause-busy.wav", wav(false, 16, None, 1, 8000, &pcm));
+        let sink = MemorySink::default();
+        let mut player = BitPerfectPlayer::new(sink.clone());
+        let state = Arc::new(Mutex::new(PlayerState { is_playing: false, ..PlayerState::headless() }));
+
+        // The GUI draws for a while during the pause, then stops playback
+        let gui = std::thread::spawn({
+            let (state, sink) = (state.clone(), sink.clone());
+            move || {
+                while !sink.capture().paused {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                let mut s = state.lock().unwrap();
+                std::thread::sleep(Duration::from_millis(200));
+                s.command = Some(PlayerCommand::Stop);
+            }
+        });
+        let played = player.play_track(&Track::file(&path), 0.0, state, |_| None).unwrap();
+        gui.join().unwrap();
+        assert_eq!(played, Played::Finished);
+        assert!(sink.capture().paused);
+        assert!(sink.capture().data.is_empty());
+    }
+
+    #[test]
+    fn test_position_excludes_queued_frames() {
+        assert_eq!(audible_frames(48000, 12000), 36000);
+        assert_eq!(audible_frames(48000, -32), 48000);
+        assert_eq!(audible_frames(100, 4096), 0);
+    }
 }
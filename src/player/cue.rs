@@ -0,0 +1,445 @@
+//! CUE sheets: one file per album, cut into tracks by a `.cue` next to it or
+//! by a cue sheet embedded in a FLAC file
+
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use symphonia::core::codecs::CODEC_TYPE_NULL;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataRevision;
+use symphonia::core::probe::Hint;
+
+use crate::player::formats::sniff_file;
+use crate::player::track::{Span, Track};
+
+/// CD frames per second, the unit of INDEX times
+const CD_FRAMES: u64 = 75;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CueFile {
+    /// As written in the sheet, relative to it
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// INDEX 01 in CD frames. The pregap (INDEX 00) stays with the track before.
+    pub start: u64,
+}
+
+impl CueSheet {
+    /// Lenient: unknown commands, data tracks and malformed lines are skipped
+    pub fn parse(text: &str) -> Self {
+        let mut sheet = CueSheet::default();
+        // TITLE/PERFORMER before any TRACK describe the album; inside a data
+        // track they are dropped
+        let mut in_track = false;
+        let mut pending: Option<CueTrack> = None;
+
+        for line in text.trim_start_matches('\u{feff}').lines() {
+            let words = split_words(line);
+            let Some((command, args)) = words.split_first() else { continue };
+            match (command.to_ascii_uppercase().as_str(), args) {
+                ("FILE", [name, ..]) => {
+                    finish_track(&mut sheet, &mut pending);
+                    sheet.files.push(CueFile { name: name.clone(), tracks: Vec::new() });
+                }
+                ("TRACK", [number, kind, ..]) => {
+                    finish_track(&mut sheet, &mut pending);
+                    in_track = true;
+                    if kind.eq_ignore_ascii_case("AUDIO") && let Ok(number) = number.parse() {
+                        pending = Some(CueTrack { number, title: None, performer: None, start: u64::MAX });
+                    }
+                }
+                ("TITLE", [title, ..]) => match &mut pending {
+                    Some(track) => track.title = Some(title.clone()),
+                    None if !in_track => sheet.title = Some(title.clone()),
+                    None => {}
+                },
+                ("PERFORMER", [performer, ..]) => match &mut pending {
+                    Some(track) => track.performer = Some(performer.clone()),
+                    None if !in_track => sheet.performer = Some(performer.clone()),
+                    None => {}
+                },
+                ("INDEX", [number, time, ..]) if number.parse() == Ok(1) => {
+                    if let (Some(track), Some(frames)) = (&mut pending, parse_time(time)) {
+                        track.start = frames;
+                    }
+                }
+                _ => {}
+            }
+        }
+        finish_track(&mut sheet, &mut pending);
+        sheet
+    }
+}
+
+/// A track without INDEX 01 cannot be placed and is dropped
+fn finish_track(sheet: &mut CueSheet, pending: &mut Option<CueTrack>) {
+    if let Some(track) = pending.take()
+        && track.start != u64::MAX
+        && let Some(file) = sheet.files.last_mut()
+    {
+        file.tracks.push(track);
+    }
+}
+
+/// Words of a line, with double-quoted strings kept together
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            words.push(quoted[..end].to_string());
+            rest = quoted.get(end + 1..).unwrap_or("").trim_start();
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            words.push(rest[..end].to_string());
+            rest = rest[end..].trim_start();
+        }
+    }
+    words
+}
+
+/// `mm:ss:ff` in CD frames
+fn parse_time(time: &str) -> Option<u64> {
+    let mut parts = time.split(':').map(|p| p.parse::<u64>().ok());
+    let (m, s, f) = (parts.next()??, parts.next()??, parts.next()??);
+    (parts.next().is_none() && s < 60 && f < CD_FRAMES).then_some((m * 60 + s) * CD_FRAMES + f)
+}
+
+/// What `path` contributes to the playlist: the tracks of a `.cue` file or of a
+/// cue sheet embedded in the audio, or else the file as a whole
+pub fn tracks_for(path: &Path) -> Vec<Track> {
+    if is_cue_file(path) {
+        return read_sheet(path).map(|(sheet, dir)| sheet_tracks(&sheet, &dir)).unwrap_or_default();
+    }
+    embedded_tracks(path)
+        .filter(|tracks| !tracks.is_empty())
+        .unwrap_or_else(|| vec![Track::file(path)])
+}
+
+pub fn is_cue_file(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e.eq_ignore_ascii_case("cue")) && path.is_file()
+}
+
+/// Audio files in `dir` that a `.cue` there already covers, so adding the
+/// directory does not list the album twice
+pub fn covered_files(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+    entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| is_cue_file(p))
+        .filter_map(|p| read_sheet(&p))
+        .flat_map(|(sheet, dir)| sheet.files.iter().filter_map(|f| locate(&dir, &f.name)).collect::<Vec<_>>())
+        .collect()
+}
+
+fn read_sheet(path: &Path) -> Option<(CueSheet, PathBuf)> {
+    let bytes = fs::read(path).ok()?;
+    // Older rippers write the system code page; Latin-1 keeps those readable
+    let text = String::from_utf8(bytes).unwrap_or_else(|e| e.into_bytes().iter().map(|&b| b as char).collect());
+    Some((CueSheet::parse(&text), path.parent()?.to_path_buf()))
+}
+
+/// The file a FILE line names. Rips are often re-encoded after the sheet was
+/// written ("album.wav" next to "album.flac"), so fall back to the same stem.
+fn locate(dir: &Path, name: &str) -> Option<PathBuf> {
+    let path = dir.join(name);
+    if path.is_file() {
+        return Some(path);
+    }
+    let stem = Path::new(name).file_stem()?;
+    fs::read_dir(dir).ok()?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .find(|p| p.file_stem() == Some(stem) && !is_cue_file(p) && sniff_file(p).is_some())
+}
+
+fn sheet_tracks(sheet: &CueSheet, dir: &Path) -> Vec<Track> {
+    let mut tracks = Vec::new();
+    for file in &sheet.files {
+        let Some(path) = locate(dir, &file.name) else { continue };
+        let Some(rate) = probe(&path).map(|p| p.sample_rate) else { continue };
+        tracks.extend(cut(&path, rate, &file.tracks, sheet.performer.as_deref()));
+    }
+    tracks
+}
+
+/// Each track runs from its INDEX 01 to the next one's, the last to the end of the file
+fn cut(path: &Path, rate: u32, tracks: &[CueTrack], performer: Option<&str>) -> Vec<Track> {
+    let starts: Vec<u64> = tracks.iter().map(|t| t.start * rate as u64 / CD_FRAMES).collect();
+    tracks.iter().enumerate().map(|(i, t)| Track {
+        path: path.to_path_buf(),
+        span: Some(Span { start: starts[i], end: starts.get(i + 1).copied() }),
+        number: Some(t.number),
+        title: t.title.clone(),
+        performer: t.performer.clone().or_else(|| performer.map(str::to_string)),
+    }).collect()
+}
+
+struct Probed {
+    sample_rate: u32,
+    /// A CUESHEET tag: the text of the sheet the file was ripped with
+    sheet: Option<String>,
+}
+
+fn probe(path: &Path) -> Option<Probed> {
+    let mss = MediaSourceStream::new(Box::new(File::open(path).ok()?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mut probed = symphonia::default::get_probe()
+        .format(&hint, mss, &Default::default(), &Default::default())
+        .ok()?;
+    let sample_rate = probed.format.tracks().iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)?
+        .codec_params.sample_rate?;
+    let sheet_tag = |meta: &MetadataRevision| {
+        meta.tags().iter().find(|t| t.key.eq_ignore_ascii_case("CUESHEET")).map(|t| t.value.to_string())
+    };
+    let mut sheet = probed.format.metadata().current().and_then(sheet_tag);
+    if sheet.is_none() {
+        sheet = probed.metadata.get().as_ref().and_then(|m| m.current()).and_then(sheet_tag);
+    }
+    Some(Probed { sample_rate, sheet })
+}
+
+/// Tracks from a cue sheet inside the file: the CUESHEET tag has titles, the
+/// FLAC CUESHEET block only positions
+fn embedded_tracks(path: &Path) -> Option<Vec<Track>> {
+    let probed = probe(path)?;
+    if let Some(text) = probed.sheet {
+        let sheet = CueSheet::parse(&text);
+        // The sheet describes this very file, whatever its FILE line says
+        let tracks: Vec<CueTrack> = sheet.files.into_iter().flat_map(|f| f.tracks).collect();
+        return Some(cut(path, probed.sample_rate, &tracks, sheet.performer.as_deref()));
+    }
+    Some(block_tracks(path, &read_cuesheet_block(path)?))
+}
+
+/// A track of a FLAC CUESHEET block
+#[derive(Debug, PartialEq)]
+struct BlockTrack {
+    number: u8,
+    /// In samples from the start of the file
+    offset: u64,
+    audio: bool,
+    /// Index number and offset from the track's start
+    indices: Vec<(u8, u64)>,
+}
+
+impl BlockTrack {
+    /// Where INDEX 01 is, or the first index for a track without one
+    fn start(&self) -> u64 {
+        let index = self.indices.iter().find(|&&(n, _)| n == 1).or(self.indices.first());
+        self.offset + index.map_or(0, |&(_, offset)| offset)
+    }
+
+    /// Track 170 on CDs, 255 otherwise
+    fn is_lead_out(&self) -> bool {
+        self.number == 170 || self.number == 255
+    }
+}
+
+/// Audio tracks run from INDEX 01 to the next track's; the lead-out ends the last
+fn block_tracks(path: &Path, block: &[BlockTrack]) -> Vec<Track> {
+    block.iter().enumerate()
+        .filter(|(_, t)| t.audio && !t.is_lead_out())
+        .map(|(i, t)| Track {
+            path: path.to_path_buf(),
+            span: Some(Span { start: t.start(), end: block.get(i + 1).map(BlockTrack::start) }),
+            number: Some(t.number as u32),
+            title: None,
+            performer: None,
+        })
+        .collect()
+}
+
+/// The CUESHEET metadata block of a FLAC file. Read here rather than through
+/// symphonia, which drops the index numbers.
+fn read_cuesheet_block(path: &Path) -> Option<Vec<BlockTrack>> {
+    const CUESHEET: u8 = 5;
+    let mut file = File::open(path).ok()?;
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic).ok()?;
+    // An ID3v2 tag in front of the stream, as some taggers write
+    if magic[..3] == *b"ID3" {
+        let mut header = [0u8; 6];
+        file.read_exact(&mut header).ok()?;
+        let size = header[2..].iter().fold(0u64, |n, &b| n << 7 | (b & 0x7f) as u64);
+        let footer = if header[1] & 0x10 != 0 { 10 } else { 0 };
+        file.seek(SeekFrom::Start(10 + size + footer)).ok()?;
+        file.read_exact(&mut magic).ok()?;
+    }
+    if magic != *b"fLaC" {
+        return None;
+    }
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header).ok()?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+        if header[0] & 0x7f == CUESHEET {
+            let mut block = vec![0; len as usize];
+            file.read_exact(&mut block).ok()?;
+            return parse_cuesheet_block(&block);
+        }
+        if header[0] & 0x80 != 0 {
+            return None;
+        }
+        file.seek(SeekFrom::Current(len as i64)).ok()?;
+    }
+}
+
+/// Catalog number, lead-in and flags, then the tracks, each with its index points
+fn parse_cuesheet_block(block: &[u8]) -> Option<Vec<BlockTrack>> {
+    let u64_at = |at: usize| block.get(at..at + 8).map(|b| u64::from_be_bytes(b.try_into().unwrap()));
+    let mut at = 128 + 8 + 259;
+    let count = *block.get(at)?;
+    at += 1;
+    let mut tracks = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let offset = u64_at(at)?;
+        let number = *block.get(at + 8)?;
+        // ISRC, then the non-audio flag in the top bit
+        let audio = block.get(at + 21)? & 0x80 == 0;
+        let points = *block.get(at + 35)?;
+        at += 36;
+        let mut indices = Vec::with_capacity(points as usize);
+        for _ in 0..points {
+            indices.push((*block.get(at + 8)?, u64_at(at)?));
+            at += 12;
+        }
+        tracks.push(BlockTrack { number, offset, audio, indices });
+    }
+    Some(tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::pcm::tests::{wav, TempPath};
+
+    const SHEET: &str = "\u{feff}REM GENRE Jazz\r
+PERFORMER \"Miles Davis\"\r
+TITLE \"Kind of Blue\"\r
+FILE \"Kind of Blue.wav\" WAVE\r
+  TRACK 01 AUDIO\r
+    TITLE \"So What\"\r
+    INDEX 01 00:00:00\r
+  TRACK 02 AUDIO\r
+    TITLE \"Freddie Freeloader\"\r
+    PERFORMER \"Miles Davis Sextet\"\r
+    INDEX 00 09:20:50\r
+    INDEX 01 09:22:00\r
+  TRACK 03 MODE1/2352\r
+    TITLE \"Data\"\r
+    INDEX 01 20:00:00\r
+";
+
+    #[test]
+    fn test_parse_sheet() {
+        let sheet = CueSheet::parse(SHEET);
+        assert_eq!(sheet.title.as_deref(), Some("Kind of Blue"));
+        assert_eq!(sheet.performer.as_deref(), Some("Miles Davis"));
+        assert_eq!(sheet.files.len(), 1);
+        let file = &sheet.files[0];
+        assert_eq!(file.name, "Kind of Blue.wav");
+        assert_eq!(file.tracks, [
+            CueTrack { number: 1, title: Some("So What".into()), performer: None, start: 0 },
+            CueTrack {
+                number: 2,
+                title: Some("Freddie Freeloader".into()),
+                performer: Some("Miles Davis Sextet".into()),
+                start: (9 * 60 + 22) * 75,
+            },
+        ]);
+        assert_eq!(parse_time("01:02:74"), Some(62 * 75 + 74));
+        assert_eq!(parse_time("01:60:00"), None);
+    }
+
+    #[test]
+    fn test_sheet_next_to_renamed_rip() {
+        let dir = TempPath::dir("cue");
+        // The sheet names a .wav; the rip was re-encoded. 48 kHz: 640 samples per CD frame.
+        fs::write(dir.join("Kind of Blue.flac"), wav(false, 16, None, 1, 48000, &[0; 8])).unwrap();
+        fs::write(dir.join("album.cue"), SHEET).unwrap();
+
+        let tracks = tracks_for(&dir.join("album.cue"));
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].path, dir.join("Kind of Blue.flac"));
+        assert_eq!(tracks[0].span, Some(Span { start: 0, end: Some(562 * 75 * 640) }));
+        assert_eq!(tracks[1].span, Some(Span { start: 562 * 75 * 640, end: None }));
+        assert_eq!(tracks[1].performer.as_deref(), Some("Miles Davis Sextet"));
+        assert_eq!(tracks[0].performer.as_deref(), Some("Miles Davis"));
+        assert_eq!(tracks[0].name(), "01. So What");
+        assert!(tracks[0].continues_into(&tracks[1]));
+        assert_eq!(covered_files(&dir), [dir.join("Kind of Blue.flac")]);
+    }
+
+    /// A CUESHEET block as FLAC stores it
+    fn cuesheet_block(tracks: &[BlockTrack]) -> Vec<u8> {
+        let mut block = vec![0; 128 + 8 + 259];
+        block.push(tracks.len() as u8);
+        for track in tracks {
+            block.extend(track.offset.to_be_bytes());
+            block.push(track.number);
+            block.extend([0; 12]);
+            block.push(if track.audio { 0 } else { 0x80 });
+            block.extend([0; 13]);
+            block.push(track.indices.len() as u8);
+            for &(index, offset) in &track.indices {
+                block.extend(offset.to_be_bytes());
+                block.push(index);
+                block.extend([0; 3]);
+            }
+        }
+        block
+    }
+
+    #[test]
+    fn test_flac_cuesheet_block() {
+        let track = |number, offset, audio, indices: &[(u8, u64)]| BlockTrack { number, offset, audio, indices: indices.to_vec() };
+        let tracks = [
+            track(1, 0, true, &[(1, 0)]),
+            // Pregap before INDEX 01
+            track(2, 44100, true, &[(0, 0), (1, 588)]),
+            // No pregap; INDEX 02 marks a movement inside the track
+            track(3, 88200, true, &[(1, 0), (2, 588)]),
+            track(4, 132300, false, &[(1, 0)]),
+            track(170, 176400, true, &[]),
+        ];
+        let block = cuesheet_block(&tracks);
+        // Behind STREAMINFO, as in a real file
+        let mut flac = b"fLaC".to_vec();
+        flac.extend([0x00, 0, 0, 34]);
+        flac.extend([0; 34]);
+        flac.extend([0x80 | 5, 0, (block.len() >> 8) as u8, block.len() as u8]);
+        flac.extend(&block);
+        let path = TempPath::file("cuesheet.flac", flac);
+        let read = read_cuesheet_block(&path);
+        assert_eq!(read.as_deref(), Some(&tracks[..]));
+
+        let tracks = block_tracks(&path, &read.unwrap());
+        assert_eq!(tracks.iter().map(|t| t.span.unwrap()).collect::<Vec<_>>(), [
+            Span { start: 0, end: Some(44688) },
+            Span { start: 44688, end: Some(88200) },
+            // The data track is left out, and ends the audio
+            Span { start: 88200, end: Some(132300) },
+        ]);
+        assert_eq!(tracks[1].name(), format!("02. {}", path.file_name().unwrap().to_string_lossy()));
+    }
+}
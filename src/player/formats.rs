@@ -0,0 +1,366 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Audio formats recognised from file content, regardless of extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Flac,
+    Wav,
+    Aiff,
+    Vorbis,
+    Opus,
+    OggFlac,
+    /// MP4/M4A; ALAC or AAC inside
+    Mp4,
+    Mp3,
+    Aac,
+    Caf,
+    Matroska,
+    WavPack,
+    Ape,
+}
+
+impl AudioFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            AudioFormat::Flac => "FLAC",
+            AudioFormat::Wav => "WAV",
+            AudioFormat::Aiff => "AIFF",
+            AudioFormat::Vorbis => "Ogg Vorbis",
+            AudioFormat::Opus => "Opus",
+            AudioFormat::OggFlac => "Ogg FLAC",
+            AudioFormat::Mp4 => "MP4",
+            AudioFormat::Mp3 => "MP3",
+            AudioFormat::Aac => "AAC",
+            AudioFormat::Caf => "CAF",
+            AudioFormat::Matroska => "Matroska",
+            AudioFormat::WavPack => "WavPack",
+            AudioFormat::Ape => "Monkey's Audio",
+        }
+    }
+
+    /// Whether the symphonia build we ship can decode it. Opus, WavPack and
+    /// Monkey's Audio are recognised so the player can say why it skips them.
+    pub fn is_decodable(self) -> bool {
+        !matches!(self, AudioFormat::Opus | AudioFormat::WavPack | AudioFormat::Ape)
+    }
+}
+
+/// Identify the format from the first bytes of a file
+pub fn sniff(header: &[u8]) -> Option<AudioFormat> {
+    let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+    if at(0, b"fLaC") {
+        Some(AudioFormat::Flac)
+    } else if (at(0, b"RIFF") && at(8, b"WAVE")) || at(0, b"RF64") || at(0, b"BW64") {
+        Some(AudioFormat::Wav)
+    } else if at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) {
+        Some(AudioFormat::Aiff)
+    } else if at(0, b"OggS") {
+        // The codec id sits in the first packet, right after the 28-byte page header
+        if at(28, b"OpusHead") {
+            Some(AudioFormat::Opus)
+        } else if at(28, b"\x01vorbis") {
+            Some(AudioFormat::Vorbis)
+        } else if at(28, b"\x7fFLAC") {
+            Some(AudioFormat::OggFlac)
+        } else {
+            None
+        }
+    } else if at(4, b"ftyp") {
+        Some(AudioFormat::Mp4)
+    } else if at(0, b"caff") {
+        Some(AudioFormat::Caf)
+    } else if at(0, b"\x1a\x45\xdf\xa3") {
+        Some(AudioFormat::Matroska)
+    } else if at(0, b"wvpk") {
+        Some(AudioFormat::WavPack)
+    } else if at(0, b"MAC ") {
+        Some(AudioFormat::Ape)
+    } else if header.len() >= 2 && header[0] == 0xff && header[1] & 0xe0 == 0xe0 {
+        // MPEG frame sync; layer bits 00 mean ADTS AAC
+        if header[1] & 0x06 == 0 {
+            Some(AudioFormat::Aac)
+        } else {
+            Some(AudioFormat::Mp3)
+        }
+    } else {
+        None
+    }
+}
+
+/// Sniff a file, looking past a leading ID3v2 tag (MP3, but also some FLAC/AAC rips).
+/// MP4 and Matroska also hold video; those count only with audio and no video in them.
+pub fn sniff_file(path: &Path) -> Option<AudioFormat> {
+    let mut file = File::open(path).ok()?;
+    let format = sniff_header(&mut file)?;
+    let tracks = match format {
+        AudioFormat::Mp4 => mp4_tracks(&mut file),
+        AudioFormat::Matroska => matroska_tracks(&mut file),
+        _ => return Some(format),
+    };
+    matches!(tracks, Some(Tracks { audio: true, video: false })).then_some(format)
+}
+
+fn sniff_header(file: &mut File) -> Option<AudioFormat> {
+    let mut header = [0u8; 64];
+    let n = read_up_to(file, &mut header)?;
+    if n >= 10 && &header[..3] == b"ID3" {
+        // Syncsafe size, plus 10 bytes header and an optional 10 byte footer
+        let size = header[6..10].iter().fold(0u64, |acc, b| (acc << 7) | (*b & 0x7f) as u64);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        file.seek(SeekFrom::Start(10 + size + footer)).ok()?;
+        let n = read_up_to(file, &mut header)?;
+        // A bare ID3 tag in front of MPEG audio is still an MP3
+        return sniff(&header[..n]).or(Some(AudioFormat::Mp3));
+    }
+    sniff(&header[..n])
+}
+
+/// Kinds of track a container holds
+#[derive(Debug, Default, PartialEq)]
+struct Tracks {
+    audio: bool,
+    video: bool,
+}
+
+/// Boxes and elements holding the track list are read whole; they are small
+/// unless the file is not what it claims to be
+const MAX_TRACK_LIST: u64 = 64 << 20;
+
+/// Handlers of the `moov/trak/mdia/hdlr` boxes: "soun" or "vide"
+fn mp4_tracks(file: &mut File) -> Option<Tracks> {
+    let len = file.metadata().ok()?.len();
+    // moov comes before or after the media data; walk the top level to find it
+    let mut pos = 0;
+    while pos + 8 <= len {
+        file.seek(SeekFrom::Start(pos)).ok()?;
+        let mut head = [0u8; 16];
+        let n = read_up_to(file, &mut head)?;
+        let (size, header) = match u32::from_be_bytes(head[..4].try_into().ok()?) {
+            0 => (len - pos, 8),
+            1 if n == 16 => (u64::from_be_bytes(head[8..16].try_into().ok()?), 16),
+            size => (size as u64, 8),
+        };
+        if size < header {
+            return None;
+        }
+        if &head[4..8] == b"moov" {
+            let body_len = (size - header).min(MAX_TRACK_LIST) as usize;
+            file.seek(SeekFrom::Start(pos + header)).ok()?;
+            let mut moov = vec![0u8; body_len];
+            file.read_exact(&mut moov).ok()?;
+            let mut tracks = Tracks::default();
+            for trak in mp4_children(&moov, b"trak") {
+                for mdia in mp4_children(trak, b"mdia") {
+                    for hdlr in mp4_children(mdia, b"hdlr") {
+                        // version and flags, pre_defined, then the handler type
+                        match hdlr.get(8..12) {
+                            Some(b"soun") => tracks.audio = true,
+                            Some(b"vide") => tracks.video = true,
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            return Some(tracks);
+        }
+        pos += size;
+    }
+    None
+}
+
+/// Bodies of the child boxes of type `kind`
+fn mp4_children<'a>(data: &'a [u8], kind: &'a [u8; 4]) -> impl Iterator<Item = &'a [u8]> + 'a {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        while rest.len() >= 8 {
+            let size = (u32::from_be_bytes(rest[..4].try_into().ok()?) as usize).min(rest.len());
+            if size < 8 {
+                return None;
+            }
+            let (item, after) = rest.split_at(size);
+            rest = after;
+            if &item[4..8] == kind {
+                return Some(&item[8..]);
+            }
+        }
+        None
+    })
+}
+
+const EBML_SEGMENT: u32 = 0x1853_8067;
+const EBML_TRACKS: u32 = 0x1654_ae6b;
+const EBML_CLUSTER: u32 = 0x1f43_b675;
+const EBML_TRACK_ENTRY: u32 = 0xae;
+const EBML_TRACK_TYPE: u32 = 0x83;
+
+/// TrackType of each entry in the Segment's Tracks: 1 is video, 2 audio
+fn matroska_tracks(file: &mut File) -> Option<Tracks> {
+    file.seek(SeekFrom::Start(0)).ok()?;
+    // The EBML header, then the Segment, whose children are walked in place
+    let (_, size) = ebml_element(file)?;
+    file.seek(SeekFrom::Current(size? as i64)).ok()?;
+    let (id, _) = ebml_element(file)?;
+    if id != EBML_SEGMENT {
+        return None;
+    }
+    loop {
+        let (id, size) = ebml_element(file)?;
+        match id {
+            EBML_TRACKS => {
+                let mut body = vec![0u8; size?.min(MAX_TRACK_LIST) as usize];
+                file.read_exact(&mut body).ok()?;
+                let mut tracks = Tracks::default();
+                for (id, entry) in ebml_children(&body) {
+                    if id != EBML_TRACK_ENTRY {
+                        continue;
+                    }
+                    for (id, value) in ebml_children(entry) {
+                        match (id, value) {
+                            (EBML_TRACK_TYPE, [1]) => tracks.video = true,
+                            (EBML_TRACK_TYPE, [2]) => tracks.audio = true,
+                            _ => {}
+                        }
+                    }
+                }
+                return Some(tracks);
+            }
+            // Media data starts; a file without Tracks by now has none
+            EBML_CLUSTER => return None,
+            _ => {
+                file.seek(SeekFrom::Current(size? as i64)).ok()?;
+            }
+        }
+    }
+}
+
+/// Id and size of the element at the reader's position; `None` size is "unknown"
+fn ebml_element(file: &mut File) -> Option<(u32, Option<u64>)> {
+    let pos = file.stream_position().ok()?;
+    let mut head = [0u8; 12];
+    let n = read_up_to(file, &mut head)?;
+    let (id, id_len) = ebml_vint(&head[..n], true)?;
+    let (size, size_len) = ebml_vint(head[..n].get(id_len..)?, false)?;
+    file.seek(SeekFrom::Start(pos + (id_len + size_len) as u64)).ok()?;
+    let unknown = size == (1 << (7 * size_len)) - 1;
+    Some((id as u32, (!unknown).then_some(size)))
+}
+
+/// Child elements of a body held in memory, as (id, body)
+fn ebml_children(data: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    let mut rest = data;
+    std::iter::from_fn(move || {
+        let (id, id_len) = ebml_vint(rest, true)?;
+        let (size, size_len) = ebml_vint(rest.get(id_len..)?, false)?;
+        let start = id_len + size_len;
+        let end = start.checked_add(size as usize)?.min(rest.len());
+        let body = rest.get(start..end)?;
+        rest = &rest[end..];
+        Some((id as u32, body))
+    })
+}
+
+/// Variable-length integer: the leading zeros of the first byte give its length.
+/// Ids keep the length marker bit, sizes drop it.
+fn ebml_vint(data: &[u8], keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return None;
+    }
+    let bytes = data.get(..len)?;
+    let head = if keep_marker { first as u64 } else { first as u64 & (0xff >> len) };
+    Some((bytes[1..].iter().fold(head, |acc, &b| (acc << 8) | b as u64), len))
+}
+
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> Option<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(_) => return None,
+        }
+    }
+    Some(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::pcm::tests::TempPath;
+
+    fn ogg(codec_id: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.resize(28, 0);
+        page.extend_from_slice(codec_id);
+        page
+    }
+
+    #[test]
+    fn test_sniff_by_content() {
+        assert_eq!(sniff(b"fLaC\0\0\0\x22"), Some(AudioFormat::Flac));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), Some(AudioFormat::Wav));
+        assert_eq!(sniff(b"FORM\0\0\0\0AIFC"), Some(AudioFormat::Aiff));
+        assert_eq!(sniff(b"\0\0\0\x20ftypM4A "), Some(AudioFormat::Mp4));
+        assert_eq!(sniff(&ogg(b"\x01vorbis")), Some(AudioFormat::Vorbis));
+        assert_eq!(sniff(&ogg(b"OpusHead")), Some(AudioFormat::Opus));
+        assert_eq!(sniff(&[0xff, 0xfb, 0x90, 0x00]), Some(AudioFormat::Mp3));
+        assert_eq!(sniff(&[0xff, 0xf1, 0x50, 0x80]), Some(AudioFormat::Aac));
+        assert_eq!(sniff(b"wvpk"), Some(AudioFormat::WavPack));
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn test_containers_need_audio_and_no_video() {
+        fn mp4_box(kind: &[u8], body: &[u8]) -> Vec<u8> {
+            let mut b = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+            b.extend_from_slice(kind);
+            b.extend_from_slice(body);
+            b
+        }
+        let trak = |handler: &[u8]| {
+            let hdlr = mp4_box(b"hdlr", &[&[0; 8][..], handler, &[0; 12]].concat());
+            mp4_box(b"trak", &mp4_box(b"mdia", &hdlr))
+        };
+        let mp4 = |traks: &[Vec<u8>]| {
+            // Media data ahead of moov, as a plain (non-faststart) file has it
+            [mp4_box(b"ftyp", b"M4A \0\0\0\0"), mp4_box(b"mdat", &[0; 32]), mp4_box(b"moov", &traks.concat())].concat()
+        };
+        // EBML header, Segment of unknown size, Tracks with one TrackEntry per type
+        let mkv = |types: &[u8]| {
+            let mut data = b"\x1a\x45\xdf\xa3\x80\x18\x53\x80\x67\x01\xff\xff\xff\xff\xff\xff\xff".to_vec();
+            data.extend(b"\x16\x54\xae\x6b");
+            data.push(0x80 | (types.len() * 5) as u8);
+            for t in types {
+                data.extend([0xae, 0x83, 0x83, 0x81, *t]);
+            }
+            data.extend(b"\x1f\x43\xb6\x75\x80");
+            data
+        };
+
+        let path = TempPath::new("container.bin");
+        for (data, expected) in [
+            (mp4(&[trak(b"soun")]), Some(AudioFormat::Mp4)),
+            (mp4(&[trak(b"vide"), trak(b"soun")]), None),
+            (mp4(&[]), None),
+            (mkv(&[2]), Some(AudioFormat::Matroska)),
+            (mkv(&[1, 2]), None),
+        ] {
+            std::fs::write(&path, &data).unwrap();
+            assert_eq!(sniff_file(&path), expected);
+        }
+    }
+
+    #[test]
+    fn test_sniff_file_skips_id3() {
+        let mut data = b"ID3\x04\0\0\0\0\0\x05".to_vec();
+        data.extend_from_slice(&[0; 5]);
+        data.extend_from_slice(b"fLaC\0\0\0\x22");
+        let path = TempPath::file("sniff.bin", data);
+        assert_eq!(sniff_file(&path), Some(AudioFormat::Flac));
+    }
+}
@@ -1,10 +1,13 @@
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
-use crate::gui::PlayerState;
-use crate::player::bitperfect::{BitPerfectPlayer, PlayerError};
-use crate::alsa::device::BitPerfectDevice;
+use crate::gui::{PlayerState, TrackInfo};
+use crate::player::bitperfect::{BitPerfectPlayer, Following, Played, PlayerError};
+use crate::player::queue::PlayQueue;
+use crate::player::track::Track;
+use crate::output::{AudioSink, SinkError};
 
 #[derive(Debug, Error)]
 pub enum GaplessError {
@@ -16,79 +19,145 @@ pub enum GaplessError {
     Io(#[from] std::io::Error),
 }
 
+impl GaplessError {
+    /// Another application holds the DAC; retrying will not help until it lets go
+    pub fn is_device_busy(&self) -> bool {
+        matches!(self, GaplessError::Player(PlayerError::Sink(SinkError::Busy { .. })))
+    }
+
+    /// No decoder for the file; retrying will not help, skipping it will
+    pub fn is_unsupported_format(&self) -> bool {
+        matches!(self, GaplessError::Player(PlayerError::UnsupportedFormat(_)))
+    }
+
+    /// The DAC was unplugged (or is not plugged in); playback is paused until it returns
+    pub fn is_device_gone(&self) -> bool {
+        matches!(self, GaplessError::Player(PlayerError::Sink(e)) if e.is_disconnected())
+    }
+}
+
 pub struct GaplessEngine {
-    playlist: Vec<PathBuf>,
+    playlist: Vec<Track>,
+    queue: PlayQueue,
     current_track: usize,
     player_state: Arc<Mutex<PlayerState>>,
     is_playing: bool,
-    card: String,
-    device_index: u32,
+    /// Lives across tracks, together with the sink it writes to
+    player: BitPerfectPlayer<Box<dyn AudioSink>>,
+    /// Close the device after this long without playing
+    idle_release: Option<Duration>,
+    idle_since: Option<Instant>,
+    /// Where to pick the current track up again after losing the device
+    resume_at: Option<f64>,
 }
 
 impl GaplessEngine {
-    pub fn new(player_state: Arc<Mutex<PlayerState>>, card: String, device_index: u32) -> Self {
+    pub fn new(player_state: Arc<Mutex<PlayerState>>, sink: Box<dyn AudioSink>) -> Self {
         Self {
             playlist: Vec::new(),
+            queue: PlayQueue::new(),
             current_track: 0,
             player_state,
             is_playing: false,
-            card,
-            device_index,
+            player: BitPerfectPlayer::new(sink),
+            idle_release: None,
+            idle_since: None,
+            resume_at: None,
         }
     }
 
+    pub fn set_idle_release(&mut self, after: Option<Duration>) {
+        self.idle_release = after;
+        self.player.set_idle_release(after);
+    }
+
+    /// Play each track from RAM, loading the next one while the current one plays
+    pub fn set_read_ahead(&mut self, enabled: bool) {
+        self.player.set_read_ahead(enabled);
+    }
+
     pub fn add_to_playlist(&mut self, path: &Path) {
-        self.playlist.push(path.to_path_buf());
+        self.add_track(Track::file(path));
     }
 
-    fn open_device(&self) -> Result<BitPerfectDevice, alsa::Error> {
-        if self.card == "default" {
-            BitPerfectDevice::open("2", 0)
-                .or_else(|_| BitPerfectDevice::open("1", 0))
-                .or_else(|_| BitPerfectDevice::open("0", 0))
-                .or_else(|_| BitPerfectDevice::open_raw("default"))
-        } else {
-            BitPerfectDevice::open(&self.card, self.device_index)
-        }
+    pub fn add_track(&mut self, track: Track) {
+        self.queue.push(&track.path);
+        self.playlist.push(track);
+    }
+
+    pub fn playlist_len(&self) -> usize {
+        self.playlist.len()
     }
 
     pub fn play(&mut self) -> Result<(), GaplessError> {
-        // Handle commands first
+        // Changing DAC leaves the queue alone: the same track carries on. Opening
+        // the new device can take a while, so the GUI is not kept waiting on the lock.
+        let switch = {
+            let mut state = self.player_state.lock().unwrap();
+            let switch = state.command.take_if(|c| matches!(c, crate::gui::PlayerCommand::SwitchOutput(_)));
+            // From what the old DAC played last, or where an unplug cut it off
+            if switch.is_some() && state.current_track.is_some() && self.resume_at.is_none() {
+                self.resume_at = Some(state.position_secs);
+            }
+            switch
+        };
+        if let Some(crate::gui::PlayerCommand::SwitchOutput(output)) = switch {
+            let result = self.player.select_output(&output);
+            let mut state = self.player_state.lock().unwrap();
+            match result {
+                Ok(()) => {
+                    state.output = output;
+                    state.device_lost = false;
+                    state.error_message = None;
+                }
+                Err(e) => state.error_message = Some(format!("Cannot switch to {}: {}", output, e)),
+            }
+            self.idle_since = Some(Instant::now());
+        }
+
+        // Then the other commands
         {
             let mut state = self.player_state.lock().unwrap();
+            self.queue.set_repeat(state.repeat);
+            self.queue.set_shuffle(state.shuffle);
+
             if let Some(cmd) = state.command.take() {
-                match cmd {
-                    crate::gui::PlayerCommand::Next => {
-                        if !self.playlist.is_empty() && self.current_track < self.playlist.len() - 1 {
-                            self.current_track += 1;
-                        } else if !self.playlist.is_empty() {
-                            // Loop to beginning if at end
-                            self.current_track = 0;
-                        }
-                    }
-                    crate::gui::PlayerCommand::Prev => {
-                        if self.current_track > 0 {
-                            self.current_track -= 1;
-                        }
-                    }
-                    crate::gui::PlayerCommand::PlayIndex(idx) => {
-                        if idx < self.playlist.len() {
-                            self.current_track = idx;
-                        }
-                    }
-                }
-                
-                if !self.playlist.is_empty() {
+                // Whatever was cut off by an unplug is not coming back
+                self.resume_at = None;
+                state.device_lost = false;
+                let stop = cmd == crate::gui::PlayerCommand::Stop;
+                let target = match cmd {
+                    crate::gui::PlayerCommand::Next => self.queue.next(),
+                    crate::gui::PlayerCommand::Prev => self.queue.prev(),
+                    crate::gui::PlayerCommand::PlayIndex(idx) => self.queue.jump_to(idx),
+                    crate::gui::PlayerCommand::Stop => None,
+                    crate::gui::PlayerCommand::SwitchOutput(_) => unreachable!("taken above"),
+                };
+
+                state.current_index = target;
+                if let Some(idx) = target {
+                    self.current_track = idx;
                     self.is_playing = true;
                     state.is_playing = true;
                 } else {
+                    // Stop, empty playlist, or Next past the end with repeat off
+                    self.queue.reset();
+                    self.current_track = self.queue.current().unwrap_or(0);
                     self.is_playing = false;
                     state.is_playing = false;
                 }
+
+                if stop {
+                    // play_file already dropped what was queued; hand the DAC back
+                    state.current_track = None;
+                    state.position_secs = 0.0;
+                    self.player.release();
+                    self.idle_since = None;
+                }
             }
         }
 
-        let (path, should_play) = {
+        let (track, should_play) = {
             let state = self.player_state.lock().unwrap();
             if !state.is_playing || self.playlist.is_empty() || self.current_track >= self.playlist.len() {
                 (None, false)
@@ -103,66 +172,118 @@ impl GaplessEngine {
                 let mut state = self.player_state.lock().unwrap();
                 state.is_playing = false;
                 state.current_track = None;
+                state.current_index = None;
+            }
+            if let Some(after) = self.idle_release
+                && self.idle_since.is_some_and(|since| since.elapsed() >= after)
+            {
+                self.player.release();
+                self.idle_since = None;
             }
             std::thread::sleep(std::time::Duration::from_millis(50));
             return Ok(());
         }
 
-        let path = path.unwrap();
-        
-        // Open hardware for this track session
-        let device = self.open_device()?;
-        let mut player = BitPerfectPlayer::new(device);
+        let track = track.unwrap();
+        let start_secs = self.resume_at.take().unwrap_or(0.0);
 
         {
             let mut state = self.player_state.lock().unwrap();
-            state.current_track = Some(crate::gui::TrackInfo {
-                filename: path.file_name().unwrap().to_string_lossy().to_string(),
-                sample_rate: 0,
-                bit_depth: 0,
-                title: None,
-                artist: None,
-            });
+            state.current_index = Some(self.current_track);
+            state.current_track = Some(track_info(&track));
             state.error_message = None; // Clear any old errors
+            state.position_secs = start_secs;
+            state.position_at = None;
         }
         
-        player.play_file(&path, self.player_state.clone())?;
+        self.idle_since = None;
+        self.player.set_next_track(self.queue.peek_next().map(|idx| self.playlist[idx].path.as_path()));
+        // The next CUE track of the same file is joined on without a break
+        let (queue, playlist, current_track) = (&mut self.queue, &self.playlist, &mut self.current_track);
+        let result = self.player.play_track(&track, start_secs, self.player_state.clone(), |boundary| {
+            let idx = queue.peek_next()?;
+            let next = &playlist[idx];
+            let span = next.span.filter(|span| playlist[*current_track].continues_into(next) && span.start == boundary)?;
+            queue.advance();
+            *current_track = idx;
+            Some(Following { span, index: idx, info: track_info(next) })
+        });
+        self.idle_since = Some(Instant::now());
+        match result {
+            // Pause on the spot rather than retry against a device that is not there
+            Err(PlayerError::Sink(e)) if e.is_disconnected() => {
+                let mut state = self.player_state.lock().unwrap();
+                self.resume_at = Some(state.position_secs);
+                state.is_playing = false;
+                state.device_lost = true;
+                drop(state);
+                self.player.release();
+                return Err(PlayerError::Sink(e).into());
+            }
+            // The device is closed already; Play picks the track up where it paused
+            Ok(Played::Released(position)) => {
+                self.resume_at = Some(position);
+                self.idle_since = None;
+            }
+            other => {
+                other?;
+            }
+        }
         
-        // After track ends (or was stopped)
+        self.track_finished();
+        Ok(())
+    }
+
+    /// After a track ends (or was stopped), pick what plays next and publish it
+    fn track_finished(&mut self) {
         let mut state = self.player_state.lock().unwrap();
         if state.is_playing && state.command.is_none() {
-            self.current_track += 1;
-            if self.current_track >= self.playlist.len() {
-                state.is_playing = false;
-                state.current_track = None;
+            match self.queue.advance() {
+                Some(idx) => {
+                    self.current_track = idx;
+                    state.current_index = Some(idx);
+                }
+                None => {
+                    // Back to the start of the play order, ready for the next Play
+                    self.queue.reset();
+                    self.current_track = self.queue.current().unwrap_or(0);
+                    state.is_playing = false;
+                    state.current_track = None;
+                    state.current_index = None;
+                }
             }
         }
+    }
+}
 
-        Ok(())
+fn track_info(track: &Track) -> TrackInfo {
+    TrackInfo {
+        path: track.path.clone(),
+        filename: track.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+        title: track.title.clone(),
+        artist: track.performer.clone(),
+        track_number: track.number,
+        ..Default::default()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::gui::PlayerState;
+    use crate::gui::{PlayerState, RepeatMode};
+    use crate::output::StreamFormat;
+    use crate::output::memory::MemorySink;
+    use crate::player::pcm::OutputEncoding;
+    use crate::player::pcm::tests::{wav, TempPath};
     use std::sync::{Arc, Mutex};
 
     fn setup_engine() -> (GaplessEngine, Arc<Mutex<PlayerState>>) {
-        let state = Arc::new(Mutex::new(PlayerState {
-            current_track: None,
-            is_playing: false,
-            position_secs: 0.0,
-            duration_secs: 0.0,
-            volume_db: 0.0,
-            playlist: Vec::new(),
-            command: None,
-            error_message: None,
-            album_art: None,
-        }));
-        // Note: BitPerfectPlayer needs a mock or we skip hardware-dependent tests
-        // For logic tests, we focus on the Engine's state management
-        let engine = GaplessEngine::new(state.clone(), "default".into(), 0);
+        setup_engine_with(MemorySink::default())
+    }
+
+    fn setup_engine_with(sink: MemorySink) -> (GaplessEngine, Arc<Mutex<PlayerState>>) {
+        let state = Arc::new(Mutex::new(PlayerState { is_playing: false, ..PlayerState::headless() }));
+        let engine = GaplessEngine::new(state.clone(), Box::new(sink));
         (engine, state)
     }
 
@@ -201,4 +322,333 @@ mod tests {
         let _ = engine.play(); 
         assert_eq!(engine.current_track, 0);
     }
+
+    #[test]
+    fn test_next_respects_repeat_mode() {
+        let (mut engine, state) = setup_engine();
+        engine.add_to_playlist(Path::new("test1.flac"));
+        engine.add_to_playlist(Path::new("test2.flac"));
+
+        for _ in 0..2 {
+            state.lock().unwrap().command = Some(crate::gui::PlayerCommand::Next);
+            let _ = engine.play();
+        }
+        // Repeat off: Next past the last track stops and rewinds
+        assert_eq!(engine.current_track, 0);
+        assert!(!state.lock().unwrap().is_playing);
+
+        {
+            let mut s = state.lock().unwrap();
+            s.repeat = RepeatMode::All;
+            s.command = Some(crate::gui::PlayerCommand::PlayIndex(1));
+        }
+        let _ = engine.play();
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::Next);
+        let _ = engine.play();
+        assert_eq!(engine.current_track, 0);
+    }
+
+    #[test]
+    fn test_current_index_follows_transitions() {
+        let (mut engine, state) = setup_engine();
+        for name in ["test1.flac", "test2.flac", "test3.flac"] {
+            engine.add_to_playlist(Path::new(name));
+        }
+
+        // Explicit jump
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(1));
+        let _ = engine.play();
+        assert_eq!(state.lock().unwrap().current_index, Some(1));
+
+        // Natural end of track 1 moves on to track 2
+        state.lock().unwrap().is_playing = true;
+        engine.track_finished();
+        assert_eq!(state.lock().unwrap().current_index, Some(2));
+
+        // Prev goes back to what was actually played
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::Prev);
+        let _ = engine.play();
+        assert_eq!(state.lock().unwrap().current_index, Some(1));
+
+        // Running off the end clears the highlight
+        state.lock().unwrap().is_playing = true;
+        engine.track_finished();
+        engine.track_finished();
+        let s = state.lock().unwrap();
+        assert_eq!(s.current_index, None);
+        assert!(!s.is_playing);
+    }
+
+    fn fixture(name: &str, data: Vec<u8>) -> TempPath {
+        TempPath::file(&format!("gapless-{}.wav", name), data)
+    }
+
+    #[test]
+    fn test_plays_through_sink_across_format_change() {
+        let sink = MemorySink::default();
+        let (mut engine, state) = setup_engine_with(sink.clone());
+        let cd = fixture("cd", wav(false, 16, None, 2, 44100, &[0x01, 0x00, 0xff, 0xff, 0x34, 0x12, 0x00, 0x80]));
+        let hires = fixture("hires", wav(false, 24, None, 1, 96000, &[0x56, 0x34, 0x12]));
+        engine.add_to_playlist(&cd);
+        engine.add_to_playlist(&hires);
+
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        engine.play().unwrap();
+        assert_eq!(state.lock().unwrap().current_index, Some(1));
+        engine.play().unwrap();
+
+        let capture = sink.capture();
+        assert_eq!(capture.streams, [
+            StreamFormat { sample_rate: 44100, encoding: OutputEncoding::S16LE, channels: 2 },
+            StreamFormat { sample_rate: 96000, encoding: OutputEncoding::S32LE, channels: 1 },
+        ]);
+        assert_eq!(capture.data, [0x01, 0x00, 0xff, 0xff, 0x34, 0x12, 0x00, 0x80, 0x00, 0x56, 0x34, 0x12]);
+        assert_eq!(capture.drains, 2);
+        let s = state.lock().unwrap();
+        assert!(!s.is_playing);
+        assert_eq!(s.current_index, None);
+        assert!(s.bit_perfect);
+        // Drained to the last frame of the 96 kHz track
+        assert_eq!(s.position_secs, 1.0 / 96000.0);
+        drop(s);
+    }
+
+    #[test]
+    fn test_read_ahead_plays_from_memory() {
+        let sink = MemorySink::default();
+        let (mut engine, state) = setup_engine_with(sink.clone());
+        engine.set_read_ahead(true);
+        let first = fixture("ahead-1", wav(false, 16, None, 1, 44100, &[0x01, 0x00, 0x02, 0x00]));
+        let second = fixture("ahead-2", wav(false, 16, None, 1, 44100, &[0x03, 0x00]));
+        engine.add_to_playlist(&first);
+        engine.add_to_playlist(&second);
+
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        engine.play().unwrap();
+        // Gone from the disk once it is loaded: only the copy in memory can play
+        engine.player.wait_read_ahead();
+        std::fs::remove_file(&second).unwrap();
+        engine.play().unwrap();
+        assert_eq!(sink.capture().data, [0x01, 0x00, 0x02, 0x00, 0x03, 0x00]);
+    }
+
+    #[test]
+    fn test_lyrics_follow_the_track() {
+        let sink = MemorySink::default();
+        let (mut engine, state) = setup_engine_with(sink.clone());
+        let sung = fixture("sung", wav(false, 16, None, 1, 44100, &[0x01, 0x00]));
+        let instrumental = fixture("instrumental", wav(false, 16, None, 1, 44100, &[0x02, 0x00]));
+        let _lrc = TempPath::file("gapless-sung.lrc", "[00:00.00]La");
+        engine.add_to_playlist(&sung);
+        engine.add_to_playlist(&instrumental);
+
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        engine.play().unwrap();
+        {
+            let s = state.lock().unwrap();
+            assert_eq!(s.lyrics.as_ref().map(|l| l.lines[0].text.as_str()), Some("La"));
+            // Drained: the clock stops where the track ended
+            assert_eq!(s.position_at, None);
+        }
+        engine.play().unwrap();
+        assert!(state.lock().unwrap().lyrics.is_none());
+    }
+
+    #[test]
+    fn test_cue_tracks_join_without_a_gap() {
+        use crate::player::track::Span;
+
+        // 16-bit mono, every sample distinct; boundaries fall inside packets
+        let samples: Vec<u8> = (0..20000u16).flat_map(|i| i.to_le_bytes()).collect();
+        let path = fixture("cue", wav(false, 16, None, 1, 8000, &samples));
+        let track = |number, start, end| Track {
+            path: path.to_path_buf(),
+            span: Some(Span { start, end }),
+            number: Some(number),
+            title: Some(format!("Part {}", number)),
+            performer: None,
+        };
+
+        let sink = MemorySink::default();
+        let (mut engine, state) = setup_engine_with(sink.clone());
+        engine.add_track(track(1, 0, Some(5000)));
+        engine.add_track(track(2, 5000, Some(12345)));
+        engine.add_track(track(3, 12345, None));
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        engine.play().unwrap();
+
+        // One pass through the file: every sample once, one stream, one drain
+        let capture = sink.capture();
+        assert_eq!(capture.data, samples);
+        assert_eq!((capture.streams.len(), capture.drains), (1, 1));
+        drop(capture);
+        let s = state.lock().unwrap();
+        assert_eq!(s.current_index, None);
+        assert!(!s.is_playing);
+        drop(s);
+
+        // Starting on the second track begins on its first sample and ends on its last
+        let sink = MemorySink::default();
+        let (mut engine, state) = setup_engine_with(sink.clone());
+        engine.add_track(track(2, 5000, Some(12345)));
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        engine.play().unwrap();
+        assert_eq!(sink.capture().data, samples[5000 * 2..12345 * 2]);
+        assert_eq!(state.lock().unwrap().position_secs, 7345.0 / 8000.0);
+    }
+
+    #[test]
+    fn test_cue_tracks_get_their_part_of_the_lyrics() {
+        use crate::player::track::Span;
+
+        // Two half-second tracks in one file, with lyrics for the whole of it
+        let samples: Vec<u8> = (0..8000u16).flat_map(|i| i.to_le_bytes()).collect();
+        let path = fixture("cue-lyrics", wav(false, 16, None, 1, 8000, &samples));
+        let _lrc = TempPath::file("gapless-cue-lyrics.lrc", "[00:00.25]One\n[00:00.75]Two");
+        let track = |number, start, end| Track {
+            path: path.to_path_buf(),
+            span: Some(Span { start, end }),
+            number: Some(number),
+            title: None,
+            performer: None,
+        };
+        let lyrics = |state: &Arc<Mutex<PlayerState>>| state.lock().unwrap().lyrics.as_ref().map(|l| l.lines.clone());
+        let line = |time, text: &str| vec![crate::player::lyrics::Line { time: Some(time), text: text.into() }];
+
+        // Started on the second track
+        let (mut engine, state) = setup_engine();
+        engine.add_track(track(2, 4000, None));
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        engine.play().unwrap();
+        assert_eq!(lyrics(&state), Some(line(0.25, "Two")));
+
+        // Run on into it from the first
+        let (mut engine, state) = setup_engine();
+        engine.add_track(track(1, 0, Some(4000)));
+        engine.add_track(track(2, 4000, None));
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        engine.play().unwrap();
+        assert_eq!(state.lock().unwrap().current_index, None);
+        assert_eq!(lyrics(&state), Some(line(0.25, "Two")));
+    }
+
+    #[test]
+    fn test_falls_back_to_packed_24bit() {
+        let sink = MemorySink::supporting(&[OutputEncoding::S16LE, OutputEncoding::S24_3LE]);
+        let (mut engine, state) = setup_engine_with(sink.clone());
+        let data = [0x56, 0x34, 0x12, 0xfe, 0xff, 0xff];
+        let path = fixture("packed", wav(false, 24, None, 2, 48000, &data));
+        engine.add_to_playlist(&path);
+
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        engine.play().unwrap();
+        assert_eq!(sink.capture().streams[0].encoding, OutputEncoding::S24_3LE);
+        assert_eq!(sink.capture().data, data);
+    }
+
+    #[test]
+    fn test_stop_rewinds_and_releases_device() {
+        let sink = MemorySink::default();
+        let (mut engine, state) = setup_engine_with(sink.clone());
+        let a = fixture("stop-a", wav(false, 16, None, 1, 44100, &[0x01, 0x00]));
+        let b = fixture("stop-b", wav(false, 16, None, 1, 44100, &[0x02, 0x00]));
+        engine.add_to_playlist(&a);
+        engine.add_to_playlist(&b);
+
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        engine.play().unwrap();
+        assert_eq!(state.lock().unwrap().current_index, Some(1));
+
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::Stop);
+        engine.play().unwrap();
+        {
+            let s = state.lock().unwrap();
+            assert!(!s.is_playing);
+            assert_eq!(s.current_index, None);
+            assert_eq!(s.position_secs, 0.0);
+        }
+        assert_eq!(engine.current_track, 0);
+        assert_eq!(sink.capture().releases, 1);
+    }
+
+    #[test]
+    fn test_releases_device_when_idle() {
+        let sink = MemorySink::default();
+        let (mut engine, state) = setup_engine_with(sink.clone());
+        engine.set_idle_release(Some(Duration::ZERO));
+        let path = fixture("idle", wav(false, 16, None, 1, 44100, &[0x01, 0x00]));
+        engine.add_to_playlist(&path);
+
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        engine.play().unwrap();
+        assert_eq!(sink.capture().releases, 0);
+        engine.play().unwrap();
+        engine.play().unwrap();
+        // Released once, not on every idle tick
+        assert_eq!(sink.capture().releases, 1);
+    }
+
+    #[test]
+    fn test_unplug_pauses_and_resumes_in_place() {
+        let sink = MemorySink::default();
+        let (mut engine, state) = setup_engine_with(sink.clone());
+        // Two seconds, so the reader hands it over in more than one packet
+        let pcm: Vec<u8> = (0..16000u16).flat_map(|i| i.to_le_bytes()).collect();
+        let path = fixture("unplug", wav(false, 16, None, 1, 8000, &pcm));
+        engine.add_to_playlist(&path);
+
+        sink.capture().writes_left = Some(1);
+        state.lock().unwrap().command = Some(crate::gui::PlayerCommand::PlayIndex(0));
+        let err = engine.play().unwrap_err();
+        assert!(err.is_device_gone());
+        let heard = sink.capture().data.len();
+        {
+            let s = state.lock().unwrap();
+            assert!(!s.is_playing);
+            assert!(s.device_lost);
+            assert_eq!(s.position_secs, (heard / 2) as f64 / 8000.0);
+        }
+        assert_eq!(sink.capture().releases, 1);
+
+        // Plugged back in; the hotplug monitor restarts playback
+        sink.capture().writes_left = None;
+        {
+            let mut s = state.lock().unwrap();
+            s.device_lost = false;
+            s.is_playing = true;
+        }
+        engine.play().unwrap();
+        assert_eq!(sink.capture().data, pcm);
+    }
+
+    #[test]
+    fn test_switch_output_resumes_at_same_position() {
+        let sink = MemorySink::default();
+        let (mut engine, state) = setup_engine_with(sink.clone());
+        let pcm: Vec<u8> = (0..16000u16).flat_map(|i| i.to_le_bytes()).collect();
+        let path = fixture("switch", wav(false, 16, None, 1, 8000, &pcm));
+        engine.add_to_playlist(&path);
+
+        // One second in when the new DAC is picked
+        let dac = crate::alsa::hotplug::Target { card: "DAC".into(), device: 0 };
+        {
+            let mut s = state.lock().unwrap();
+            s.is_playing = true;
+            s.current_track = Some(crate::gui::TrackInfo {
+                path: path.to_path_buf(),
+                filename: "switch.wav".into(),
+                sample_rate: 8000,
+                bit_depth: 16,
+                ..Default::default()
+            });
+            s.position_secs = 1.0;
+            s.command = Some(crate::gui::PlayerCommand::SwitchOutput(dac.clone()));
+        }
+        engine.play().unwrap();
+
+        assert_eq!(sink.capture().selected, std::slice::from_ref(&dac));
+        assert_eq!(state.lock().unwrap().output, dac);
+        assert_eq!(engine.current_track, 0);
+        assert_eq!(sink.capture().data, pcm[16000..]);
+    }
 }
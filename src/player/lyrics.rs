@@ -0,0 +1,346 @@
+//! Lyrics: a `.lrc` next to the file, ID3 SYLT/USLT frames or a LYRICS tag,
+//! with LRC timestamps for following along
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use symphonia::core::meta::{StandardTagKey, Tag};
+
+/// ID3 tags larger than this are cover art, not lyrics; only the first part is read
+const MAX_ID3: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    /// Seconds from the start of the track; `None` for unsynchronised lyrics
+    pub time: Option<f64>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Lyrics {
+    /// In time order when synced
+    pub lines: Vec<Line>,
+}
+
+impl Lyrics {
+    /// LRC when any line carries a timestamp, plain text otherwise
+    pub fn parse(text: &str) -> Self {
+        let mut offset = 0.0;
+        let mut timed = Vec::new();
+        let mut plain = Vec::new();
+        'lines: for raw in text.trim_start_matches('\u{feff}').lines() {
+            let mut rest = raw.trim();
+            let mut times = Vec::new();
+            while let Some(tag) = rest.strip_prefix('[')
+                && let Some(end) = tag.find(']')
+            {
+                let (body, after) = (&tag[..end], &tag[end + 1..]);
+                if let Some(t) = parse_stamp(body) {
+                    times.push(t);
+                } else if times.is_empty()
+                    && let Some((key, value)) = body.split_once(':')
+                {
+                    // [ar:...], [ti:...] and other header tags are not lyrics;
+                    // a positive [offset:ms] shows every line earlier
+                    if key.trim().eq_ignore_ascii_case("offset") {
+                        offset = value.trim().parse::<f64>().map_or(0.0, |ms| ms / 1000.0);
+                    }
+                    continue 'lines;
+                } else {
+                    // "[Chorus]" and the like are part of the text
+                    break;
+                }
+                rest = after;
+            }
+            if times.is_empty() {
+                plain.push(raw.trim_end().to_string());
+                continue;
+            }
+            let text = strip_word_stamps(rest.trim());
+            timed.extend(times.into_iter().map(|t| (t, text.clone())));
+        }
+
+        if timed.is_empty() {
+            while plain.last().is_some_and(|l| l.is_empty()) {
+                plain.pop();
+            }
+            return Lyrics { lines: plain.into_iter().map(|text| Line { time: None, text }).collect() };
+        }
+        timed.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Lyrics {
+            lines: timed.into_iter().map(|(t, text)| Line { time: Some((t - offset).max(0.0)), text }).collect(),
+        }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.lines.first().is_some_and(|l| l.time.is_some())
+    }
+
+    /// The part of a whole album's lyrics from `start` to `end` seconds into
+    /// the file, timed from `start`: what one of its CUE tracks sings.
+    /// Unsynchronised lyrics cannot be cut up and are kept whole.
+    pub fn span(&self, start: f64, end: Option<f64>) -> Lyrics {
+        if !self.is_synced() {
+            return self.clone();
+        }
+        let lines = self.lines.iter()
+            .filter(|l| l.time.is_some_and(|t| t >= start && end.is_none_or(|end| t < end)))
+            .map(|l| Line { time: l.time.map(|t| t - start), text: l.text.clone() })
+            .collect();
+        Lyrics { lines }
+    }
+
+    /// The line being sung at `secs`: the last one that has started
+    pub fn current_line(&self, secs: f64) -> Option<usize> {
+        let started = self.lines.partition_point(|l| l.time.is_some_and(|t| t <= secs));
+        started.checked_sub(1).filter(|_| self.is_synced())
+    }
+}
+
+/// Lyrics for the file at `path`. Synced ones win; among those a sidecar
+/// `.lrc` comes first, as it is the easiest to fix.
+pub fn find(path: &Path, sources: &[&[Tag]]) -> Option<Lyrics> {
+    let sidecar = fs::read(path.with_extension("lrc")).ok()
+        .map(|bytes| Lyrics::parse(&String::from_utf8_lossy(&bytes)));
+    let tagged = sources.iter().flat_map(|tags| tags.iter())
+        .filter(|tag| tag.std_key == Some(StandardTagKey::Lyrics))
+        .map(|tag| Lyrics::parse(&tag.value.to_string()));
+    let found: Vec<Lyrics> = sidecar.into_iter().chain(read_sylt(path)).chain(tagged)
+        .filter(|l| !l.lines.is_empty())
+        .collect();
+    let synced = found.iter().position(Lyrics::is_synced);
+    found.into_iter().nth(synced.unwrap_or(0))
+}
+
+/// `mm:ss.xx`, `mm:ss:xx` or `mm:ss` in seconds
+fn parse_stamp(stamp: &str) -> Option<f64> {
+    let (min, sec) = stamp.split_once(':')?;
+    let min: u32 = min.trim().parse().ok()?;
+    let sec: f64 = match sec.split_once(':') {
+        Some((s, frac)) => format!("{}.{}", s, frac).parse().ok()?,
+        None => sec.parse().ok()?,
+    };
+    (0.0..60.0).contains(&sec).then_some(min as f64 * 60.0 + sec)
+}
+
+/// Enhanced LRC times each word as `<mm:ss.xx>`; only whole lines are followed
+fn strip_word_stamps(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) if parse_stamp(&rest[start + 1..start + end]).is_some() => {
+                out.push_str(&rest[..start]);
+                rest = &rest[start + end + 1..];
+            }
+            _ => {
+                out.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// The first SYLT frame with millisecond timestamps in an ID3v2.3/2.4 tag at the
+/// start of the file. The decoder's own ID3 reader skips these frames.
+fn read_sylt(path: &Path) -> Option<Lyrics> {
+    let mut file = File::open(path).ok()?;
+    let mut header = [0u8; 10];
+    file.read_exact(&mut header).ok()?;
+    let (version, flags) = (header[3], header[5]);
+    if &header[..3] != b"ID3" || !(3..=4).contains(&version) {
+        return None;
+    }
+    let size = (syncsafe(&header[6..10]) as usize).min(MAX_ID3);
+    let mut tag = vec![0u8; size];
+    file.read_exact(&mut tag).ok()?;
+    // Unsynchronisation put a zero after every 0xFF
+    if flags & 0x80 != 0 {
+        let mut kept = Vec::with_capacity(tag.len());
+        for (i, &b) in tag.iter().enumerate() {
+            if !(b == 0 && i > 0 && tag[i - 1] == 0xff) {
+                kept.push(b);
+            }
+        }
+        tag = kept;
+    }
+    let mut pos = 0;
+    if flags & 0x40 != 0 {
+        let ext = tag.get(..4)?;
+        pos = if version == 4 { syncsafe(ext) as usize } else { u32::from_be_bytes(ext.try_into().ok()?) as usize + 4 };
+    }
+
+    while let Some(frame) = tag.get(pos..pos + 10) {
+        let id = &frame[..4];
+        if id[0] == 0 {
+            break;
+        }
+        let len = if version == 4 { syncsafe(&frame[4..8]) } else { u32::from_be_bytes(frame[4..8].try_into().ok()?) } as usize;
+        let body = tag.get(pos + 10..pos + 10 + len)?;
+        if id == b"SYLT"
+            && let Some(lyrics) = parse_sylt(body)
+        {
+            return Some(lyrics);
+        }
+        pos += 10 + len;
+    }
+    None
+}
+
+fn syncsafe(b: &[u8]) -> u32 {
+    b.iter().fold(0, |acc, &b| (acc << 7) | (b & 0x7f) as u32)
+}
+
+/// encoding, language, timestamp format, content type, descriptor, then
+/// (text, u32 time) pairs
+fn parse_sylt(body: &[u8]) -> Option<Lyrics> {
+    let (&encoding, rest) = body.split_first()?;
+    let &format = rest.get(3)?;
+    // 1 counts MPEG frames, which would need the stream to convert
+    if format != 2 {
+        return None;
+    }
+    let (_, mut rest) = split_text(encoding, rest.get(5..)?)?;
+    let mut lines = Vec::new();
+    while let Some((text, after)) = split_text(encoding, rest) {
+        let Some(stamp) = after.get(..4) else { break };
+        let ms = u32::from_be_bytes([stamp[0], stamp[1], stamp[2], stamp[3]]);
+        // A leading newline marks a new line of the song in many taggers
+        let text = text.trim_start_matches(['\n', '\r']).trim_end().to_string();
+        lines.push(Line { time: Some(ms as f64 / 1000.0), text });
+        rest = &after[4..];
+    }
+    lines.sort_by(|a, b| a.time.unwrap_or_default().total_cmp(&b.time.unwrap_or_default()));
+    (!lines.is_empty()).then_some(Lyrics { lines })
+}
+
+/// A terminated string in ID3 text encoding `encoding`, and what follows it
+fn split_text(encoding: u8, data: &[u8]) -> Option<(String, &[u8])> {
+    if data.is_empty() {
+        return None;
+    }
+    match encoding {
+        // UTF-16 with BOM, or big-endian without
+        1 | 2 => {
+            let end = data.chunks_exact(2).position(|c| c == [0, 0]).map_or(data.len(), |i| i * 2);
+            let units = &data[..end];
+            let (little, units) = match units {
+                [0xff, 0xfe, rest @ ..] => (true, rest),
+                [0xfe, 0xff, rest @ ..] => (false, rest),
+                _ => (false, units),
+            };
+            let utf16: Vec<u16> = units.chunks_exact(2)
+                .map(|c| if little { u16::from_le_bytes([c[0], c[1]]) } else { u16::from_be_bytes([c[0], c[1]]) })
+                .collect();
+            Some((String::from_utf16_lossy(&utf16), data.get(end + 2..).unwrap_or(&[])))
+        }
+        _ => {
+            let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+            let text = match encoding {
+                3 => String::from_utf8_lossy(&data[..end]).into_owned(),
+                _ => data[..end].iter().map(|&b| b as char).collect(),
+            };
+            Some((text, data.get(end + 1..).unwrap_or(&[])))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::meta::Value;
+    use crate::player::pcm::tests::TempPath;
+
+    #[test]
+    fn test_parse_lrc() {
+        let lyrics = Lyrics::parse(concat!(
+            "\u{feff}[ar:Miles Davis]\n",
+            "[ti:So What]\n",
+            "[offset:+500]\n",
+            "[00:12.50]First <00:12.50>line <00:13.10>here\n",
+            "[00:05.00][01:05.00]Chorus\n",
+            "[00:20:25]Third\n",
+            "not timed\n",
+        ));
+        let times: Vec<_> = lyrics.lines.iter().map(|l| l.time.unwrap()).collect();
+        let texts: Vec<_> = lyrics.lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(times, [4.5, 12.0, 19.75, 64.5]);
+        assert_eq!(texts, ["Chorus", "First line here", "Third", "Chorus"]);
+        assert!(lyrics.is_synced());
+        assert_eq!(lyrics.current_line(1.0), None);
+        assert_eq!(lyrics.current_line(12.0), Some(1));
+        assert_eq!(lyrics.current_line(600.0), Some(3));
+    }
+
+    #[test]
+    fn test_span_of_album_lyrics() {
+        let album = Lyrics::parse("[00:10.00]One\n[03:00.00]Two\n[03:30.00]Three\n[06:00.00]Four");
+        let second = album.span(170.0, Some(360.0));
+        assert_eq!(second.lines, [
+            Line { time: Some(10.0), text: "Two".into() },
+            Line { time: Some(40.0), text: "Three".into() },
+        ]);
+        assert_eq!(album.span(360.0, None).lines, [Line { time: Some(0.0), text: "Four".into() }]);
+        let plain = Lyrics::parse("One\nTwo");
+        assert_eq!(plain.span(170.0, Some(360.0)), plain);
+    }
+
+    #[test]
+    fn test_synced_lyrics_win() {
+        let dir = TempPath::dir("lyrics");
+        let path = dir.join("01.flac");
+        let plain = [Tag::new(Some(StandardTagKey::Lyrics), "LYRICS", Value::from("[Chorus]\nSo what\n\n"))];
+
+        let lyrics = find(&path, &[&plain]).unwrap();
+        assert!(!lyrics.is_synced());
+        assert_eq!(lyrics.lines.iter().map(|l| l.text.as_str()).collect::<Vec<_>>(), ["[Chorus]", "So what"]);
+        assert_eq!(lyrics.current_line(10.0), None);
+
+        fs::write(path.with_extension("lrc"), "[00:01.00]So what").unwrap();
+        let lyrics = find(&path, &[&plain]).unwrap();
+        assert_eq!(lyrics.lines, [Line { time: Some(1.0), text: "So what".into() }]);
+    }
+
+    #[test]
+    fn test_sylt_frame() {
+        // ID3v2.3, UTF-16 with BOM, millisecond stamps
+        let utf16 = |s: &str| -> Vec<u8> {
+            let mut out = vec![0xff, 0xfe];
+            out.extend(s.encode_utf16().flat_map(u16::to_le_bytes));
+            out.extend([0, 0]);
+            out
+        };
+        let mut body = vec![1];
+        body.extend(b"eng");
+        body.extend([2, 1]);
+        body.extend(utf16(""));
+        body.extend(utf16("\nSecond"));
+        body.extend(2500u32.to_be_bytes());
+        body.extend(utf16("First"));
+        body.extend(1000u32.to_be_bytes());
+
+        let mut frames = b"TIT2".to_vec();
+        frames.extend(3u32.to_be_bytes());
+        frames.extend([0, 0, 3, b'S', b'o']);
+        frames.extend(b"SYLT");
+        frames.extend((body.len() as u32).to_be_bytes());
+        frames.extend([0, 0]);
+        frames.extend(&body);
+        frames.extend([0; 16]);
+
+        let mut file = b"ID3\x03\x00\x00".to_vec();
+        let size = frames.len() as u32;
+        file.extend([(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]);
+        file.extend(&frames);
+        let path = TempPath::file("sylt.mp3", file);
+
+        let lyrics = read_sylt(&path).unwrap();
+        assert_eq!(lyrics.lines, [
+            Line { time: Some(1.0), text: "First".into() },
+            Line { time: Some(2.5), text: "Second".into() },
+        ]);
+    }
+}
@@ -1,2 +1,15 @@
+#[cfg(test)]
+mod alloc_count;
+pub mod art;
 pub mod bitperfect;
-pub mod gapless;
\ No newline at end of file
+pub mod cue;
+pub mod formats;
+pub mod gapless;
+pub mod lyrics;
+pub mod pcm;
+pub mod queue;
+pub mod readahead;
+pub mod replaygain;
+pub mod tags;
+pub mod track;
+pub mod verify;
\ No newline at end of file
@@ -0,0 +1,465 @@
+use alsa::pcm::Format;
+use symphonia::core::audio::{AudioBufferRef, SampleBuffer, SignalSpec};
+use symphonia::core::codecs::CodecParameters;
+use symphonia::core::conv::ConvertibleSample;
+use symphonia::core::sample::SampleFormat;
+
+use crate::player::replaygain::Dither;
+
+/// What the decoder hands us, as far as picking an output format goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceFormat {
+    /// Integer PCM with this many significant bits
+    Int(u32),
+    /// Floating point: float WAV/AIFF, and lossy codecs (MP3, AAC, Vorbis) which
+    /// have no bit depth of their own and decode to float
+    Float,
+}
+
+impl SourceFormat {
+    pub fn from_codec_params(params: &CodecParameters) -> Self {
+        match (params.sample_format, params.bits_per_sample) {
+            (Some(SampleFormat::F32 | SampleFormat::F64), _) => SourceFormat::Float,
+            (_, Some(bits)) => SourceFormat::Int(bits),
+            (_, None) => SourceFormat::Float,
+        }
+    }
+
+    /// Bit depth for display
+    pub fn bits(self) -> u16 {
+        match self {
+            SourceFormat::Int(bits) => bits as u16,
+            SourceFormat::Float => 32,
+        }
+    }
+
+    /// Output formats that carry this source without loss, best first.
+    /// Narrow integer sources are padded with zero bits into wider words.
+    fn candidates(self) -> &'static [OutputEncoding] {
+        use OutputEncoding::*;
+        match self {
+            SourceFormat::Int(0..=8) => &[U8, S16LE, S32LE, S24LE, S24_3LE],
+            SourceFormat::Int(9..=16) => &[S16LE, S32LE, S24LE, S24_3LE],
+            SourceFormat::Int(17..=24) => &[S32LE, S24LE, S24_3LE],
+            SourceFormat::Int(_) => &[S32LE],
+            // f32 has a 24-bit mantissa, so S32 (and nearly S24) holds it exactly.
+            // S16 is the documented last resort: it loses resolution and is always dithered.
+            SourceFormat::Float => &[FloatLE, S32LE, S24LE, S24_3LE, S16LE],
+        }
+    }
+
+    /// Size of one source LSB inside a `container_bits` wide integer word
+    fn lsb(self, container_bits: u32) -> f64 {
+        let bits = match self {
+            SourceFormat::Int(bits) => bits.min(container_bits),
+            SourceFormat::Float => 24.min(container_bits),
+        };
+        (1u64 << (container_bits - bits)) as f64
+    }
+}
+
+/// Sample layout written to the hardware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEncoding {
+    U8,
+    S16LE,
+    /// 24 bits in the low three bytes of a 32-bit word, sign extended
+    S24LE,
+    /// 24 bits packed into three bytes
+    S24_3LE,
+    S32LE,
+    FloatLE,
+}
+
+impl OutputEncoding {
+    pub fn alsa_format(self) -> Format {
+        match self {
+            OutputEncoding::U8 => Format::U8,
+            OutputEncoding::S16LE => Format::S16LE,
+            OutputEncoding::S24LE => Format::S24LE,
+            OutputEncoding::S24_3LE => Format::S243LE,
+            OutputEncoding::S32LE => Format::S32LE,
+            OutputEncoding::FloatLE => Format::FloatLE,
+        }
+    }
+
+    /// ALSA's name for the format
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputEncoding::U8 => "U8",
+            OutputEncoding::S16LE => "S16_LE",
+            OutputEncoding::S24LE => "S24_LE",
+            OutputEncoding::S24_3LE => "S24_3LE",
+            OutputEncoding::S32LE => "S32_LE",
+            OutputEncoding::FloatLE => "FLOAT_LE",
+        }
+    }
+
+    /// Parse an ALSA format name, case and underscores ignored ("s24_3le", "S32LE")
+    pub fn from_name(name: &str) -> Option<Self> {
+        let key = name.replace('_', "").to_ascii_uppercase();
+        [OutputEncoding::U8, OutputEncoding::S16LE, OutputEncoding::S24LE,
+            OutputEncoding::S24_3LE, OutputEncoding::S32LE, OutputEncoding::FloatLE]
+            .into_iter()
+            .find(|e| e.name().replace('_', "") == key)
+    }
+
+    /// Bytes per sample on the wire
+    pub fn width(self) -> usize {
+        match self {
+            OutputEncoding::U8 => 1,
+            OutputEncoding::S16LE => 2,
+            OutputEncoding::S24_3LE => 3,
+            OutputEncoding::S24LE | OutputEncoding::S32LE | OutputEncoding::FloatLE => 4,
+        }
+    }
+}
+
+/// Pick the best encoding for `source` among what the hardware supports
+pub fn choose_encoding(source: SourceFormat, supports: impl Fn(OutputEncoding) -> bool) -> Option<OutputEncoding> {
+    source.candidates().iter().copied().find(|e| supports(*e))
+}
+
+/// Converts decoded packets into the bytes ALSA expects for one stream.
+///
+/// The sample and output buffers are allocated up front from the codec's
+/// largest packet, so the per-packet path does not touch the heap. A codec that
+/// does not say how big its packets get has them sized by the first one.
+///
+/// At unity gain integer sources are only widened (zero padded), never
+/// altered. Any other gain goes through the dithered requantizer.
+pub struct Encoder {
+    source: SourceFormat,
+    encoding: OutputEncoding,
+    u8s: Option<SampleBuffer<u8>>,
+    i16s: Option<SampleBuffer<i16>>,
+    i32s: Option<SampleBuffer<i32>>,
+    f32s: Option<SampleBuffer<f32>>,
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new(source: SourceFormat, encoding: OutputEncoding, spec: SignalSpec, max_frames: Option<u64>) -> Self {
+        let mut encoder = Self { source, encoding, u8s: None, i16s: None, i32s: None, f32s: None, out: Vec::new() };
+        if let Some(frames) = max_frames.filter(|f| *f > 0) {
+            match encoder.layout() {
+                Layout::U8 => encoder.u8s = Some(SampleBuffer::new(frames, spec)),
+                Layout::I16 => encoder.i16s = Some(SampleBuffer::new(frames, spec)),
+                Layout::I32 => encoder.i32s = Some(SampleBuffer::new(frames, spec)),
+                Layout::F32 => encoder.f32s = Some(SampleBuffer::new(frames, spec)),
+            }
+            encoder.out.reserve(frames as usize * spec.channels.count() * encoding.width());
+        }
+        encoder
+    }
+
+    /// Sample type the decoder output is copied into before packing
+    fn layout(&self) -> Layout {
+        match self.encoding {
+            OutputEncoding::U8 => Layout::U8,
+            OutputEncoding::S16LE if self.source == SourceFormat::Float => Layout::F32,
+            OutputEncoding::S16LE => Layout::I16,
+            OutputEncoding::S24LE | OutputEncoding::S24_3LE | OutputEncoding::S32LE => Layout::I32,
+            OutputEncoding::FloatLE => Layout::F32,
+        }
+    }
+
+    /// Convert one decoded packet. The bytes stay valid until the next call.
+    pub fn encode(&mut self, decoded: AudioBufferRef, gain: f64, dither: &mut Dither) -> &[u8] {
+        let (source, encoding, layout) = (self.source, self.encoding, self.layout());
+        let out = &mut self.out;
+        out.clear();
+        out.reserve(decoded.frames() * decoded.spec().channels.count() * encoding.width());
+
+        match layout {
+            Layout::U8 => {
+                for &s in interleave(&mut self.u8s, decoded) {
+                    let s = if gain != 1.0 {
+                        (dither.apply(s as f64 - 128.0, gain, 1.0, -128.0, 127.0) + 128.0) as u8
+                    } else {
+                        s
+                    };
+                    out.push(s);
+                }
+            }
+            Layout::F32 if encoding == OutputEncoding::S16LE => {
+                for &s in interleave(&mut self.f32s, decoded) {
+                    let v = dither.apply(s as f64 * 32768.0, gain, 1.0, i16::MIN as f64, i16::MAX as f64) as i16;
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            Layout::I16 => {
+                let lsb = source.lsb(16);
+                for &s in interleave(&mut self.i16s, decoded) {
+                    let v = if gain != 1.0 {
+                        dither.apply(s as f64, gain, lsb, i16::MIN as f64, i16::MAX as f64) as i16
+                    } else {
+                        s
+                    };
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            Layout::I32 => {
+                // Symphonia left-aligns narrower integer samples in i32, which is
+                // exactly what S32_LE expects; floats are scaled by 2^31.
+                // The 24-bit layouts take the top three bytes of that word.
+                let lsb = if encoding == OutputEncoding::S32LE { source.lsb(32) } else { source.lsb(24) * 256.0 };
+                for &s in interleave(&mut self.i32s, decoded) {
+                    let v = if gain != 1.0 {
+                        dither.apply(s as f64, gain, lsb, i32::MIN as f64, i32::MAX as f64) as i32
+                    } else {
+                        s
+                    };
+                    match encoding {
+                        OutputEncoding::S32LE => out.extend_from_slice(&v.to_le_bytes()),
+                        OutputEncoding::S24LE => out.extend_from_slice(&(v >> 8).to_le_bytes()),
+                        _ => out.extend_from_slice(&(v >> 8).to_le_bytes()[..3]),
+                    }
+                }
+            }
+            Layout::F32 => {
+                for &s in interleave(&mut self.f32s, decoded) {
+                    let v = if gain != 1.0 { (s as f64 * gain) as f32 } else { s };
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+        }
+        &self.out
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Layout {
+    U8,
+    I16,
+    I32,
+    F32,
+}
+
+/// Copy `decoded` into `buf` as interleaved samples, growing it only if the
+/// packet does not fit
+fn interleave<'a, S: ConvertibleSample>(buf: &'a mut Option<SampleBuffer<S>>, decoded: AudioBufferRef) -> &'a [S] {
+    let needed = decoded.frames() * decoded.spec().channels.count();
+    if buf.as_ref().is_none_or(|b| b.capacity() < needed) {
+        *buf = Some(SampleBuffer::new(decoded.capacity() as u64, *decoded.spec()));
+    }
+    let buf = buf.as_mut().unwrap();
+    buf.copy_interleaved_ref(decoded);
+    buf.samples()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::probe::Hint;
+
+    /// Build a WAV file in memory. `valid_bits` switches to WAVE_FORMAT_EXTENSIBLE.
+    pub fn wav(float: bool, bits: u16, valid_bits: Option<u16>, channels: u16, rate: u32, data: &[u8]) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut fmt = Vec::new();
+        let tag: u16 = if valid_bits.is_some() { 0xfffe } else if float { 3 } else { 1 };
+        fmt.extend_from_slice(&tag.to_le_bytes());
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&rate.to_le_bytes());
+        fmt.extend_from_slice(&(rate * block_align as u32).to_le_bytes());
+        fmt.extend_from_slice(&block_align.to_le_bytes());
+        fmt.extend_from_slice(&bits.to_le_bytes());
+        if let Some(valid) = valid_bits {
+            fmt.extend_from_slice(&22u16.to_le_bytes());
+            fmt.extend_from_slice(&valid.to_le_bytes());
+            let mask: u32 = if channels == 1 { 0x4 } else { 0x3 };
+            fmt.extend_from_slice(&mask.to_le_bytes());
+            // KSDATAFORMAT_SUBTYPE_PCM / IEEE_FLOAT
+            fmt.extend_from_slice(&[if float { 3 } else { 1 }, 0, 0, 0, 0x00, 0x00, 0x10, 0x00,
+                0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71]);
+        }
+
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&((4 + 8 + fmt.len() + 8 + data.len()) as u32).to_le_bytes());
+        out.extend_from_slice(b"WAVEfmt ");
+        out.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+        out.extend_from_slice(&fmt);
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+        out
+    }
+
+    /// A path in the temp dir, unique to this process, removed together with
+    /// whatever was put there when it goes out of scope. `name` keeps its
+    /// extension: "verify-24.wav" is `elitebox-verify-24-<pid>.wav`.
+    pub struct TempPath(std::path::PathBuf);
+
+    impl TempPath {
+        pub fn new(name: &str) -> Self {
+            let name = std::path::Path::new(name);
+            let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+            let mut file = format!("elitebox-{}-{}", stem, std::process::id());
+            if let Some(ext) = name.extension() {
+                file = format!("{}.{}", file, ext.to_string_lossy());
+            }
+            Self(std::env::temp_dir().join(file))
+        }
+
+        /// A file holding `data`
+        pub fn file(name: &str, data: impl AsRef<[u8]>) -> Self {
+            let path = Self::new(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        }
+
+        /// An empty directory
+        pub fn dir(name: &str) -> Self {
+            let path = Self::new(name);
+            std::fs::create_dir_all(&path).unwrap();
+            path
+        }
+    }
+
+    impl std::ops::Deref for TempPath {
+        type Target = std::path::Path;
+
+        fn deref(&self) -> &std::path::Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<std::path::Path> for TempPath {
+        fn as_ref(&self) -> &std::path::Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = if self.0.is_dir() { std::fs::remove_dir_all(&self.0) } else { std::fs::remove_file(&self.0) };
+        }
+    }
+
+    /// Decode a whole in-memory file and encode it the way the player would
+    pub fn render(file: Vec<u8>, encoding: Option<OutputEncoding>, supported: &[OutputEncoding]) -> (SourceFormat, OutputEncoding, Vec<u8>) {
+        let mss = MediaSourceStream::new(Box::new(Cursor::new(file)), Default::default());
+        let mut probed = symphonia::default::get_probe()
+            .format(&Hint::new(), mss, &Default::default(), &Default::default())
+            .unwrap();
+        let params = probed.format.tracks().iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .unwrap()
+            .codec_params
+            .clone();
+        let mut decoder = symphonia::default::get_codecs().make(&params, &DecoderOptions::default()).unwrap();
+        let source = SourceFormat::from_codec_params(&params);
+        let encoding = encoding.unwrap_or_else(|| choose_encoding(source, |e| supported.contains(&e)).unwrap());
+
+        let spec = SignalSpec::new(params.sample_rate.unwrap(), params.channels.unwrap());
+        let mut encoder = Encoder::new(source, encoding, spec, params.max_frames_per_packet);
+        let mut dither = Dither::new();
+        let mut out = Vec::new();
+        while let Ok(packet) = probed.format.next_packet() {
+            let decoded = decoder.decode(&packet).unwrap();
+            out.extend_from_slice(encoder.encode(decoded, 1.0, &mut dither));
+        }
+        (source, encoding, out)
+    }
+
+    const ALL: [OutputEncoding; 6] = [
+        OutputEncoding::U8, OutputEncoding::S16LE, OutputEncoding::S24LE,
+        OutputEncoding::S24_3LE, OutputEncoding::S32LE, OutputEncoding::FloatLE,
+    ];
+
+    #[test]
+    fn test_8bit_unsigned() {
+        let file = wav(false, 8, None, 1, 44100, &[0x00, 0x80, 0xff]);
+        let (source, enc, bytes) = render(file.clone(), None, &ALL);
+        assert_eq!(source, SourceFormat::Int(8));
+        assert_eq!(enc, OutputEncoding::U8);
+        assert_eq!(bytes, [0x00, 0x80, 0xff]);
+
+        // Without U8 support the samples are re-centred and padded into S16
+        let (_, enc, bytes) = render(file, None, &[OutputEncoding::S16LE]);
+        assert_eq!(enc, OutputEncoding::S16LE);
+        assert_eq!(bytes, [0x00, 0x80, 0x00, 0x00, 0x00, 0x7f]);
+    }
+
+    #[test]
+    fn test_16bit_passthrough() {
+        let data = [0x01, 0x80, 0xff, 0x7f, 0x34, 0x12, 0x00, 0x00];
+        let (_, enc, bytes) = render(wav(false, 16, None, 2, 44100, &data), None, &ALL);
+        assert_eq!(enc, OutputEncoding::S16LE);
+        assert_eq!(bytes, data);
+    }
+
+    #[test]
+    fn test_20bit_padded_into_s32() {
+        // 20 valid bits in a 24-bit container: 0x7ffff0 is the 20-bit maximum
+        let data = [0xf0, 0xff, 0x7f, 0x10, 0x00, 0x80];
+        let (source, enc, bytes) = render(wav(false, 24, Some(20), 2, 96000, &data), None, &ALL);
+        assert_eq!(source, SourceFormat::Int(20));
+        assert_eq!(enc, OutputEncoding::S32LE);
+        assert_eq!(bytes, [0x00, 0xf0, 0xff, 0x7f, 0x00, 0x10, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn test_float_source() {
+        let samples = [0.5f32, -1.0, 0.25, 1.0];
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        let file = wav(true, 32, None, 2, 48000, &data);
+
+        let (source, enc, bytes) = render(file.clone(), None, &ALL);
+        assert_eq!(source, SourceFormat::Float);
+        assert_eq!(enc, OutputEncoding::FloatLE);
+        assert_eq!(bytes, data);
+
+        // No FLOAT_LE on the DAC: scaled into S32 (+1.0 saturates)
+        let (_, enc, bytes) = render(file, None, &[OutputEncoding::S16LE, OutputEncoding::S32LE]);
+        assert_eq!(enc, OutputEncoding::S32LE);
+        let expected: Vec<u8> = [0x4000_0000i32, i32::MIN, 0x2000_0000, i32::MAX]
+            .iter().flat_map(|s| s.to_le_bytes()).collect();
+        assert_eq!(bytes, expected);
+    }
+
+    /// 0x123456, -2 and the 24-bit minimum
+    const S24_INPUT: [u8; 9] = [0x56, 0x34, 0x12, 0xfe, 0xff, 0xff, 0x00, 0x00, 0x80];
+
+    #[test]
+    fn test_24bit_left_aligned_in_s32() {
+        let (source, enc, bytes) = render(wav(false, 24, None, 1, 96000, &S24_INPUT), None, &ALL);
+        assert_eq!(source, SourceFormat::Int(24));
+        assert_eq!(enc, OutputEncoding::S32LE);
+        assert_eq!(bytes, [
+            0x00, 0x56, 0x34, 0x12,
+            0x00, 0xfe, 0xff, 0xff,
+            0x00, 0x00, 0x00, 0x80,
+        ]);
+    }
+
+    #[test]
+    fn test_24bit_low_aligned_in_s24() {
+        let (_, enc, bytes) = render(wav(false, 24, None, 1, 96000, &S24_INPUT), None, &[OutputEncoding::S24LE, OutputEncoding::S24_3LE]);
+        assert_eq!(enc, OutputEncoding::S24LE);
+        assert_eq!(bytes, [
+            0x56, 0x34, 0x12, 0x00,
+            0xfe, 0xff, 0xff, 0xff,
+            0x00, 0x00, 0x80, 0xff,
+        ]);
+    }
+
+    #[test]
+    fn test_24bit_packed_s24_3le() {
+        let (_, enc, bytes) = render(wav(false, 24, None, 1, 96000, &S24_INPUT), None, &[OutputEncoding::S16LE, OutputEncoding::S24_3LE]);
+        assert_eq!(enc, OutputEncoding::S24_3LE);
+        assert_eq!(bytes, S24_INPUT);
+
+        // 16-bit content padded into the packed layout
+        let (_, _, bytes) = render(wav(false, 16, None, 1, 44100, &[0x34, 0x12, 0xff, 0xff]), Some(OutputEncoding::S24_3LE), &[]);
+        assert_eq!(bytes, [0x00, 0x34, 0x12, 0x00, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_no_lossless_match() {
+        assert_eq!(choose_encoding(SourceFormat::Int(24), |e| e == OutputEncoding::S16LE), None);
+    }
+}
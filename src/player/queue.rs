@@ -0,0 +1,329 @@
+use std::path::{Path, PathBuf};
+
+use crate::gui::{RepeatMode, ShuffleMode};
+
+/// Playback order for the engine's playlist.
+///
+/// The queue only deals in playlist indices. `order` is the sequence tracks are
+/// played in (identity when shuffle is off), `history` is what was actually
+/// played so that Prev returns to the truly previous track even after jumps.
+pub struct PlayQueue {
+    albums: Vec<PathBuf>,
+    order: Vec<usize>,
+    position: usize,
+    history: Vec<usize>,
+    repeat: RepeatMode,
+    shuffle: ShuffleMode,
+    rng: XorShift,
+}
+
+impl PlayQueue {
+    pub fn new() -> Self {
+        Self {
+            albums: Vec::new(),
+            order: Vec::new(),
+            position: 0,
+            history: Vec::new(),
+            repeat: RepeatMode::Off,
+            shuffle: ShuffleMode::Off,
+            rng: XorShift::from_time(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.albums.len()
+    }
+
+    /// Playlist index of the current track
+    pub fn current(&self) -> Option<usize> {
+        self.order.get(self.position).copied()
+    }
+
+    pub fn push(&mut self, path: &Path) {
+        let idx = self.albums.len();
+        self.albums.push(album_key(path));
+        self.order.push(idx);
+        // Keep the played part of the order intact, mix the new track into the rest
+        self.reshuffle_upcoming();
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    pub fn set_shuffle(&mut self, shuffle: ShuffleMode) {
+        if shuffle == self.shuffle {
+            return;
+        }
+        self.shuffle = shuffle;
+        let current = self.current();
+        self.order = (0..self.len()).collect();
+        // Back in playlist order, carrying on from the playing track
+        self.position = current.unwrap_or(0);
+        if shuffle == ShuffleMode::Off {
+            return;
+        }
+        if let Some(cur) = current {
+            self.position = 0;
+            // The playing track stays put; everything else is re-ordered behind it
+            self.order.retain(|&i| i != cur);
+            self.order.insert(0, cur);
+            self.reshuffle_upcoming();
+            self.position = self.order.iter().position(|&i| i == cur).unwrap_or(0);
+        }
+    }
+
+    /// Jump straight to a playlist index (e.g. double click in the playlist)
+    pub fn jump_to(&mut self, idx: usize) -> Option<usize> {
+        let pos = self.order.iter().position(|&i| i == idx)?;
+        if let Some(cur) = self.current()
+            && cur != idx
+        {
+            self.history.push(cur);
+        }
+        self.position = pos;
+        Some(idx)
+    }
+
+    /// Advance after the current track finished by itself.
+    /// Repeat-one replays the same track; `None` means playback should stop.
+    pub fn advance(&mut self) -> Option<usize> {
+        if self.repeat == RepeatMode::One {
+            return self.current();
+        }
+        self.step_forward()
+    }
+
+    /// What `advance` will move to, without moving. `None` also when a
+    /// shuffled queue wraps, since the next pass is not drawn yet.
+    pub fn peek_next(&self) -> Option<usize> {
+        if self.repeat == RepeatMode::One {
+            return self.current();
+        }
+        self.current()?;
+        match self.order.get(self.position + 1) {
+            Some(&idx) => Some(idx),
+            None if self.repeat == RepeatMode::All && self.shuffle == ShuffleMode::Off => self.order.first().copied(),
+            None => None,
+        }
+    }
+
+    /// Advance on an explicit Next command. Repeat-one does not trap the user.
+    pub fn next(&mut self) -> Option<usize> {
+        self.step_forward()
+    }
+
+    /// Go back to the track that was really played before the current one
+    pub fn prev(&mut self) -> Option<usize> {
+        if let Some(idx) = self.history.pop()
+            && let Some(pos) = self.order.iter().position(|&i| i == idx)
+        {
+            self.position = pos;
+            return Some(idx);
+        }
+        if self.position > 0 {
+            self.position -= 1;
+        } else if self.repeat == RepeatMode::All && !self.order.is_empty() {
+            self.position = self.order.len() - 1;
+        }
+        self.current()
+    }
+
+    /// Rewind to the start of the order without touching repeat/shuffle settings
+    pub fn reset(&mut self) {
+        self.position = 0;
+        self.history.clear();
+    }
+
+    fn step_forward(&mut self) -> Option<usize> {
+        let cur = self.current()?;
+        if self.position + 1 < self.order.len() {
+            self.history.push(cur);
+            self.position += 1;
+            return self.current();
+        }
+        if self.repeat == RepeatMode::Off {
+            return None;
+        }
+        // Wrap around; a shuffled queue gets a fresh order for the next pass
+        self.history.push(cur);
+        if self.shuffle != ShuffleMode::Off {
+            self.order = self.shuffled(&(0..self.len()).collect::<Vec<_>>());
+        }
+        self.position = 0;
+        self.current()
+    }
+
+    fn reshuffle_upcoming(&mut self) {
+        if self.shuffle == ShuffleMode::Off || self.order.is_empty() {
+            return;
+        }
+        let split = (self.position + 1).min(self.order.len());
+        let mut upcoming = self.order.split_off(split);
+        if self.shuffle == ShuffleMode::Album {
+            // Finish the album that is playing before jumping to another one;
+            // its tracks before the current one are not "the rest of it"
+            let cur = self.order[self.position.min(split - 1)];
+            let album = &self.albums[cur];
+            let (mut same, rest): (Vec<usize>, Vec<usize>) =
+                upcoming.into_iter().partition(|&i| self.albums[i] == *album && i > cur);
+            same.sort_unstable();
+            self.order.extend(same);
+            upcoming = rest;
+        }
+        let upcoming = self.shuffled(&upcoming);
+        self.order.extend(upcoming);
+    }
+
+    fn shuffled(&mut self, indices: &[usize]) -> Vec<usize> {
+        match self.shuffle {
+            ShuffleMode::Off => indices.to_vec(),
+            ShuffleMode::Track => {
+                let mut out = indices.to_vec();
+                self.rng.shuffle(&mut out);
+                out
+            }
+            ShuffleMode::Album => {
+                // Group by album (first appearance order), shuffle the groups and
+                // keep the track order inside each album
+                let mut groups: Vec<(PathBuf, Vec<usize>)> = Vec::new();
+                for &i in indices {
+                    match groups.iter_mut().find(|(k, _)| *k == self.albums[i]) {
+                        Some((_, g)) => g.push(i),
+                        None => groups.push((self.albums[i].clone(), vec![i])),
+                    }
+                }
+                for (_, g) in groups.iter_mut() {
+                    g.sort_unstable();
+                }
+                self.rng.shuffle(&mut groups);
+                groups.into_iter().flat_map(|(_, g)| g).collect()
+            }
+        }
+    }
+}
+
+/// Tracks are grouped into albums by their containing directory
+fn album_key(path: &Path) -> PathBuf {
+    path.parent().map(Path::to_path_buf).unwrap_or_default()
+}
+
+/// Small xorshift PRNG; shuffling does not need anything stronger
+struct XorShift(u64);
+
+impl XorShift {
+    fn from_time() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self(nanos | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// Fisher-Yates
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next_u64() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_with(paths: &[&str]) -> PlayQueue {
+        let mut q = PlayQueue::new();
+        for p in paths {
+            q.push(Path::new(p));
+        }
+        q
+    }
+
+    #[test]
+    fn test_repeat_modes() {
+        let mut q = queue_with(&["a/1.flac", "a/2.flac"]);
+        assert_eq!(q.advance(), Some(1));
+        assert_eq!(q.advance(), None);
+
+        q.set_repeat(RepeatMode::All);
+        assert_eq!(q.peek_next(), Some(0));
+        assert_eq!(q.advance(), Some(0));
+        assert_eq!(q.peek_next(), Some(1));
+
+        q.set_repeat(RepeatMode::One);
+        assert_eq!(q.advance(), Some(0));
+        // Explicit Next still moves on
+        assert_eq!(q.next(), Some(1));
+    }
+
+    #[test]
+    fn test_track_shuffle_is_permutation_with_history() {
+        let paths: Vec<String> = (0..20).map(|i| format!("a/{}.flac", i)).collect();
+        let refs: Vec<&str> = paths.iter().map(|s| s.as_str()).collect();
+        let mut q = queue_with(&refs);
+        q.set_shuffle(ShuffleMode::Track);
+
+        let mut played = vec![q.current().unwrap()];
+        while let Some(i) = q.next() {
+            played.push(i);
+        }
+        let mut sorted = played.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+
+        // Prev walks back through what was actually played
+        assert_eq!(q.prev(), Some(played[18]));
+        assert_eq!(q.prev(), Some(played[17]));
+    }
+
+    #[test]
+    fn test_shuffle_off_continues_in_playlist_order() {
+        let mut q = queue_with(&["a/1.flac", "a/2.flac", "a/3.flac", "a/4.flac", "a/5.flac"]);
+        q.jump_to(2);
+        q.set_shuffle(ShuffleMode::Track);
+        assert_eq!(q.current(), Some(2));
+        q.set_shuffle(ShuffleMode::Off);
+        assert_eq!(q.current(), Some(2));
+        assert_eq!(q.next(), Some(3));
+        assert_eq!(q.next(), Some(4));
+
+        // Album shuffle from the middle of an album plays on from there
+        q.jump_to(2);
+        q.set_shuffle(ShuffleMode::Album);
+        assert_eq!((q.next(), q.next()), (Some(3), Some(4)));
+    }
+
+    #[test]
+    fn test_prev_after_jump() {
+        let mut q = queue_with(&["a/1.flac", "a/2.flac", "a/3.flac", "a/4.flac"]);
+        q.jump_to(3);
+        assert_eq!(q.prev(), Some(0));
+    }
+
+    #[test]
+    fn test_album_shuffle_keeps_albums_together() {
+        let mut q = queue_with(&["a/1.flac", "a/2.flac", "b/1.flac", "b/2.flac", "c/1.flac"]);
+        q.set_shuffle(ShuffleMode::Album);
+        let mut played = vec![q.current().unwrap()];
+        while let Some(i) = q.next() {
+            played.push(i);
+        }
+        assert_eq!(played.len(), 5);
+        // Every album's tracks are adjacent and in order
+        for (first, second) in [(0, 1), (2, 3)] {
+            let p = played.iter().position(|&i| i == first).unwrap();
+            assert_eq!(played[p + 1], second);
+        }
+    }
+}
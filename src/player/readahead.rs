@@ -0,0 +1,218 @@
+//! Whole tracks in RAM, so a slow disk or a network mount cannot stall the
+//! audio thread mid-track
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// Background reads go this far between looks at the cancellation flag
+const CHUNK: u64 = 1 << 20;
+
+/// A file read completely into memory, locked there when the memlock limit allows
+pub struct LoadedTrack {
+    bytes: Vec<u8>,
+    locked: bool,
+}
+
+impl LoadedTrack {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::load_unless(path, &AtomicBool::new(false))
+    }
+
+    /// Read in chunks, giving up with `Interrupted` once `cancel` is set
+    fn load_unless(path: &Path, cancel: &AtomicBool) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        // A failed allocation here means streaming from disk, not an abort
+        let mut bytes = Vec::new();
+        bytes.try_reserve_exact(len).map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            if (&mut file).take(CHUNK).read_to_end(&mut bytes)? == 0 {
+                break;
+            }
+        }
+        let locked = unsafe { libc::mlock(bytes.as_ptr().cast(), bytes.len()) } == 0;
+        Ok(Self { bytes, locked })
+    }
+}
+
+impl AsRef<[u8]> for LoadedTrack {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Drop for LoadedTrack {
+    fn drop(&mut self) {
+        if self.locked {
+            unsafe { libc::munlock(self.bytes.as_ptr().cast(), self.bytes.len()) };
+        }
+    }
+}
+
+/// Loads the track about to play, then the one after it in the background
+#[derive(Default)]
+pub struct ReadAhead {
+    next: Option<PathBuf>,
+    /// Started with the first prefetch
+    loader: Option<Loader>,
+    pending: Option<Prefetch>,
+    warned: bool,
+}
+
+/// The track being read in the background
+struct Prefetch {
+    path: PathBuf,
+    cancel: Arc<AtomicBool>,
+    /// Filled in once the loader is done with it
+    result: Option<io::Result<LoadedTrack>>,
+}
+
+/// The one thread that reads ahead; it ends once this is dropped. Results
+/// come back in the order the paths went in, cancelled ones included.
+struct Loader {
+    requests: Sender<(PathBuf, Arc<AtomicBool>)>,
+    results: Receiver<io::Result<LoadedTrack>>,
+    /// Results of cancelled prefetches still to come, to be thrown away
+    stale: usize,
+}
+
+impl Loader {
+    fn spawn() -> Self {
+        let (requests, queue) = mpsc::channel::<(PathBuf, Arc<AtomicBool>)>();
+        let (done, results) = mpsc::channel();
+        thread::spawn(move || {
+            crate::rt::make_ordinary();
+            for (path, cancel) in queue {
+                if done.send(LoadedTrack::load_unless(&path, &cancel)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { requests, results, stale: 0 }
+    }
+
+    /// The result of the latest request
+    fn wait(&mut self) -> io::Result<LoadedTrack> {
+        let gone = || io::Error::other("read-ahead thread stopped");
+        for _ in 0..std::mem::take(&mut self.stale) {
+            self.results.recv().map_err(|_| gone())?.ok();
+        }
+        self.results.recv().map_err(|_| gone())?
+    }
+}
+
+impl ReadAhead {
+    /// The track expected after the next `open`
+    pub fn set_next(&mut self, path: Option<&Path>) {
+        self.next = path.map(Path::to_path_buf);
+    }
+
+    /// `path` in memory: prefetched if it was the expected one, read now otherwise
+    pub fn open(&mut self, path: &Path) -> io::Result<LoadedTrack> {
+        let track = match self.pending.take() {
+            Some(mut pending) if pending.path == path => match (pending.result.take(), &mut self.loader) {
+                (Some(result), _) => result,
+                (None, Some(loader)) => loader.wait(),
+                (None, None) => LoadedTrack::load(path),
+            },
+            // A skip made the prefetched track moot; the loader stops after its current chunk
+            skipped => {
+                if let Some(skipped) = skipped {
+                    self.cancel(skipped);
+                }
+                LoadedTrack::load(path)
+            }
+        };
+        if let Ok(track) = &track
+            && !track.locked
+            && !self.warned
+        {
+            self.warned = true;
+            eprintln!("Read-ahead: cannot lock tracks in memory (raise memlock in /etc/security/limits.conf); they may be paged out");
+        }
+
+        // Only now, so the two reads do not compete for the disk
+        if let Some(next) = self.next.take().filter(|next| next != path) {
+            let cancel = Arc::new(AtomicBool::new(false));
+            let loader = self.loader.get_or_insert_with(Loader::spawn);
+            if loader.requests.send((next.clone(), cancel.clone())).is_ok() {
+                self.pending = Some(Prefetch { path: next, cancel, result: None });
+            }
+        }
+        track
+    }
+
+    /// Block until the pending prefetch is loaded
+    #[cfg(test)]
+    pub fn wait(&mut self) {
+        if let (Some(pending), Some(loader)) = (&mut self.pending, &mut self.loader)
+            && pending.result.is_none()
+        {
+            pending.result = Some(loader.wait());
+        }
+    }
+
+    fn cancel(&mut self, prefetch: Prefetch) {
+        prefetch.cancel.store(true, Ordering::Relaxed);
+        if prefetch.result.is_none()
+            && let Some(loader) = &mut self.loader
+        {
+            loader.stale += 1;
+        }
+    }
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            self.cancel(pending);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::pcm::tests::TempPath;
+
+    #[test]
+    fn test_prefetches_the_next_track() {
+        let first = TempPath::file("readahead-1", b"first");
+        let second = TempPath::file("readahead-2", b"second");
+
+        let mut read_ahead = ReadAhead::default();
+        read_ahead.set_next(Some(&second));
+        assert_eq!(read_ahead.open(&first).unwrap().as_ref(), b"first");
+        assert_eq!(read_ahead.pending.as_ref().map(|p| p.path.as_path()), Some(&*second));
+        assert_eq!(read_ahead.open(&second).unwrap().as_ref(), b"second");
+        assert!(read_ahead.pending.is_none());
+
+        // Skipping elsewhere cancels the prefetch and reads the new track directly
+        read_ahead.set_next(Some(&second));
+        read_ahead.open(&first).unwrap();
+        let skipped = read_ahead.pending.as_ref().unwrap().cancel.clone();
+        assert_eq!(read_ahead.open(&first).unwrap().as_ref(), b"first");
+        assert!(skipped.load(Ordering::Relaxed));
+        assert!(read_ahead.open(&TempPath::new("readahead-missing")).is_err());
+
+        // The one loader thread carries on after a cancelled read
+        read_ahead.set_next(Some(&second));
+        read_ahead.open(&first).unwrap();
+        assert_eq!(read_ahead.open(&second).unwrap().as_ref(), b"second");
+    }
+
+    #[test]
+    fn test_cancelled_load_stops() {
+        let path = TempPath::file("readahead-cancel", b"track");
+        let err = LoadedTrack::load_unless(&path, &AtomicBool::new(true)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    }
+}
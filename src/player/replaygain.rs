@@ -0,0 +1,176 @@
+use symphonia::core::meta::{StandardTagKey, Tag};
+
+use crate::gui::ReplayGainMode;
+
+/// ReplayGain values of one track, as found in its tags
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_gain: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+impl ReplayGain {
+    /// Collect REPLAYGAIN_* tags, plus R128_*_GAIN as written into Opus files
+    pub fn from_tags(tags: &[Tag]) -> Self {
+        let mut rg = Self::default();
+        for tag in tags {
+            let value = tag.value.to_string();
+            match tag.std_key {
+                Some(StandardTagKey::ReplayGainTrackGain) => rg.track_gain = parse_gain(&value),
+                Some(StandardTagKey::ReplayGainTrackPeak) => rg.track_peak = parse_peak(&value),
+                Some(StandardTagKey::ReplayGainAlbumGain) => rg.album_gain = parse_gain(&value),
+                Some(StandardTagKey::ReplayGainAlbumPeak) => rg.album_peak = parse_peak(&value),
+                _ => match tag.key.to_ascii_uppercase().as_str() {
+                    "REPLAYGAIN_TRACK_GAIN" => rg.track_gain = parse_gain(&value),
+                    "REPLAYGAIN_TRACK_PEAK" => rg.track_peak = parse_peak(&value),
+                    "REPLAYGAIN_ALBUM_GAIN" => rg.album_gain = parse_gain(&value),
+                    "REPLAYGAIN_ALBUM_PEAK" => rg.album_peak = parse_peak(&value),
+                    "R128_TRACK_GAIN" => rg.track_gain = rg.track_gain.or(parse_r128(&value)),
+                    "R128_ALBUM_GAIN" => rg.album_gain = rg.album_gain.or(parse_r128(&value)),
+                    _ => {}
+                },
+            }
+        }
+        rg
+    }
+
+    /// Fill whatever is missing here from another source
+    pub fn or(self, other: ReplayGain) -> Self {
+        Self {
+            track_gain: self.track_gain.or(other.track_gain),
+            track_peak: self.track_peak.or(other.track_peak),
+            album_gain: self.album_gain.or(other.album_gain),
+            album_peak: self.album_peak.or(other.album_peak),
+        }
+    }
+
+    /// Gain to apply for the given mode, limited so that the peak cannot clip.
+    /// Album mode falls back to track values when the album has not been scanned.
+    pub fn gain_db(&self, mode: ReplayGainMode) -> Option<f64> {
+        let (gain, peak) = match mode {
+            ReplayGainMode::Off => return None,
+            ReplayGainMode::Track => (self.track_gain, self.track_peak),
+            ReplayGainMode::Album => match self.album_gain {
+                Some(g) => (Some(g), self.album_peak),
+                None => (self.track_gain, self.track_peak),
+            },
+        };
+        let gain = gain.filter(|g| g.is_finite())?;
+        Some(match peak {
+            Some(p) if p.is_finite() && p > 0.0 => gain.min(-20.0 * p.log10()),
+            _ => gain,
+        })
+    }
+}
+
+/// "-6.53 dB" -> -6.53; "inf" and "NaN" are not gains
+fn parse_gain(value: &str) -> Option<f64> {
+    let v = value.trim();
+    let v = v.strip_suffix("dB").or_else(|| v.strip_suffix("db")).unwrap_or(v);
+    v.trim().parse().ok().filter(|g: &f64| g.is_finite())
+}
+
+fn parse_peak(value: &str) -> Option<f64> {
+    value.trim().parse().ok().filter(|p: &f64| p.is_finite())
+}
+
+/// R128 gains are Q7.8 fixed point relative to -23 LUFS; ReplayGain uses -18 LUFS
+fn parse_r128(value: &str) -> Option<f64> {
+    let q78: i32 = value.trim().parse().ok()?;
+    Some(q78 as f64 / 256.0 + 5.0)
+}
+
+/// Triangular (TPDF) dither for the software gain stage, one LSB peak.
+///
+/// Only used when the signal is actually scaled; at unity gain samples are
+/// passed through untouched.
+pub struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub fn new() -> Self {
+        Self { state: 0x9E37_79B9 }
+    }
+
+    fn next_unit(&mut self) -> f64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x as f64 / u32::MAX as f64
+    }
+
+    /// Scale a sample by `gain` and requantize to multiples of `lsb`
+    pub fn apply(&mut self, sample: f64, gain: f64, lsb: f64, min: f64, max: f64) -> f64 {
+        let noise = (self.next_unit() - self.next_unit()) * lsb;
+        let scaled = sample * gain + noise;
+        ((scaled / lsb).round() * lsb).clamp(min, max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::meta::Value;
+
+    fn tag(key: &str, std_key: Option<StandardTagKey>, value: &str) -> Tag {
+        Tag::new(std_key, key, Value::String(value.into()))
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        let tags = [
+            tag("REPLAYGAIN_TRACK_GAIN", Some(StandardTagKey::ReplayGainTrackGain), "-6.50 dB"),
+            tag("replaygain_track_peak", None, "0.988"),
+            tag("R128_ALBUM_GAIN", None, "-512"),
+        ];
+        let rg = ReplayGain::from_tags(&tags);
+        assert_eq!(rg.track_gain, Some(-6.5));
+        assert_eq!(rg.track_peak, Some(0.988));
+        // -2 dB against -23 LUFS is +3 dB against the ReplayGain reference
+        assert_eq!(rg.album_gain, Some(3.0));
+    }
+
+    #[test]
+    fn test_clipping_prevention() {
+        let rg = ReplayGain {
+            track_gain: Some(4.0),
+            track_peak: Some(0.8),
+            album_gain: None,
+            album_peak: None,
+        };
+        // 0.8 peak leaves only ~1.94 dB of headroom
+        let g = rg.gain_db(ReplayGainMode::Track).unwrap();
+        assert!((g - 1.938).abs() < 0.01);
+        // No album values: album mode uses the track gain
+        assert_eq!(rg.gain_db(ReplayGainMode::Album), Some(g));
+        assert_eq!(rg.gain_db(ReplayGainMode::Off), None);
+    }
+
+    #[test]
+    fn test_non_finite_gain_is_no_gain() {
+        let tags = [
+            tag("REPLAYGAIN_TRACK_GAIN", Some(StandardTagKey::ReplayGainTrackGain), "inf dB"),
+            tag("REPLAYGAIN_TRACK_PEAK", Some(StandardTagKey::ReplayGainTrackPeak), "NaN"),
+        ];
+        let rg = ReplayGain::from_tags(&tags);
+        assert_eq!((rg.track_gain, rg.track_peak), (None, None));
+        // From elsewhere, e.g. an index written before silence was handled
+        let rg = ReplayGain { track_gain: Some(f64::INFINITY), track_peak: Some(0.0), ..rg };
+        assert_eq!(rg.gain_db(ReplayGainMode::Track), None);
+    }
+
+    #[test]
+    fn test_dither_stays_within_one_lsb() {
+        let mut d = Dither::new();
+        for _ in 0..1000 {
+            let out = d.apply(1000.0, 0.5, 1.0, -32768.0, 32767.0);
+            assert!((out - 500.0).abs() <= 1.0);
+            assert_eq!(out.fract(), 0.0);
+        }
+    }
+}
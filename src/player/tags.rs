@@ -0,0 +1,164 @@
+//! Track details: what a file's tags and stream parameters say about it
+
+use std::path::Path;
+
+use symphonia::core::audio::Channels;
+use symphonia::core::codecs::CodecParameters;
+use symphonia::core::meta::{StandardTagKey, Tag, Value};
+
+use crate::gui::TrackInfo;
+use crate::player::pcm::SourceFormat;
+
+/// Details of the file as a whole, before a CUE sheet names its tracks.
+/// `sources` are tag lists in order of preference; each fills what the ones
+/// before it left empty.
+pub fn read(path: &Path, params: &CodecParameters, sources: &[&[Tag]]) -> TrackInfo {
+    let mut info = TrackInfo {
+        path: path.to_path_buf(),
+        filename: path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+        sample_rate: params.sample_rate.unwrap_or(0),
+        bit_depth: SourceFormat::from_codec_params(params).bits(),
+        codec: symphonia::default::get_codecs().get_codec(params.codec).map(|c| c.long_name.to_string()),
+        channels: params.channels.map(channel_layout),
+        file_size: std::fs::metadata(path).ok().map(|m| m.len()),
+        ..Default::default()
+    };
+
+    // Compressed files report no bitrate; the average over the file is what players show
+    if let (Some(size), Some(frames), Some(rate)) = (info.file_size, params.n_frames, params.sample_rate)
+        && frames > 0
+    {
+        let secs = frames as f64 / rate as f64;
+        info.bitrate_kbps = Some((size as f64 * 8.0 / secs / 1000.0).round() as u32);
+    }
+
+    for tags in sources {
+        for tag in tags.iter() {
+            apply(&mut info, tag);
+        }
+    }
+    info
+}
+
+fn apply(info: &mut TrackInfo, tag: &Tag) {
+    let value = match &tag.value {
+        // Pictures and other blobs would fill the panel with hex
+        Value::Binary(data) => format!("<{} bytes>", data.len()),
+        value => value.to_string().trim().to_string(),
+    };
+    if value.is_empty() {
+        return;
+    }
+    if !info.tags.iter().any(|(k, v)| *k == tag.key && *v == value) {
+        info.tags.push((tag.key.clone(), value.clone()));
+    }
+
+    let set = |field: &mut Option<String>| {
+        field.get_or_insert_with(|| value.clone());
+    };
+    match tag.std_key {
+        Some(StandardTagKey::TrackTitle) => set(&mut info.title),
+        Some(StandardTagKey::Artist) => set(&mut info.artist),
+        Some(StandardTagKey::Album) => set(&mut info.album),
+        Some(StandardTagKey::AlbumArtist) => set(&mut info.album_artist),
+        Some(StandardTagKey::Genre) => set(&mut info.genre),
+        Some(StandardTagKey::Composer) => set(&mut info.composer),
+        Some(StandardTagKey::Encoder | StandardTagKey::EncoderSettings) => set(&mut info.encoder),
+        Some(StandardTagKey::TrackNumber) => {
+            let (number, total) = parse_position(&value);
+            info.track_number = info.track_number.or(number);
+            info.track_total = info.track_total.or(total);
+        }
+        Some(StandardTagKey::TrackTotal) => info.track_total = info.track_total.or(value.parse().ok()),
+        Some(StandardTagKey::DiscNumber) => {
+            let (number, total) = parse_position(&value);
+            info.disc_number = info.disc_number.or(number);
+            info.disc_total = info.disc_total.or(total);
+        }
+        Some(StandardTagKey::DiscTotal) => info.disc_total = info.disc_total.or(value.parse().ok()),
+        Some(StandardTagKey::Date | StandardTagKey::ReleaseDate | StandardTagKey::OriginalDate) => {
+            info.year = info.year.or(parse_year(&value));
+        }
+        _ => {}
+    }
+}
+
+/// "3/12" (ID3) or "3" -> number and total
+fn parse_position(value: &str) -> (Option<u32>, Option<u32>) {
+    let mut parts = value.split('/').map(|p| p.trim().parse().ok());
+    (parts.next().flatten(), parts.next().flatten())
+}
+
+/// "1959", "1959-08-17" or "1959-08-17T12:00" -> 1959
+fn parse_year(value: &str) -> Option<u16> {
+    value.get(..4).filter(|y| y.bytes().all(|b| b.is_ascii_digit()))?.parse().ok()
+}
+
+fn channel_layout(channels: Channels) -> String {
+    let lfe = channels.contains(Channels::LFE1);
+    match (channels.count(), lfe) {
+        (1, _) => "Mono".to_string(),
+        (2, false) => "Stereo".to_string(),
+        (n, true) => format!("{}.1", n - 1),
+        (n, false) => format!("{} channels", n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::codecs::CODEC_TYPE_FLAC;
+    use crate::player::pcm::tests::TempPath;
+
+    fn tag(std_key: Option<StandardTagKey>, key: &str, value: &str) -> Tag {
+        Tag::new(std_key, key, Value::from(value))
+    }
+
+    #[test]
+    fn test_read_details() {
+        let id3 = [
+            tag(Some(StandardTagKey::TrackNumber), "TRCK", "3/12"),
+            tag(Some(StandardTagKey::Date), "TDRC", "1959-08-17"),
+            tag(Some(StandardTagKey::Album), "TALB", "Kind of Blue"),
+            tag(None, "TXXX:CATALOG", " CK 64935 "),
+        ];
+        let vorbis = [
+            tag(Some(StandardTagKey::Album), "ALBUM", "Kind of Blue (Legacy)"),
+            tag(Some(StandardTagKey::DiscNumber), "DISCNUMBER", "1"),
+            tag(Some(StandardTagKey::DiscTotal), "DISCTOTAL", "2"),
+            tag(Some(StandardTagKey::Encoder), "ENCODER", "flac 1.4.3"),
+            tag(Some(StandardTagKey::Genre), "GENRE", ""),
+        ];
+        let mut params = CodecParameters::new();
+        params.for_codec(CODEC_TYPE_FLAC)
+            .with_sample_rate(44100)
+            .with_n_frames(441000)
+            .with_bits_per_sample(16)
+            .with_channels(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        // 10 s in 1.25 MB: 1000 kbit/s
+        let path = TempPath::file("tags.flac", vec![0; 1_250_000]);
+
+        let info = read(&path, &params, &[&id3, &vorbis]);
+        assert_eq!((info.sample_rate, info.bit_depth), (44100, 16));
+        assert_eq!(info.codec.as_deref(), Some("Free Lossless Audio Codec"));
+        assert_eq!(info.channels.as_deref(), Some("Stereo"));
+        assert_eq!((info.file_size, info.bitrate_kbps), (Some(1_250_000), Some(1000)));
+        assert_eq!(info.album.as_deref(), Some("Kind of Blue"));
+        assert_eq!((info.track_number, info.track_total), (Some(3), Some(12)));
+        assert_eq!((info.disc_number, info.disc_total), (Some(1), Some(2)));
+        assert_eq!(info.year, Some(1959));
+        assert_eq!(info.encoder.as_deref(), Some("flac 1.4.3"));
+        assert_eq!(info.genre, None);
+        assert!(info.tags.contains(&("TXXX:CATALOG".to_string(), "CK 64935".to_string())));
+        assert_eq!(info.tags.len(), 8);
+    }
+
+    #[test]
+    fn test_channel_layout() {
+        assert_eq!(channel_layout(Channels::FRONT_LEFT), "Mono");
+        let surround = Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE
+            | Channels::LFE1 | Channels::REAR_LEFT | Channels::REAR_RIGHT;
+        assert_eq!(channel_layout(surround), "5.1");
+        assert_eq!(channel_layout(Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE), "3 channels");
+    }
+}
@@ -0,0 +1,44 @@
+use std::path::{Path, PathBuf};
+
+/// Sample frames of a file that make up one track: from `start` up to, not
+/// including, `end` (the end of the file when `None`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+/// One playlist entry: a whole file, or a track a CUE sheet cuts out of one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub path: PathBuf,
+    /// `None` plays the whole file
+    pub span: Option<Span>,
+    pub number: Option<u32>,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+}
+
+impl Track {
+    pub fn file(path: &Path) -> Self {
+        Self { path: path.to_path_buf(), span: None, number: None, title: None, performer: None }
+    }
+
+    /// Whether `next` carries on in the same file exactly where this one stops
+    pub fn continues_into(&self, next: &Track) -> bool {
+        match (self.span, next.span) {
+            (Some(Span { end: Some(end), .. }), Some(next_span)) => self.path == next.path && next_span.start == end,
+            _ => false,
+        }
+    }
+
+    /// For lists: "03. Title" for CUE tracks, the file name otherwise
+    pub fn name(&self) -> String {
+        let file = self.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        match (self.number, &self.title) {
+            (Some(n), Some(title)) => format!("{:02}. {}", n, title),
+            (Some(n), None) => format!("{:02}. {}", n, file),
+            (None, _) => file,
+        }
+    }
+}
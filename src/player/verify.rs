@@ -0,0 +1,365 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use symphonia::core::audio::{AudioBufferRef, SampleBuffer};
+use symphonia::core::codecs::{Decoder, DecoderOptions, VerificationCheck, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatReader;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::probe::Hint;
+
+use crate::gui::PlayerState;
+use crate::output::{AudioSink, SinkError};
+use crate::player::bitperfect::{BitPerfectPlayer, PlayerError};
+use crate::player::pcm::{choose_encoding, OutputEncoding, SourceFormat};
+
+/// Outcome of verifying one file
+pub struct Report {
+    pub source: SourceFormat,
+    pub encoding: OutputEncoding,
+    pub sample_rate: u32,
+    pub frames: u64,
+    /// MD5 of the bytes we would send, converted back to the source width
+    pub captured_md5: [u8; 16],
+    /// MD5 of the decoder output at the source width
+    pub reference_md5: [u8; 16],
+    /// The MD5 a FLAC encoder stored in STREAMINFO, if any
+    pub stored_md5: Option<[u8; 16]>,
+    /// First frame where the captured stream differs from the reference
+    pub first_mismatch: Option<u64>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.first_mismatch.is_none()
+            && self.captured_md5 == self.reference_md5
+            && self.stored_md5.is_none_or(|md5| md5 == self.reference_md5)
+    }
+}
+
+/// `elitebox verify`: check that every file goes out bit for bit.
+/// Returns whether all of them passed.
+pub fn verify_paths(paths: &[PathBuf], encoding: Option<OutputEncoding>) -> Result<bool, PlayerError> {
+    let mut all_passed = true;
+    for path in paths {
+        let r = verify_file(path, encoding)?;
+        println!("{}", path.display());
+        println!("  source     {} bit, {} Hz", match r.source {
+            SourceFormat::Int(bits) => bits.to_string(),
+            SourceFormat::Float => "float".to_string(),
+        }, r.sample_rate);
+        println!("  output     {}", r.encoding.name());
+        println!("  frames     {}", r.frames);
+        println!("  captured   {}", hex(&r.captured_md5));
+        println!("  reference  {}", hex(&r.reference_md5));
+        match r.stored_md5 {
+            Some(md5) => println!("  STREAMINFO {}", hex(&md5)),
+            None => println!("  STREAMINFO (none)"),
+        }
+        if r.passed() {
+            println!("PASS");
+        } else {
+            all_passed = false;
+            match r.first_mismatch {
+                Some(frame) => println!(
+                    "FAIL: first differing frame {} ({:.3}s)",
+                    frame, frame as f64 / r.sample_rate as f64,
+                ),
+                None => println!("FAIL: decoded audio does not match the STREAMINFO MD5"),
+            }
+        }
+    }
+    Ok(all_passed)
+}
+
+/// Play `path` at unity gain into a sink that captures the bytes, and compare
+/// them with an independent decode. `encoding` forces an output format; by
+/// default the player's first choice is used.
+pub fn verify_file(path: &Path, encoding: Option<OutputEncoding>) -> Result<Report, PlayerError> {
+    let reference = Reference::open(path)?;
+    let state = Arc::new(Mutex::new(PlayerState::headless()));
+    let mut player = BitPerfectPlayer::new(VerifySink::new(reference, encoding));
+    player.play_file(path, 0.0, state)?;
+    player.into_sink().finish()
+}
+
+/// The decoder output on its own, without the player in between
+struct Reference {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    source: SourceFormat,
+    sample_rate: u32,
+    stored_md5: Option<[u8; 16]>,
+    md5: md5::Context,
+}
+
+impl Reference {
+    fn open(path: &Path) -> Result<Self, PlayerError> {
+        let mss = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+        let probed = symphonia::default::get_probe()
+            .format(&hint, mss, &Default::default(), &Default::default())?;
+        let track = probed.format.tracks().iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(PlayerError::NoAudioTrack)?;
+        let track_id = track.id;
+        let params = track.codec_params.clone();
+        let decoder = symphonia::default::get_codecs().make(&params, &DecoderOptions::default())?;
+
+        // All zeros means the encoder did not compute one
+        let stored_md5 = match params.verification_check {
+            Some(VerificationCheck::Md5(md5)) if md5 != [0; 16] => Some(md5),
+            _ => None,
+        };
+        Ok(Self {
+            format: probed.format,
+            decoder,
+            track_id,
+            source: SourceFormat::from_codec_params(&params),
+            sample_rate: params.sample_rate.ok_or(PlayerError::NoAudioTrack)?,
+            stored_md5,
+            md5: md5::Context::new(),
+        })
+    }
+
+    /// Append the next packet at the source width; false at the end of the stream
+    fn read(&mut self, out: &mut Vec<u8>) -> Result<bool, PlayerError> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(p) => p,
+                Err(SymphoniaError::IoError(_)) => return Ok(false),
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let start = out.len();
+                    reference_pcm(decoded, self.source, out);
+                    self.md5.consume(&out[start..]);
+                    return Ok(true);
+                }
+                // The player skips the same packet
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Captures what the player writes and compares it with the reference as it
+/// arrives, so only the difference in packetization is held in memory
+struct VerifySink {
+    reference: Reference,
+    forced: Option<OutputEncoding>,
+    stream: Option<(SourceFormat, OutputEncoding)>,
+    frame_bytes: usize,
+    /// Reference bytes not yet matched against the capture
+    pending: Vec<u8>,
+    native: Vec<u8>,
+    md5: md5::Context,
+    frames: u64,
+    first_mismatch: Option<u64>,
+    error: Option<PlayerError>,
+}
+
+impl VerifySink {
+    fn new(reference: Reference, forced: Option<OutputEncoding>) -> Self {
+        Self {
+            reference,
+            forced,
+            stream: None,
+            frame_bytes: 1,
+            pending: Vec::new(),
+            native: Vec::new(),
+            md5: md5::Context::new(),
+            frames: 0,
+            first_mismatch: None,
+            error: None,
+        }
+    }
+
+    fn finish(mut self) -> Result<Report, PlayerError> {
+        // Whatever the player did not write still counts towards the reference
+        let mut rest = std::mem::take(&mut self.pending);
+        while !rest.is_empty() || self.reference.read(&mut rest)? {
+            if self.first_mismatch.is_none() {
+                self.first_mismatch = Some(self.frames);
+            }
+            rest.clear();
+        }
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let (source, encoding) = self.stream.ok_or(PlayerError::NoAudioTrack)?;
+        Ok(Report {
+            source,
+            encoding,
+            sample_rate: self.reference.sample_rate,
+            frames: self.frames,
+            captured_md5: self.md5.compute().0,
+            reference_md5: self.reference.md5.compute().0,
+            stored_md5: self.reference.stored_md5,
+            first_mismatch: self.first_mismatch,
+        })
+    }
+}
+
+impl AudioSink for VerifySink {
+    fn configure(&mut self, _sample_rate: u32, source: SourceFormat, channels: u8) -> Result<OutputEncoding, SinkError> {
+        // A DAC that takes everything gets the first candidate
+        let encoding = self.forced
+            .or_else(|| choose_encoding(source, |_| true))
+            .ok_or(SinkError::UnsupportedFormat(source))?;
+        self.stream = Some((source, encoding));
+        self.frame_bytes = channels as usize * native_width(source);
+        Ok(encoding)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), SinkError> {
+        let (source, encoding) = self.stream.ok_or(SinkError::NotConfigured)?;
+        to_native(data, encoding, source, &mut self.native);
+        self.md5.consume(&self.native);
+
+        while self.pending.len() < self.native.len() && self.error.is_none() {
+            match self.reference.read(&mut self.pending) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => self.error = Some(e),
+            }
+        }
+        if self.first_mismatch.is_none() && !self.pending.starts_with(&self.native) {
+            let offset = self.native.chunks(self.frame_bytes)
+                .zip(self.pending.chunks(self.frame_bytes))
+                .position(|(a, b)| a != b)
+                .unwrap_or(self.pending.len() / self.frame_bytes);
+            self.first_mismatch = Some(self.frames + offset as u64);
+        }
+        let matched = self.native.len().min(self.pending.len());
+        self.pending.drain(..matched);
+        self.frames += (self.native.len() / self.frame_bytes) as u64;
+        Ok(())
+    }
+
+    fn drain(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn drop_queued(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn delay(&self) -> Result<i64, SinkError> {
+        Ok(0)
+    }
+}
+
+/// Bytes per sample at the source's own width, as FLAC's MD5 is computed
+fn native_width(source: SourceFormat) -> usize {
+    match source {
+        SourceFormat::Int(bits) => bits.div_ceil(8) as usize,
+        SourceFormat::Float => 4,
+    }
+}
+
+/// Append a left-aligned sample at the source width: signed little endian,
+/// in as many bytes as the bit depth needs
+fn push_native(out: &mut Vec<u8>, aligned: i32, bits: u32) {
+    let v = aligned >> (32 - bits.clamp(1, 32));
+    out.extend_from_slice(&v.to_le_bytes()[..bits.div_ceil(8) as usize]);
+}
+
+fn reference_pcm(decoded: AudioBufferRef, source: SourceFormat, out: &mut Vec<u8>) {
+    let spec = *decoded.spec();
+    let capacity = decoded.capacity() as u64;
+    match source {
+        SourceFormat::Int(bits) => {
+            let mut buf = SampleBuffer::<i32>::new(capacity, spec);
+            buf.copy_interleaved_ref(decoded);
+            for &s in buf.samples() {
+                push_native(out, s, bits);
+            }
+        }
+        SourceFormat::Float => {
+            let mut buf = SampleBuffer::<f32>::new(capacity, spec);
+            buf.copy_interleaved_ref(decoded);
+            for &s in buf.samples() {
+                out.extend_from_slice(&s.to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Undo the output encoding so the capture can be compared with the reference
+fn to_native(sent: &[u8], encoding: OutputEncoding, source: SourceFormat, out: &mut Vec<u8>) {
+    const SCALE: f64 = 2147483648.0;
+    out.clear();
+    for s in sent.chunks_exact(encoding.width()) {
+        let aligned = match encoding {
+            OutputEncoding::U8 => ((s[0] ^ 0x80) as i8 as i32) << 24,
+            OutputEncoding::S16LE => (i16::from_le_bytes([s[0], s[1]]) as i32) << 16,
+            OutputEncoding::S24_3LE => i32::from_le_bytes([0, s[0], s[1], s[2]]),
+            OutputEncoding::S24LE => i32::from_le_bytes([s[0], s[1], s[2], s[3]]) << 8,
+            OutputEncoding::S32LE => i32::from_le_bytes([s[0], s[1], s[2], s[3]]),
+            OutputEncoding::FloatLE => {
+                if source == SourceFormat::Float {
+                    out.extend_from_slice(s);
+                    continue;
+                }
+                (f32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f64 * SCALE) as i32
+            }
+        };
+        match source {
+            SourceFormat::Int(bits) => push_native(out, aligned, bits),
+            SourceFormat::Float => out.extend_from_slice(&((aligned as f64 / SCALE) as f32).to_le_bytes()),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::pcm::tests::{wav, TempPath};
+
+    #[test]
+    fn test_24bit_passes_in_every_lossless_layout() {
+        // Two stereo frames with all 24 bits in use
+        let data = [0x56, 0x34, 0x12, 0xfe, 0xff, 0xff, 0x01, 0x00, 0x80, 0xff, 0xff, 0x7f];
+        let path = TempPath::file("verify-24.wav", wav(false, 24, None, 2, 96000, &data));
+        for encoding in [None, Some(OutputEncoding::S24LE), Some(OutputEncoding::S24_3LE)] {
+            let r = verify_file(&path, encoding).unwrap();
+            assert!(r.passed(), "{:?}", encoding);
+            assert_eq!(r.frames, 2);
+            assert_eq!(r.reference_md5, md5::compute(data).0);
+        }
+    }
+
+    #[test]
+    fn test_truncation_reports_first_frame() {
+        // Frame 0 fits in 16 bits, frame 1 does not
+        let data = [0x00, 0x34, 0x12, 0x01, 0x34, 0x12];
+        let path = TempPath::file("verify-trunc.wav", wav(false, 24, None, 1, 44100, &data));
+        let r = verify_file(&path, Some(OutputEncoding::S16LE)).unwrap();
+        assert!(!r.passed());
+        assert_eq!(r.first_mismatch, Some(1));
+    }
+
+    #[test]
+    fn test_8bit_reference_is_signed() {
+        let path = TempPath::file("verify-8.wav", wav(false, 8, None, 1, 8000, &[0x00, 0x80, 0xff]));
+        let r = verify_file(&path, None).unwrap();
+        assert!(r.passed());
+        assert_eq!(r.encoding, OutputEncoding::U8);
+        assert_eq!(r.reference_md5, md5::compute([0x80, 0x00, 0x7f]).0);
+    }
+}
@@ -1,48 +1,217 @@
-use libc::{sched_setscheduler, SCHED_FIFO, sched_param};
+mod rtkit;
+
+use std::fmt;
+use std::io;
+
+use libc::{sched_setscheduler, sched_param};
+use serde::{Deserialize, Serialize};
+
+use crate::config::RealtimeConfig;
 
 #[derive(Debug, thiserror::Error)]
 pub enum RtError {
-    #[error("Permission denied to set real-time priority")]
-    PermissionDenied,
-}
-
-pub fn set_audio_thread_priority() -> Result<(), RtError> {
-    // SCHED_FIFO: Real-time, first-in-first-out
-    // Priority 99 is highest (use 90-95 for audio)
-    let param = sched_param { sched_priority: 95 };
-    
-    let result = unsafe {
-        sched_setscheduler(0, SCHED_FIFO, &param)
-    };
-    
-    if result != 0 {
-        return Err(RtError::PermissionDenied);
-    }
-    
-    // Lock memory to prevent page faults
-    let rlim = libc::rlimit {
-        rlim_cur: libc::RLIM_INFINITY,
-        rlim_max: libc::RLIM_INFINITY,
-    };
-    unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &rlim) };
-    
-    Ok(())
+    #[error("{call}: {source}{}", hint(.call, .source))]
+    Os { call: &'static str, source: io::Error },
+    #[error("{direct}; RTKit: {rtkit}")]
+    Refused { direct: Box<RtError>, rtkit: rtkit::RtKitError },
+}
+
+impl RtError {
+    /// The failed call's errno
+    fn os(call: &'static str) -> Self {
+        RtError::Os { call, source: io::Error::last_os_error() }
+    }
+
+    fn errno(&self) -> Option<i32> {
+        match self {
+            RtError::Os { source, .. } => source.raw_os_error(),
+            RtError::Refused { .. } => None,
+        }
+    }
+}
+
+/// What to do about the usual reasons each call fails
+fn hint(call: &str, e: &io::Error) -> &'static str {
+    match (call, e.raw_os_error()) {
+        ("sched_setscheduler", Some(libc::EPERM)) if cfg!(feature = "rtkit") => {
+            " (raise rtprio in /etc/security/limits.conf or grant CAP_SYS_NICE)"
+        }
+        ("sched_setscheduler", Some(libc::EPERM)) => {
+            " (raise rtprio in /etc/security/limits.conf, grant CAP_SYS_NICE, or build with the `rtkit` feature)"
+        }
+        ("sched_setscheduler", Some(libc::EINVAL)) => " (priority must be between 1 and 99)",
+        ("mlockall", Some(libc::ENOMEM | libc::EPERM)) => " (raise memlock in /etc/security/limits.conf)",
+        ("sched_setaffinity", Some(libc::EINVAL)) => " (no such CPU, or not one this process may use)",
+        _ => "",
+    }
+}
+
+/// Scheduling class for the audio thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SchedPolicy {
+    Fifo,
+    Rr,
+    /// Leave the thread as an ordinary one
+    None,
+}
+
+impl SchedPolicy {
+    fn libc(self) -> Option<libc::c_int> {
+        match self {
+            SchedPolicy::Fifo => Some(libc::SCHED_FIFO),
+            SchedPolicy::Rr => Some(libc::SCHED_RR),
+            SchedPolicy::None => None,
+        }
+    }
+}
+
+impl fmt::Display for SchedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SchedPolicy::Fifo => "SCHED_FIFO",
+            SchedPolicy::Rr => "SCHED_RR",
+            SchedPolicy::None => "none",
+        })
+    }
+}
+
+/// What each real-time measure came to: a description of what is in effect, or why not
+pub struct RtReport {
+    pub scheduling: Result<String, RtError>,
+    pub memory_lock: Result<String, RtError>,
+    pub cpu_pinning: Result<String, RtError>,
+}
+
+impl fmt::Display for RtReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Real-time setup:")?;
+        for (what, result) in [
+            ("scheduling", &self.scheduling),
+            ("memory lock", &self.memory_lock),
+            ("cpu pinning", &self.cpu_pinning),
+        ] {
+            match result {
+                Ok(done) => writeln!(f, "  {:<12} {}", what, done)?,
+                Err(e) => writeln!(f, "  {:<12} FAILED: {}", what, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply `config` to the calling thread (and, for the memory lock, the process)
+pub fn apply(config: &RealtimeConfig) -> RtReport {
+    RtReport {
+        scheduling: set_scheduling(config),
+        memory_lock: if config.lock_memory { lock_memory() } else { Ok("off".into()) },
+        cpu_pinning: match config.cpu {
+            Some(core) => pin_to_cpu(core).map(|_| format!("core {}", core)),
+            None => Ok("off".into()),
+        },
+    }
+}
+
+fn set_scheduling(config: &RealtimeConfig) -> Result<String, RtError> {
+    let Some(policy) = config.policy.libc() else { return Ok("off".into()) };
+    // Login sessions often set rtprio as a hard limit only
+    raise_soft_limit(libc::RLIMIT_RTPRIO);
+    let param = sched_param { sched_priority: config.priority };
+    if unsafe { sched_setscheduler(0, policy, &param) } == 0 {
+        return Ok(format!("{} priority {}", config.policy, config.priority));
+    }
+
+    let direct = RtError::os("sched_setscheduler");
+    if !config.rtkit || direct.errno() != Some(libc::EPERM) {
+        return Err(direct);
+    }
+    // Desktop sessions hand out limited real-time priority over D-Bus
+    match rtkit::make_thread_realtime(config.priority) {
+        Ok(priority) => Ok(format!("SCHED_RR priority {} via RTKit ({} was refused)", priority, config.policy)),
+        Err(rtkit) => Err(RtError::Refused { direct: Box::new(direct), rtkit }),
+    }
 }
 
-pub fn pin_to_cpu(core_id: usize) {
+pub fn pin_to_cpu(core_id: usize) -> Result<(), RtError> {
     use libc::{cpu_set_t, CPU_SET, sched_setaffinity};
-    
+
+    // CPU_SET does not check: past the end of the set it writes out of bounds
+    if core_id >= libc::CPU_SETSIZE as usize {
+        return Err(RtError::Os { call: "sched_setaffinity", source: io::Error::from_raw_os_error(libc::EINVAL) });
+    }
     let mut set: cpu_set_t = unsafe { std::mem::zeroed() };
     unsafe { CPU_SET(core_id, &mut set) };
-    
-    unsafe {
-        sched_setaffinity(0, std::mem::size_of::<cpu_set_t>(), &set);
+
+    if unsafe { sched_setaffinity(0, std::mem::size_of::<cpu_set_t>(), &set) } != 0 {
+        return Err(RtError::os("sched_setaffinity"));
+    }
+    Ok(())
+}
+
+/// Undo the audio thread's priority and pinning for a helper thread it spawned,
+/// which would otherwise inherit both
+pub fn make_ordinary() {
+    let param = sched_param { sched_priority: 0 };
+    unsafe { sched_setscheduler(0, libc::SCHED_OTHER, &param) };
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for core in 0..libc::CPU_SETSIZE as usize {
+        unsafe { libc::CPU_SET(core, &mut set) };
     }
+    unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
 }
 
-pub fn lock_memory() {
-    // Prevent swapping of audio buffers
-    unsafe {
-        libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE);
+/// Keep every page resident, so the audio path never waits on a page fault
+pub fn lock_memory() -> Result<String, RtError> {
+    let limit = raise_soft_limit(libc::RLIMIT_MEMLOCK);
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        return Err(RtError::os("mlockall"));
     }
-}
\ No newline at end of file
+    Ok(match limit {
+        libc::RLIM_INFINITY => "all pages (no limit)".into(),
+        bytes => format!("all pages (limit {} MiB)", bytes / (1024 * 1024)),
+    })
+}
+
+/// Unprivileged processes may still raise a soft limit up to the hard one.
+/// Returns the soft limit now in effect.
+fn raise_soft_limit(resource: libc::__rlimit_resource_t) -> libc::rlim_t {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+        return 0;
+    }
+    if limit.rlim_cur < limit.rlim_max {
+        limit.rlim_cur = limit.rlim_max;
+        if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+            unsafe { libc::getrlimit(resource, &mut limit) };
+        }
+    }
+    limit.rlim_cur
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_measures_report_off() {
+        let config = RealtimeConfig { policy: SchedPolicy::None, priority: 95, cpu: None, lock_memory: false, rtkit: false };
+        let report = apply(&config);
+        assert_eq!(report.to_string(), "Real-time setup:\n  scheduling   off\n  memory lock  off\n  cpu pinning  off\n");
+    }
+
+    #[test]
+    fn test_pin_rejects_core_past_cpu_set() {
+        let e = pin_to_cpu(libc::CPU_SETSIZE as usize).unwrap_err();
+        assert_eq!(e.errno(), Some(libc::EINVAL));
+        assert!(e.to_string().ends_with("(no such CPU, or not one this process may use)"), "{}", e);
+    }
+
+    #[test]
+    fn test_failure_names_call_errno_and_remedy() {
+        let e = RtError::Os { call: "mlockall", source: io::Error::from_raw_os_error(libc::ENOMEM) };
+        let text = e.to_string();
+        assert!(text.starts_with("mlockall: "), "{}", text);
+        assert!(text.contains("os error 12"), "{}", text);
+        assert!(text.ends_with("(raise memlock in /etc/security/limits.conf)"), "{}", text);
+    }
+}
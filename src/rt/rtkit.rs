@@ -0,0 +1,48 @@
+//! org.freedesktop.RealtimeKit1: real-time scheduling for unprivileged
+//! processes, granted by a system daemon within limits it sets.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RtKitError {
+    #[cfg(feature = "rtkit")]
+    #[error("D-Bus error: {0}")]
+    Dbus(#[from] zbus::Error),
+    #[cfg(feature = "rtkit")]
+    #[error("setrlimit(RLIMIT_RTTIME): {0}")]
+    RtTime(std::io::Error),
+    #[cfg(not(feature = "rtkit"))]
+    #[error("elitebox was built without the `rtkit` feature")]
+    Unavailable,
+}
+
+/// Make the calling thread SCHED_RR at `priority`, or at RTKit's ceiling if
+/// that is lower. Returns the priority granted.
+#[cfg(feature = "rtkit")]
+pub fn make_thread_realtime(priority: i32) -> Result<i32, RtKitError> {
+    let connection = zbus::blocking::Connection::system()?;
+    let rtkit = zbus::blocking::Proxy::new(
+        &connection,
+        "org.freedesktop.RealtimeKit1",
+        "/org/freedesktop/RealtimeKit1",
+        "org.freedesktop.RealtimeKit1",
+    )?;
+    let max_priority: i32 = rtkit.get_property("MaxRealtimePriority")?;
+    let rttime_max: i64 = rtkit.get_property("RTTimeUSecMax")?;
+
+    // RTKit only helps threads that will be killed rather than hog a CPU
+    let limit = libc::rlimit { rlim_cur: rttime_max as libc::rlim_t, rlim_max: rttime_max as libc::rlim_t };
+    if unsafe { libc::setrlimit(libc::RLIMIT_RTTIME, &limit) } != 0 {
+        return Err(RtKitError::RtTime(std::io::Error::last_os_error()));
+    }
+
+    let priority = priority.min(max_priority);
+    let thread = unsafe { libc::gettid() } as u64;
+    rtkit.call::<_, _, ()>("MakeThreadRealtime", &(thread, priority as u32))?;
+    Ok(priority)
+}
+
+#[cfg(not(feature = "rtkit"))]
+pub fn make_thread_realtime(_priority: i32) -> Result<i32, RtKitError> {
+    Err(RtKitError::Unavailable)
+}